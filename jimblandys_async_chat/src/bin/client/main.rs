@@ -0,0 +1,815 @@
+use async_std::prelude::*;
+use async_chat::utils::{self, ChatResult, WireFormat};
+use async_std::io;
+use async_std::net;
+use futures_lite::future::FutureExt;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[cfg(feature = "tui")]
+mod tui;
+
+/// Why a conversation with the server ended.
+enum Ending {
+    /// The user has no more commands.
+    Quit,
+    /// The server closed the connection.
+    Dropped,
+    /// The server turned down our `Hello`, for the reason given.
+    Refused(String),
+}
+
+async fn send_commands(
+    to_server: &ToServer<impl io::Write + Unpin>,
+    commands: &mut (impl Stream<Item = io::Result<FromClient>> + Unpin),
+    session: &Mutex<Session>,
+    format: WireFormat,
+) -> ChatResult<Ending> {
+    while let Some(request) = commands.next().await {
+        let mut request = request?;
+        session.lock().unwrap().sent(&mut request);
+        send(to_server, format, &request).await?;
+    }
+
+    Ok(Ending::Quit)
+}
+
+/// The sending half of the connection, shared by the command loop and by
+/// `handle_replies`, which answers the server's pings.
+type ToServer<W> = async_std::sync::Mutex<W>;
+
+async fn send(
+    to_server: &ToServer<impl io::Write + Unpin>,
+    format: WireFormat,
+    packet: &FromClient,
+) -> ChatResult<()> {
+    let mut to_server = to_server.lock().await;
+    utils::send_packet(&format, &mut *to_server, packet).await?;
+    to_server.flush().await?;
+    Ok(())
+}
+
+use async_chat::FromServer;
+use chrono::Local;
+
+/// The longest line we'll accept from the server.
+const MAX_REPLY_BYTES: usize = 1 << 20;
+
+async fn handle_replies(
+    reply_stream: &mut (impl Stream<Item = ChatResult<FromServer>> + Unpin),
+    to_server: &ToServer<impl io::Write + Unpin>,
+    session: &Mutex<Session>,
+    format: WireFormat,
+    frontend: &dyn Frontend,
+) -> ChatResult<Ending> {
+    while let Some(reply) = reply_stream.next().await {
+        let reply = reply?;
+        session.lock().unwrap().received(&reply);
+        match reply {
+            FromServer::Ping => {
+                send(to_server, format, &FromClient::Pong).await?;
+            }
+            FromServer::Pong | FromServer::Ack { .. } => {}
+            reply => frontend.reply(reply),
+        }
+    }
+
+    Ok(Ending::Dropped)
+}
+
+/// How the client shows the user what is going on.
+trait Frontend {
+    /// Show a packet from the server.
+    fn reply(&self, reply: FromServer);
+    /// Show news about the connection itself.
+    fn status(&self, status: String);
+}
+
+/// The plain line-at-a-time interface, which suits scripts too.
+struct LineMode;
+
+impl Frontend for LineMode {
+    fn reply(&self, reply: FromServer) {
+        match reply {
+            FromServer::Message { group_name, sender, timestamp, message, .. } => {
+                println!("[{}] {}@{}: {}",
+                         timestamp.with_timezone(&Local).format("%H:%M:%S"),
+                         sender, group_name, message);
+            }
+            FromServer::Private { from, timestamp, message } => {
+                println!("[{}] {} (private): {}",
+                         timestamp.with_timezone(&Local).format("%H:%M:%S"),
+                         from, message);
+            }
+            FromServer::Left { group_name } => {
+                println!("left {}", group_name);
+            }
+            FromServer::Groups { group_names } => {
+                println!("groups: {}", join_names(&group_names));
+            }
+            FromServer::Members { group_name, nicknames } => {
+                println!("members of {}: {}",
+                         group_name, join_names(&nicknames));
+            }
+            FromServer::Shutdown { reason } => {
+                println!("server shutting down: {}", reason);
+            }
+            FromServer::Error(message) => {
+                println!("error from server: {}", message);
+            }
+            FromServer::Ping | FromServer::Pong | FromServer::Ack { .. } => {}
+        }
+    }
+
+    fn status(&self, status: String) {
+        println!("{}", status);
+    }
+}
+
+fn join_names(names: &[Arc<String>]) -> String {
+    let names: Vec<&str> = names.iter().map(|name| name.as_str()).collect();
+    names.join(", ")
+}
+
+/// The most posts to hold on to for sending again. The server never
+/// acknowledges posts it refuses, so these can pile up.
+const MAX_UNACKNOWLEDGED: usize = 100;
+
+/// How often to send again posts the server hasn't acknowledged. The server
+/// may drop a post without closing the connection, when it or the group is
+/// too busy; it recognizes posts it has already seen by their ids.
+const RESEND_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How many times to send a post again on one connection. A post the server
+/// refuses outright would otherwise go out forever.
+const MAX_RESENDS: u32 = 3;
+
+/// What the client restores after reconnecting: its nickname, the groups it
+/// had joined, with how far it had read in each, and the posts it can't be
+/// sure arrived.
+struct Session {
+    nickname: Arc<String>,
+    password: Option<Arc<String>>,
+    /// The sequence number of the last message seen from each group, if any.
+    joined: HashMap<Arc<String>, Option<u64>>,
+    /// The id to give our next post. Counting from the time we started
+    /// keeps ids from repeating those of earlier runs, which the server may
+    /// still remember.
+    next_post_id: u64,
+    /// Posts the server has yet to acknowledge, by id. These are sent again
+    /// on a new connection, and now and then on the current one too.
+    unacknowledged: BTreeMap<u64, FromClient>,
+    /// Set once the server says it is shutting down, after which its `Left`
+    /// packets don't mean we chose to leave.
+    server_shutting_down: bool,
+}
+
+impl Session {
+    fn new(nickname: String, password: Option<String>) -> Session {
+        Session {
+            nickname: Arc::new(nickname),
+            password: password.map(Arc::new),
+            joined: HashMap::new(),
+            next_post_id: chrono::Utc::now().timestamp_micros() as u64,
+            unacknowledged: BTreeMap::new(),
+            server_shutting_down: false,
+        }
+    }
+
+    /// Note a request we are about to send, giving it an id if it is a post.
+    fn sent(&mut self, request: &mut FromClient) {
+        if let FromClient::Post { id: id @ None, .. } = request {
+            *id = Some(self.next_post_id);
+            self.next_post_id += 1;
+        }
+        match request {
+            FromClient::Hello { nickname, password, .. } => {
+                self.nickname = nickname.clone();
+                self.password = password.clone();
+            }
+            FromClient::Join { group_name, .. } => {
+                self.joined.entry(group_name.clone()).or_insert(None);
+            }
+            FromClient::Leave { group_name } => {
+                self.joined.remove(group_name);
+            }
+            FromClient::Post { id: Some(id), .. } => {
+                let id = *id;
+                self.unacknowledged.insert(id, request.clone());
+                if self.unacknowledged.len() > MAX_UNACKNOWLEDGED {
+                    self.unacknowledged.pop_first();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Note a reply from the server.
+    fn received(&mut self, reply: &FromServer) {
+        match reply {
+            FromServer::Message { group_name, seq, .. } => {
+                if let Some(last) = self.joined.get_mut(group_name) {
+                    *last = Some(*seq);
+                }
+            }
+            FromServer::Left { group_name } if !self.server_shutting_down => {
+                self.joined.remove(group_name);
+                self.unacknowledged.retain(|_, post| {
+                    !matches!(post, FromClient::Post { group_name: to, .. }
+                              if to == group_name)
+                });
+            }
+            FromServer::Ack { id, .. } => {
+                self.unacknowledged.remove(id);
+            }
+            FromServer::Shutdown { .. } => self.server_shutting_down = true,
+            _ => {}
+        }
+    }
+
+    /// The posts that have gone unacknowledged since the last call, and
+    /// have been sent again fewer than `MAX_RESENDS` times. `resent` counts
+    /// the resends of each post still waiting.
+    fn overdue(&self, resent: &mut BTreeMap<u64, u32>) -> Vec<FromClient> {
+        let mut overdue = Vec::new();
+        let mut waiting = BTreeMap::new();
+        for (id, post) in &self.unacknowledged {
+            // A post we haven't seen before may have only just gone out.
+            let count = match resent.get(id) {
+                Some(&count) if count < MAX_RESENDS => {
+                    overdue.push(post.clone());
+                    count + 1
+                }
+                Some(&count) => count,
+                None => 0,
+            };
+            waiting.insert(*id, count);
+        }
+        *resent = waiting;
+        overdue
+    }
+
+    /// The requests that pick up where we left off on a new connection,
+    /// framed after the first as `format` says.
+    fn resume(&mut self, format: WireFormat) -> Vec<FromClient> {
+        self.server_shutting_down = false;
+        let mut requests = vec![FromClient::Hello {
+            nickname: self.nickname.clone(),
+            password: self.password.clone(),
+            format,
+        }];
+        let mut groups: Vec<_> = self.joined.iter().collect();
+        groups.sort();
+        for (group_name, last) in groups {
+            requests.push(FromClient::Join {
+                group_name: group_name.clone(),
+                // We can't know what we missed in a group we've seen nothing
+                // of yet.
+                history: last.map(|last| History::Since(last + 1)),
+            });
+        }
+        requests.extend(self.unacknowledged.values().cloned());
+        requests
+    }
+}
+
+use async_std::task;
+
+/// How long to wait before the first attempt to reconnect. Each failure
+/// doubles this, up to `MAX_RETRY_DELAY`.
+const MIN_RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+fn main() -> ChatResult<()> {
+    const USAGE: &str = "Usage: client [--tui] ADDRESS:PORT [NICKNAME]";
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let tui = match args.iter().position(|arg| arg == "--tui") {
+        Some(index) => {
+            args.remove(index);
+            true
+        }
+        None => false,
+    };
+    let mut args = args.into_iter();
+    let address = args.next().expect(USAGE);
+    let nickname = args.next()
+        .or_else(|| std::env::var("USER").ok())
+        .expect(USAGE);
+
+    // Servers with an accounts file want a password with the nickname.
+    let password = std::env::var("CHAT_PASSWORD").ok();
+    let format = match std::env::var("CHAT_WIRE_FORMAT") {
+        Ok(format) => format.parse()?,
+        Err(_) => WireFormat::default(),
+    };
+    let session = Mutex::new(Session::new(nickname, password));
+
+    if tui {
+        return task::block_on(run_tui(&address, &session, format));
+    }
+
+    println!("Commands:\n\
+              join GROUP [last N | since SEQ]\n\
+              leave GROUP\n\
+              post GROUP MESSAGE...\n\
+              groups\n\
+              who GROUP\n\
+              msg NICKNAME MESSAGE...\n\
+              nick NICKNAME [PASSWORD]\n\
+              op|kick|ban|unban|invite GROUP NICKNAME\n\
+              private GROUP on|off\n\
+              delivery GROUP drop|disconnect|block:MILLISECONDS\n\
+              Type Control-D (on Unix) or Control-Z (on Windows) \
+              to close the connection.");
+
+    let commands = io::BufReader::new(io::stdin())
+        .lines()
+        .filter_map(|line| match line {
+            // See the GitHub repo for the definition of `parse_command`.
+            Ok(line) => match parse_command(&line) {
+                Some(request) => Some(Ok(request)),
+                None if line.trim().is_empty() => {
+                    println!("empty input");
+                    None
+                }
+                None => {
+                    println!("Unrecognized command: {:?}", line);
+                    None
+                }
+            },
+            Err(error) => Some(Err(error)),
+        });
+    task::block_on(chat(&address, &session, commands, format, &LineMode))
+}
+
+#[cfg(feature = "tui")]
+async fn run_tui(
+    address: &str,
+    session: &Mutex<Session>,
+    format: WireFormat,
+) -> ChatResult<()> {
+    tui::run(address, session, format).await
+}
+
+#[cfg(not(feature = "tui"))]
+async fn run_tui(
+    _address: &str,
+    _session: &Mutex<Session>,
+    _format: WireFormat,
+) -> ChatResult<()> {
+    Err("this client was built without the `tui` feature".into())
+}
+
+/// Connect to `address` and carry out `commands`, reconnecting whenever the
+/// connection is lost, until the commands run out.
+async fn chat(
+    address: &str,
+    session: &Mutex<Session>,
+    mut commands: impl Stream<Item = io::Result<FromClient>> + Unpin,
+    format: WireFormat,
+    frontend: &dyn Frontend,
+) -> ChatResult<()> {
+    // Failing to connect at all is most likely a mistake in the address, and
+    // being refused at first a mistake in the nickname or password, so only
+    // retry once we've been connected.
+    let mut socket = connect(address).await?;
+    let mut delay = MIN_RETRY_DELAY;
+    let mut first = true;
+    loop {
+        let ending =
+            converse(socket, &mut commands, session, format, frontend).await;
+        match ending {
+            Ok(Ending::Quit) => return Ok(()),
+            Ok(Ending::Dropped) => {
+                frontend.status("server closed the connection".to_string());
+                delay = MIN_RETRY_DELAY;
+            }
+            Ok(Ending::Refused(reason)) if first => return Err(reason.into()),
+            // Most likely the server hasn't yet noticed our old connection is
+            // gone, and still holds our nickname for it; keep backing off
+            // until it lets go.
+            Ok(Ending::Refused(reason)) => {
+                frontend.status(format!("server refused us: {}", reason))
+            }
+            Err(error) => {
+                frontend.status(format!("connection lost: {}", error));
+                delay = MIN_RETRY_DELAY;
+            }
+        }
+        first = false;
+
+        socket = loop {
+            frontend.status(format!("reconnecting in {:.1?}", delay));
+            task::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+            match connect(address).await {
+                Ok(socket) => break socket,
+                Err(error) => {
+                    frontend.status(format!("cannot reconnect: {}", error))
+                }
+            }
+        };
+        frontend.status("reconnected".to_string());
+    }
+}
+
+/// Either a plain TCP stream or a TLS one.
+trait Socket: io::Read + io::Write + Unpin {}
+impl<T: io::Read + io::Write + Unpin> Socket for T {}
+
+async fn connect(address: &str) -> ChatResult<Box<dyn Socket>> {
+    let socket = net::TcpStream::connect(address).await?;
+    socket.set_nodelay(true)?;
+    match std::env::var_os("CHAT_TLS_CA") {
+        Some(ca_bundle) => {
+            Ok(Box::new(connect_tls(address, ca_bundle, socket).await?))
+        }
+        None => Ok(Box::new(socket)),
+    }
+}
+
+/// Say Hello, rejoin the session's groups, and then carry out `commands`
+/// until they run out or the connection fails. If the server refuses the
+/// Hello, give up on this connection at once.
+async fn converse(
+    socket: impl io::Read + io::Write + Unpin,
+    commands: &mut (impl Stream<Item = io::Result<FromClient>> + Unpin),
+    session: &Mutex<Session>,
+    format: WireFormat,
+    frontend: &dyn Frontend,
+) -> ChatResult<Ending> {
+    let (reader, writer) = futures_lite::io::split(socket);
+    let writer = async_std::sync::Mutex::new(writer);
+    let mut replies = utils::receive_packets(
+        format,
+        io::BufReader::new(reader),
+        MAX_REPLY_BYTES,
+    );
+
+    // The first packet is always JSON; it says how the rest are framed.
+    let mut resume = session.lock().unwrap().resume(format).into_iter();
+    if let Some(hello) = resume.next() {
+        let mut writer = writer.lock().await;
+        utils::send_as_json(&mut *writer, &hello).await?;
+        writer.flush().await?;
+    }
+
+    // The server says nothing when it accepts a Hello, but it answers
+    // requests in order, so a Pong means it has. Anything we sent after a
+    // refused Hello would only be refused too.
+    send(&writer, format, &FromClient::Ping).await?;
+    loop {
+        let reply = match replies.next().await {
+            Some(reply) => reply?,
+            None => return Ok(Ending::Dropped),
+        };
+        session.lock().unwrap().received(&reply);
+        match reply {
+            FromServer::Pong => break,
+            FromServer::Error(reason) => return Ok(Ending::Refused(reason)),
+            FromServer::Ping => {
+                send(&writer, format, &FromClient::Pong).await?;
+            }
+            reply => frontend.reply(reply),
+        }
+    }
+
+    for request in resume {
+        send(&writer, format, &request).await?;
+    }
+
+    let to_server = send_commands(&writer, commands, session, format);
+    let from_server =
+        handle_replies(&mut replies, &writer, session, format, frontend);
+    let resends = resend_overdue(&writer, session, format);
+
+    // from_server.race(to_server).await?;
+    from_server.or(to_server).or(resends).await
+}
+
+/// Every `RESEND_INTERVAL`, send again the posts the server has yet to
+/// acknowledge. This only ends if sending fails.
+async fn resend_overdue(
+    to_server: &ToServer<impl io::Write + Unpin>,
+    session: &Mutex<Session>,
+    format: WireFormat,
+) -> ChatResult<Ending> {
+    let mut resent = BTreeMap::new();
+    loop {
+        task::sleep(RESEND_INTERVAL).await;
+        let overdue = session.lock().unwrap().overdue(&mut resent);
+        for post in overdue {
+            send(to_server, format, &post).await?;
+        }
+    }
+}
+
+/// Setting `CHAT_TLS_CA` to a PEM bundle of trusted certificates makes the
+/// client speak TLS, verifying the server against the host in `address`.
+#[cfg(feature = "tls")]
+async fn connect_tls(
+    address: &str,
+    ca_bundle: std::ffi::OsString,
+    socket: net::TcpStream,
+) -> ChatResult<impl io::Read + io::Write + Unpin> {
+    use async_chat::tls;
+
+    let connector = tls::connector(ca_bundle.as_ref())?;
+    Ok(connector.connect(tls::server_name(address)?, socket).await?)
+}
+
+#[cfg(not(feature = "tls"))]
+async fn connect_tls(
+    _address: &str,
+    _ca_bundle: std::ffi::OsString,
+    _socket: net::TcpStream,
+) -> ChatResult<net::TcpStream> {
+    Err("this client was built without the `tls` feature".into())
+}
+
+use async_chat::{FromClient, History};
+use std::sync::Arc;
+
+/// Parse a line (presumably read from the standard input) as a `Request`.
+fn parse_command(line: &str) -> Option<FromClient> {
+    let (command, rest) = get_next_token(line)?;
+    match command {
+        "post" => {
+            let (group, rest) = get_next_token(rest)?;
+            let message = rest.trim_start().to_string();
+            Some(FromClient::Post {
+                group_name: Arc::new(group.to_string()),
+                message: Arc::new(message),
+                id: None,
+            })
+        }
+        "msg" => {
+            let (nickname, rest) = get_next_token(rest)?;
+            let message = rest.trim_start().to_string();
+            Some(FromClient::Whisper {
+                to: Arc::new(nickname.to_string()),
+                message: Arc::new(message),
+            })
+        }
+        "nick" => {
+            let (nickname, rest) = get_next_token(rest)?;
+            let password = match get_next_token(rest) {
+                None => None,
+                Some(_) => Some(Arc::new(get_only_token(rest)?.to_string())),
+            };
+            Some(FromClient::Hello {
+                nickname: Arc::new(nickname.to_string()),
+                password,
+                format: WireFormat::default(),
+            })
+        }
+        "join" => {
+            let (group, rest) = get_next_token(rest)?;
+            let history = match get_next_token(rest) {
+                None => None,
+                Some(("last", rest)) => {
+                    Some(History::Last(get_only_token(rest)?.parse().ok()?))
+                }
+                Some(("since", rest)) => {
+                    Some(History::Since(get_only_token(rest)?.parse().ok()?))
+                }
+                Some(_) => return None,
+            };
+            Some(FromClient::Join {
+                group_name: Arc::new(group.to_string()),
+                history,
+            })
+        }
+        "leave" => {
+            let group = get_only_token(rest)?;
+            Some(FromClient::Leave {
+                group_name: Arc::new(group.to_string()),
+            })
+        }
+        "groups" => {
+            if !rest.trim().is_empty() {
+                return None;
+            }
+            Some(FromClient::ListGroups)
+        }
+        "who" => {
+            let group = get_only_token(rest)?;
+            Some(FromClient::ListMembers {
+                group_name: Arc::new(group.to_string()),
+            })
+        }
+        "op" | "kick" | "ban" | "unban" | "invite" => {
+            let (group, rest) = get_next_token(rest)?;
+            let group_name = Arc::new(group.to_string());
+            let nickname = Arc::new(get_only_token(rest)?.to_string());
+            Some(match command {
+                "op" => FromClient::Op { group_name, nickname },
+                "kick" => FromClient::Kick { group_name, nickname },
+                "ban" => FromClient::Ban { group_name, nickname },
+                "unban" => FromClient::Unban { group_name, nickname },
+                _ => FromClient::Invite { group_name, nickname },
+            })
+        }
+        "private" => {
+            let (group, rest) = get_next_token(rest)?;
+            let invite_only = match get_only_token(rest)? {
+                "on" => true,
+                "off" => false,
+                _ => return None,
+            };
+            Some(FromClient::SetInviteOnly {
+                group_name: Arc::new(group.to_string()),
+                invite_only,
+            })
+        }
+        "delivery" => {
+            let (group, rest) = get_next_token(rest)?;
+            Some(FromClient::SetDelivery {
+                group_name: Arc::new(group.to_string()),
+                delivery: get_only_token(rest)?.parse().ok()?,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Return the single token in `input`, or `None` if it holds zero tokens or
+/// more than one.
+fn get_only_token(input: &str) -> Option<&str> {
+    let (token, rest) = get_next_token(input)?;
+    if !rest.trim_start().is_empty() {
+        return None;
+    }
+    Some(token)
+}
+
+/// Given a string `input`, return `Some((token, rest))`, where `token` is the
+/// first run of non-whitespace characters in `input`, and `rest` is the rest of
+/// the string. If the string contains no non-whitespace characters, return
+/// `None`.
+fn get_next_token(mut input: &str) -> Option<(&str, &str)> {
+    input = input.trim_start();
+
+    if input.is_empty() {
+        return None;
+    }
+
+    match input.find(char::is_whitespace) {
+        Some(space) => Some((&input[0..space], &input[space..])),
+        None => Some((input, "")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> Arc<String> {
+        Arc::new(s.to_string())
+    }
+
+    fn message(group_name: &str, seq: u64) -> FromServer {
+        FromServer::Message {
+            group_name: text(group_name),
+            seq,
+            sender: text("jimb"),
+            timestamp: chrono::Utc::now(),
+            message: text("woof"),
+        }
+    }
+
+    #[test]
+    fn session_resumes_where_it_left_off() {
+        let mut session = Session::new("jimb".to_string(), None);
+        for command in ["join dogs", "join cats", "join birds", "leave birds"] {
+            session.sent(&mut parse_command(command).unwrap());
+        }
+        session.received(&message("dogs", 6));
+        session.received(&message("dogs", 7));
+        session.sent(&mut parse_command("nick jimbo hunter2").unwrap());
+
+        // Leaving as the server shuts down doesn't count.
+        session.received(&FromServer::Shutdown { reason: "bye".to_string() });
+        session.received(&FromServer::Left { group_name: text("dogs") });
+
+        assert_eq!(
+            session.resume(WireFormat::Cbor),
+            vec![
+                FromClient::Hello {
+                    nickname: text("jimbo"),
+                    password: Some(text("hunter2")),
+                    format: WireFormat::Cbor,
+                },
+                FromClient::Join { group_name: text("cats"), history: None },
+                FromClient::Join {
+                    group_name: text("dogs"),
+                    history: Some(History::Since(8)),
+                },
+            ]
+        );
+
+        // Being kicked out does.
+        session.received(&FromServer::Left { group_name: text("dogs") });
+        assert_eq!(session.resume(WireFormat::JsonLines).len(), 2);
+    }
+
+    #[test]
+    fn unacknowledged_posts_are_sent_again() {
+        let mut session = Session::new("jimb".to_string(), None);
+        let mut posts = Vec::new();
+        for command in ["join dogs", "post dogs woof", "post dogs bark"] {
+            let mut request = parse_command(command).unwrap();
+            session.sent(&mut request);
+            if let FromClient::Post { id, .. } = request {
+                posts.push(id.expect("post was not given an id"));
+            }
+        }
+        assert!(posts[0] < posts[1]);
+
+        session.received(&FromServer::Ack { id: posts[0], seq: 3 });
+        assert_eq!(
+            session.resume(WireFormat::JsonLines)[2..],
+            [FromClient::Post {
+                group_name: text("dogs"),
+                message: text("bark"),
+                id: Some(posts[1]),
+            }]
+        );
+
+        // On the same connection, a post is sent again only once it has
+        // waited a whole interval, and only so many times.
+        let mut resent = BTreeMap::new();
+        assert_eq!(session.overdue(&mut resent), []);
+        for _ in 0..MAX_RESENDS {
+            assert_eq!(session.overdue(&mut resent).len(), 1);
+        }
+        assert_eq!(session.overdue(&mut resent), []);
+
+        // Nothing is sent again to a group we've been kicked out of.
+        session.received(&FromServer::Left { group_name: text("dogs") });
+        assert_eq!(session.resume(WireFormat::JsonLines).len(), 1);
+    }
+
+    #[test]
+    fn refused_hello_ends_the_conversation() {
+        task::block_on(async {
+            let listener =
+                net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+
+            // A server whose old connection for jimb hasn't closed yet.
+            let server = task::spawn(async move {
+                let (socket, _) = listener.accept().await.unwrap();
+                let mut to_client = socket.clone();
+                let mut requests = utils::receive_as_json::<_, FromClient>(
+                    io::BufReader::new(socket),
+                    MAX_REPLY_BYTES,
+                );
+                let mut received = Vec::new();
+                while let Some(request) = requests.next().await {
+                    let request = request.unwrap();
+                    let reply = match request {
+                        FromClient::Hello { .. } => FromServer::Error(
+                            "Nickname already taken".to_string(),
+                        ),
+                        FromClient::Ping => FromServer::Pong,
+                        _ => continue,
+                    };
+                    received.push(request);
+                    utils::send_as_json(&mut to_client, &reply)
+                        .await
+                        .unwrap();
+                }
+                received
+            });
+
+            let session = Mutex::new(Session::new("jimb".to_string(), None));
+            for command in ["join dogs", "post dogs woof"] {
+                let mut request = parse_command(command).unwrap();
+                session.lock().unwrap().sent(&mut request);
+            }
+            let socket = net::TcpStream::connect(address).await.unwrap();
+            let mut commands = futures_lite::stream::pending();
+            let ending = converse(
+                socket,
+                &mut commands,
+                &session,
+                WireFormat::JsonLines,
+                &LineMode,
+            )
+            .await
+            .unwrap();
+            assert!(matches!(ending, Ending::Refused(ref reason)
+                             if reason == "Nickname already taken"));
+
+            // Neither the rejoin nor the post went out under a refused name.
+            let received = server.await;
+            assert_eq!(received.len(), 2);
+            assert!(matches!(received[0], FromClient::Hello { .. }));
+            assert_eq!(received[1], FromClient::Ping);
+            let resume = session.lock().unwrap().resume(WireFormat::JsonLines);
+            assert_eq!(resume.len(), 3);
+        });
+    }
+}
@@ -0,0 +1,527 @@
+//! A full-screen interface: the groups down the left, the selected group's
+//! messages on the right, and an input line along the bottom.
+//!
+//! Plain text typed into a group's pane is posted to that group; lines that
+//! start with `/` are commands, as in line mode. The first pane, for the
+//! server itself, shows everything that doesn't belong to a group.
+
+use async_chat::utils::{ChatResult, WireFormat};
+use async_chat::{FromClient, FromServer};
+use async_std::channel::{self, Receiver, Sender};
+use async_std::prelude::*;
+use chrono::{DateTime, Local, Utc};
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent};
+use crossterm::event::{KeyEventKind, KeyModifiers};
+use futures_lite::future::FutureExt;
+use ratatui::layout::{Constraint, Layout, Position};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::{Frontend, Session};
+
+/// The pane for the server itself.
+const SERVER_PANE: usize = 0;
+
+/// How many lines each pane keeps.
+const MAX_SCROLLBACK: usize = 1000;
+
+/// How far PageUp and PageDown scroll.
+const PAGE_LINES: usize = 10;
+
+/// Run the client with the full-screen interface until the user quits.
+pub async fn run(
+    address: &str,
+    session: &Mutex<Session>,
+    format: WireFormat,
+) -> ChatResult<()> {
+    let (updates, updates_received) = channel::unbounded();
+    let updates = Updates(updates);
+    let (commands, commands_received) = channel::unbounded();
+
+    let mut terminal = ratatui::try_init()?;
+    let ui = interact(&mut terminal, updates_received, commands);
+    let network = crate::chat(
+        address,
+        session,
+        commands_received.map(Ok),
+        format,
+        &updates,
+    );
+    let result = ui.or(network).await;
+    ratatui::restore();
+    result
+}
+
+/// Something for the interface to show.
+enum Update {
+    Reply(FromServer),
+    Status(String),
+}
+
+/// Passes what the network side hears on to the interface.
+struct Updates(Sender<Update>);
+
+impl Frontend for Updates {
+    fn reply(&self, reply: FromServer) {
+        let _ = self.0.try_send(Update::Reply(reply));
+    }
+
+    fn status(&self, status: String) {
+        let _ = self.0.try_send(Update::Status(status));
+    }
+}
+
+/// What woke the interface up.
+enum Input {
+    Terminal(Option<std::io::Result<Event>>),
+    Update(Result<Update, channel::RecvError>),
+}
+
+async fn interact(
+    terminal: &mut DefaultTerminal,
+    updates: Receiver<Update>,
+    commands: Sender<FromClient>,
+) -> ChatResult<()> {
+    let mut app = App::new();
+    let mut events = EventStream::new();
+    loop {
+        terminal.draw(|frame| app.draw(frame))?;
+        let input = async { Input::Terminal(events.next().await) }
+            .or(async { Input::Update(updates.recv().await) })
+            .await;
+        match input {
+            Input::Terminal(Some(Ok(Event::Key(key))))
+                if key.kind == KeyEventKind::Press =>
+            {
+                let request = match app.key(key) {
+                    Some(Action::Send(request)) => request,
+                    Some(Action::Quit) => return Ok(()),
+                    None => continue,
+                };
+                if commands.send(request).await.is_err() {
+                    return Ok(());
+                }
+            }
+            // Resizes and the like just need a redraw.
+            Input::Terminal(Some(Ok(_))) => {}
+            Input::Terminal(Some(Err(error))) => return Err(error.into()),
+            Input::Terminal(None) => return Ok(()),
+            Input::Update(Ok(update)) => app.show(update),
+            // The network side has finished.
+            Input::Update(Err(_)) => return Ok(()),
+        }
+    }
+}
+
+/// What a keystroke asks the client to do.
+#[derive(Debug, PartialEq)]
+enum Action {
+    Send(FromClient),
+    Quit,
+}
+
+struct Pane {
+    /// The group this pane shows, or `None` for the server pane.
+    group_name: Option<Arc<String>>,
+    lines: VecDeque<Line<'static>>,
+    /// Lines added since the pane was last selected.
+    unread: usize,
+    /// How many lines up from the bottom the view is scrolled.
+    scroll: usize,
+}
+
+impl Pane {
+    fn new(group_name: Option<Arc<String>>) -> Pane {
+        Pane {
+            group_name,
+            lines: VecDeque::new(),
+            unread: 0,
+            scroll: 0,
+        }
+    }
+
+    fn title(&self) -> String {
+        match &self.group_name {
+            Some(group_name) => group_name.to_string(),
+            None => "(server)".to_string(),
+        }
+    }
+}
+
+struct App {
+    panes: Vec<Pane>,
+    selected: usize,
+    input: String,
+    /// Lines entered so far, oldest first.
+    history: Vec<String>,
+    /// The position in `history` that Up and Down have reached, if any.
+    recalled: Option<usize>,
+}
+
+impl App {
+    fn new() -> App {
+        let mut app = App {
+            panes: vec![Pane::new(None)],
+            selected: SERVER_PANE,
+            input: String::new(),
+            history: Vec::new(),
+            recalled: None,
+        };
+        for help in [
+            "Type /join GROUP to join a group.",
+            "Text typed in a group's pane is posted to it.",
+            "Lines starting with / are commands, as in line mode.",
+            "Tab and Shift-Tab switch panes; Up and Down recall lines.",
+            "PageUp and PageDown scroll; Esc quits.",
+        ] {
+            app.push(SERVER_PANE, Line::from(help));
+        }
+        app
+    }
+
+    fn show(&mut self, update: Update) {
+        let reply = match update {
+            Update::Reply(reply) => reply,
+            Update::Status(status) => {
+                let style = Style::default().fg(Color::DarkGray);
+                let line = Line::styled(status, style);
+                self.push(SERVER_PANE, line);
+                return;
+            }
+        };
+        match reply {
+            FromServer::Message { group_name, sender, timestamp, message, .. } => {
+                let pane = self.pane_for(group_name);
+                let line = said(timestamp, &sender, "", &message);
+                self.push(pane, line);
+            }
+            FromServer::Private { from, timestamp, message } => {
+                let line = said(timestamp, &from, " (private)", &message);
+                self.push(SERVER_PANE, line);
+            }
+            FromServer::Left { group_name } => {
+                let pane = self.existing_pane(&group_name);
+                self.push(pane, notice(format!("left {}", group_name)));
+            }
+            FromServer::Groups { group_names } => {
+                let line =
+                    format!("groups: {}", crate::join_names(&group_names));
+                self.push(SERVER_PANE, notice(line));
+            }
+            FromServer::Members { group_name, nicknames } => {
+                let pane = self.existing_pane(&group_name);
+                let line = format!(
+                    "members of {}: {}",
+                    group_name,
+                    crate::join_names(&nicknames)
+                );
+                self.push(pane, notice(line));
+            }
+            FromServer::Shutdown { reason } => {
+                let line = format!("server shutting down: {}", reason);
+                self.push(SERVER_PANE, notice(line));
+            }
+            // Most likely about whatever the user just did, wherever they
+            // did it.
+            FromServer::Error(message) => {
+                let style = Style::default().fg(Color::Red);
+                self.push(self.selected, Line::styled(message, style));
+            }
+            FromServer::Ping | FromServer::Pong | FromServer::Ack { .. } => {}
+        }
+    }
+
+    fn key(&mut self, key: KeyEvent) -> Option<Action> {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Esc => return Some(Action::Quit),
+            KeyCode::Char('c') | KeyCode::Char('d') if control => {
+                return Some(Action::Quit)
+            }
+            KeyCode::Enter => return self.submit(),
+            KeyCode::Char(c) if !control => self.input.push(c),
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            KeyCode::Tab => self.select((self.selected + 1) % self.panes.len()),
+            KeyCode::BackTab => {
+                let count = self.panes.len();
+                self.select((self.selected + count - 1) % count);
+            }
+            KeyCode::Up => {
+                let recalled = match self.recalled {
+                    Some(index) => index.saturating_sub(1),
+                    None if self.history.is_empty() => return None,
+                    None => self.history.len() - 1,
+                };
+                self.recalled = Some(recalled);
+                self.input = self.history[recalled].clone();
+            }
+            KeyCode::Down => match self.recalled {
+                Some(index) if index + 1 < self.history.len() => {
+                    self.recalled = Some(index + 1);
+                    self.input = self.history[index + 1].clone();
+                }
+                Some(_) => {
+                    self.recalled = None;
+                    self.input.clear();
+                }
+                None => {}
+            },
+            KeyCode::PageUp => {
+                let pane = &mut self.panes[self.selected];
+                pane.scroll = (pane.scroll + PAGE_LINES).min(pane.lines.len());
+            }
+            KeyCode::PageDown => {
+                let pane = &mut self.panes[self.selected];
+                pane.scroll = pane.scroll.saturating_sub(PAGE_LINES);
+            }
+            _ => {}
+        }
+        None
+    }
+
+    /// Act on the input line.
+    fn submit(&mut self) -> Option<Action> {
+        let line = std::mem::take(&mut self.input);
+        self.recalled = None;
+        if line.trim().is_empty() {
+            return None;
+        }
+        self.history.push(line.clone());
+
+        let group_name = self.panes[self.selected].group_name.clone();
+        let request = match (line.strip_prefix('/'), group_name) {
+            (Some("quit"), _) => return Some(Action::Quit),
+            (Some(command), _) => crate::parse_command(command),
+            (None, Some(group_name)) => Some(FromClient::Post {
+                group_name,
+                message: Arc::new(line.clone()),
+                id: None,
+            }),
+            (None, None) => crate::parse_command(&line),
+        };
+        let request = match request {
+            Some(request) => request,
+            None => {
+                let complaint = format!("Unrecognized command: {:?}", line);
+                let style = Style::default().fg(Color::Red);
+                self.push(self.selected, Line::styled(complaint, style));
+                return None;
+            }
+        };
+        if let FromClient::Join { group_name, .. } = &request {
+            let pane = self.pane_for(group_name.clone());
+            self.select(pane);
+        }
+        Some(Action::Send(request))
+    }
+
+    /// The pane for `group_name`, added if need be.
+    fn pane_for(&mut self, group_name: Arc<String>) -> usize {
+        match self.find_pane(&group_name) {
+            Some(pane) => pane,
+            None => {
+                self.panes.push(Pane::new(Some(group_name)));
+                self.panes.len() - 1
+            }
+        }
+    }
+
+    /// The pane for `group_name`, or the server pane if there is none.
+    fn existing_pane(&self, group_name: &str) -> usize {
+        self.find_pane(group_name).unwrap_or(SERVER_PANE)
+    }
+
+    fn find_pane(&self, group_name: &str) -> Option<usize> {
+        self.panes.iter().position(|pane| {
+            pane.group_name.as_deref().map(String::as_str) == Some(group_name)
+        })
+    }
+
+    fn push(&mut self, pane: usize, line: Line<'static>) {
+        let selected = pane == self.selected;
+        let pane = &mut self.panes[pane];
+        if pane.lines.len() == MAX_SCROLLBACK {
+            pane.lines.pop_front();
+        } else if pane.scroll > 0 {
+            // Keep a scrolled-back view where it is.
+            pane.scroll += 1;
+        }
+        pane.lines.push_back(line);
+        if !selected {
+            pane.unread += 1;
+        }
+    }
+
+    fn select(&mut self, pane: usize) {
+        self.selected = pane;
+        self.panes[pane].unread = 0;
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [sidebar, main] =
+            Layout::horizontal([Constraint::Length(20), Constraint::Min(0)])
+                .areas(frame.area());
+        let [messages, input] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(3)])
+                .areas(main);
+
+        let items = self.panes.iter().map(|pane| {
+            let label = match pane.unread {
+                0 => pane.title(),
+                unread => format!("{} ({})", pane.title(), unread),
+            };
+            let style = if pane.unread > 0 {
+                Style::default().add_modifier(Modifier::BOLD)
+            } else {
+                Style::default()
+            };
+            ListItem::new(label).style(style)
+        });
+        let list = List::new(items)
+            .block(Block::bordered().title("Groups"))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        let mut state = ListState::default().with_selected(Some(self.selected));
+        frame.render_stateful_widget(list, sidebar, &mut state);
+
+        let pane = &self.panes[self.selected];
+        let height = messages.height.saturating_sub(2) as usize;
+        let end = pane.lines.len() - pane.scroll.min(pane.lines.len());
+        let start = end.saturating_sub(height);
+        let lines: Vec<Line> = pane.lines.range(start..end).cloned().collect();
+        let mut title = pane.title();
+        if pane.scroll > 0 {
+            title.push_str(&format!(" [{} more below]", pane.scroll));
+        }
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(title)),
+            messages,
+        );
+
+        let prompt = format!("> {}", self.input);
+        let cursor_x = input.x + 1 + prompt.chars().count() as u16;
+        frame.render_widget(
+            Paragraph::new(prompt).block(Block::bordered()),
+            input,
+        );
+        frame.set_cursor_position(Position::new(
+            cursor_x.min(input.right().saturating_sub(2)),
+            input.y + 1,
+        ));
+    }
+}
+
+/// A line showing that `sender` said `message`, with the sender coloured so
+/// that each nickname is easy to pick out.
+fn said(
+    timestamp: DateTime<Utc>,
+    sender: &str,
+    how: &str,
+    message: &str,
+) -> Line<'static> {
+    let time = timestamp.with_timezone(&Local).format("[%H:%M:%S] ");
+    Line::from(vec![
+        Span::styled(time.to_string(), Style::default().fg(Color::DarkGray)),
+        Span::styled(
+            format!("{}{}: ", sender, how),
+            Style::default()
+                .fg(sender_color(sender))
+                .add_modifier(Modifier::BOLD),
+        ),
+        Span::raw(message.to_string()),
+    ])
+}
+
+fn notice(text: String) -> Line<'static> {
+    Line::styled(text, Style::default().add_modifier(Modifier::ITALIC))
+}
+
+/// The same colour for a nickname every time.
+fn sender_color(nickname: &str) -> Color {
+    const PALETTE: [Color; 10] = [
+        Color::Cyan,
+        Color::Green,
+        Color::Yellow,
+        Color::Magenta,
+        Color::Blue,
+        Color::LightCyan,
+        Color::LightGreen,
+        Color::LightYellow,
+        Color::LightMagenta,
+        Color::LightBlue,
+    ];
+    let hash = nickname.bytes().fold(0usize, |hash, byte| {
+        hash.wrapping_mul(31).wrapping_add(byte as usize)
+    });
+    PALETTE[hash % PALETTE.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> Arc<String> {
+        Arc::new(s.to_string())
+    }
+
+    fn type_line(app: &mut App, line: &str) -> Option<Action> {
+        for c in line.chars() {
+            app.key(KeyEvent::from(KeyCode::Char(c)));
+        }
+        app.key(KeyEvent::from(KeyCode::Enter))
+    }
+
+    #[test]
+    fn panes_count_unread_and_post_to_their_group() {
+        let mut app = App::new();
+        let join = type_line(&mut app, "/join dogs");
+        assert_eq!(
+            join,
+            Some(Action::Send(FromClient::Join {
+                group_name: text("dogs"),
+                history: None,
+            }))
+        );
+        assert_eq!(app.selected, 1);
+
+        // Messages to the pane on screen aren't unread; others are.
+        app.show(Update::Reply(FromServer::Message {
+            group_name: text("dogs"),
+            seq: 0,
+            sender: text("jimb"),
+            timestamp: Utc::now(),
+            message: text("woof"),
+        }));
+        app.show(Update::Status("reconnected".to_string()));
+        assert_eq!(app.panes[1].unread, 0);
+        assert_eq!(app.panes[SERVER_PANE].unread, 1);
+
+        assert_eq!(
+            type_line(&mut app, "bark"),
+            Some(Action::Send(FromClient::Post {
+                group_name: text("dogs"),
+                message: text("bark"),
+                id: None,
+            }))
+        );
+        assert_eq!(type_line(&mut app, "/frobnicate"), None);
+
+        app.key(KeyEvent::from(KeyCode::Tab));
+        assert_eq!(app.selected, SERVER_PANE);
+        assert_eq!(app.panes[SERVER_PANE].unread, 0);
+
+        // Up recalls earlier lines, most recent first.
+        app.key(KeyEvent::from(KeyCode::Up));
+        app.key(KeyEvent::from(KeyCode::Up));
+        assert_eq!(app.input, "bark");
+        app.key(KeyEvent::from(KeyCode::Down));
+        assert_eq!(app.input, "/frobnicate");
+        app.key(KeyEvent::from(KeyCode::Down));
+        assert_eq!(app.input, "");
+    }
+}
@@ -0,0 +1,374 @@
+//! Asynchronous chat server.
+#![warn(rust_2018_idioms)]
+#![allow(elided_lifetimes_in_paths)]
+
+use async_chat::server::accounts::Accounts;
+use async_chat::server::limits::{self, Limits};
+use async_chat::server::store::StoreConfig;
+use async_chat::server::{self, ServerConfig, TlsAcceptor};
+use async_chat::utils::ChatResult;
+use async_chat::Delivery;
+use clap::Parser;
+use serde::{Deserialize, Deserializer};
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::level_filters::LevelFilter;
+
+/// Settings that can be given on the command line or in a TOML config file.
+/// Anything given on the command line wins.
+#[derive(Default, Deserialize, Parser)]
+#[serde(deny_unknown_fields)]
+#[command(about = "Asynchronous chat server")]
+struct Settings {
+    /// Address to accept connections on, like `localhost:8088`.
+    address: Option<String>,
+    /// Read settings from this TOML file.
+    #[arg(long, short)]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+    /// Address to accept WebSocket connections from browsers on.
+    #[arg(long)]
+    websocket_address: Option<String>,
+    /// Address to serve Prometheus metrics on, at `/metrics`.
+    #[arg(long)]
+    metrics_address: Option<String>,
+    /// How many messages may wait to be sent to each member of a group.
+    #[arg(long)]
+    queue_capacity: Option<usize>,
+    /// What a group does when a member's queue is full: `drop`,
+    /// `disconnect` or `block:MILLISECONDS`.
+    #[arg(long)]
+    #[serde(default, deserialize_with = "parsed")]
+    delivery: Option<Delivery>,
+    /// How many groups there may be at once.
+    #[arg(long)]
+    max_groups: Option<usize>,
+    /// How many members each group may have.
+    #[arg(long)]
+    max_members: Option<usize>,
+    /// How many recent messages each group keeps for replay.
+    #[arg(long)]
+    history: Option<usize>,
+    /// One of `off`, `error`, `warn`, `info`, `debug` or `trace`.
+    #[arg(long)]
+    #[serde(default, deserialize_with = "parsed")]
+    log_level: Option<LevelFilter>,
+    /// Keep each group's messages in a log under this directory.
+    #[arg(long)]
+    store_dir: Option<PathBuf>,
+}
+
+impl Settings {
+    /// Fill in whatever `self` leaves unset from `fallback`.
+    fn or(self, fallback: Settings) -> Settings {
+        Settings {
+            address: self.address.or(fallback.address),
+            config: self.config.or(fallback.config),
+            websocket_address: self
+                .websocket_address
+                .or(fallback.websocket_address),
+            metrics_address: self.metrics_address.or(fallback.metrics_address),
+            queue_capacity: self
+                .queue_capacity
+                .or(fallback.queue_capacity),
+            delivery: self.delivery.or(fallback.delivery),
+            max_groups: self.max_groups.or(fallback.max_groups),
+            max_members: self.max_members.or(fallback.max_members),
+            history: self.history.or(fallback.history),
+            log_level: self.log_level.or(fallback.log_level),
+            store_dir: self.store_dir.or(fallback.store_dir),
+        }
+    }
+
+    /// The settings that can also come from environment variables:
+    /// `CHAT_WEBSOCKET_ADDRESS`, `CHAT_METRICS_ADDRESS` and `CHAT_LOG_DIR`.
+    fn from_env() -> Settings {
+        Settings {
+            websocket_address: std::env::var("CHAT_WEBSOCKET_ADDRESS").ok(),
+            metrics_address: std::env::var("CHAT_METRICS_ADDRESS").ok(),
+            store_dir: std::env::var_os("CHAT_LOG_DIR").map(PathBuf::from),
+            ..Settings::default()
+        }
+    }
+
+    fn load(path: &Path) -> ChatResult<Settings> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|error| format!("{}: {}", path.display(), error))?;
+        toml::from_str(&text)
+            .map_err(|error| format!("{}: {}", path.display(), error).into())
+    }
+}
+
+/// Parse a setting from a string in the config file, the way the command
+/// line does.
+fn parsed<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let text = String::deserialize(deserializer)?;
+    text.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+fn main() -> ChatResult<()> {
+    // Report configuration problems plainly, and before binding anything.
+    let (address, log_level, config) = match configure() {
+        Ok(configured) => configured,
+        Err(error) => {
+            eprintln!("server: {}", error);
+            std::process::exit(2);
+        }
+    };
+    tracing_subscriber::fmt().with_max_level(log_level).init();
+    let signalled = server::signalled()?;
+
+    async_std::task::block_on(async {
+        let listener = async_std::net::TcpListener::bind(address).await?;
+        server::run_server_until(listener, config, signalled).await
+    })
+}
+
+/// Gather settings from the command line, the environment and the config
+/// file, in that order of precedence, and check them. Return the address to
+/// listen on, the most detailed level to log at, and the server's
+/// configuration.
+fn configure() -> ChatResult<(String, LevelFilter, ServerConfig)> {
+    let args = Settings::parse();
+    let file = match &args.config {
+        Some(path) => Settings::load(path)?,
+        None => Settings::default(),
+    };
+    let settings = args.or(Settings::from_env()).or(file);
+
+    let address = settings.address.clone().ok_or(
+        "no address to listen on; give one on the command line or as \
+         `address` in the config file",
+    )?;
+    let log_level = settings.log_level.unwrap_or(LevelFilter::INFO);
+    let config = server_config(settings)?;
+    config.validate()?;
+    check_places(&address, &config)?;
+    Ok((address, log_level, config))
+}
+
+/// Make sure every address resolves and the store's directory exists,
+/// creating it if need be, so that a mistake in either is reported before
+/// the server binds anything.
+fn check_places(address: &str, config: &ServerConfig) -> ChatResult<()> {
+    fn resolve(setting: &str, address: &str) -> ChatResult<()> {
+        let mut found = address
+            .to_socket_addrs()
+            .map_err(|error| format!("{} `{}`: {}", setting, address, error))?;
+        match found.next() {
+            Some(_) => Ok(()),
+            None => {
+                Err(format!("{} `{}` names no address", setting, address)
+                    .into())
+            }
+        }
+    }
+
+    resolve("address", address)?;
+    if let Some(websocket_address) = &config.websocket_address {
+        resolve("websocket_address", websocket_address)?;
+    }
+    if let Some(metrics_address) = &config.metrics_address {
+        resolve("metrics_address", metrics_address)?;
+    }
+    if let Some(store) = &config.store {
+        std::fs::create_dir_all(&store.dir).map_err(|error| {
+            format!("store_dir `{}`: {}", store.dir.display(), error)
+        })?;
+    }
+    Ok(())
+}
+
+fn server_config(settings: Settings) -> ChatResult<ServerConfig> {
+    let defaults = ServerConfig::default();
+    Ok(ServerConfig {
+        store: match settings.store_dir {
+            Some(dir) => Some(store_config_from_env(dir)?),
+            None => None,
+        },
+        limits: limits_from_env()?,
+        accounts: match std::env::var_os("CHAT_ACCOUNTS") {
+            Some(path) => Some(Accounts::load(path.as_ref())?),
+            None => None,
+        },
+        tls: tls_acceptor_from_env()?,
+        websocket_address: settings.websocket_address,
+        metrics_address: settings.metrics_address,
+        queue_capacity: settings
+            .queue_capacity
+            .unwrap_or(defaults.queue_capacity),
+        delivery: settings.delivery.unwrap_or(defaults.delivery),
+        max_groups: settings.max_groups,
+        max_members: settings.max_members,
+        history: settings.history.unwrap_or(defaults.history),
+        ..defaults
+    })
+}
+
+/// Logs are kept under `dir`. `CHAT_LOG_FSYNC` (`always`, `never` or a
+/// number of seconds), `CHAT_LOG_SEGMENT_BYTES` and `CHAT_LOG_SEGMENT_SECS`
+/// adjust how they are written.
+fn store_config_from_env(dir: PathBuf) -> ChatResult<StoreConfig> {
+    use std::env::var;
+
+    let mut config = StoreConfig::new(dir);
+    if let Ok(fsync) = var("CHAT_LOG_FSYNC") {
+        config.fsync = fsync.parse()?;
+    }
+    if let Ok(bytes) = var("CHAT_LOG_SEGMENT_BYTES") {
+        config.max_segment_bytes = bytes.parse()?;
+    }
+    if let Ok(secs) = var("CHAT_LOG_SEGMENT_SECS") {
+        config.max_segment_age = Some(Duration::from_secs(secs.parse()?));
+    }
+    Ok(config)
+}
+
+/// Flood protection is on by default. `CHAT_CONNECTION_RATE` and
+/// `CHAT_GROUP_RATE` (`RATE[/BURST]` per second, or `off`) limit requests per
+/// connection and posts per group; `CHAT_MAX_LINE_BYTES` and
+/// `CHAT_MAX_MESSAGE_BYTES` cap sizes; and `CHAT_LIMIT_ACTION` (`drop`,
+/// `warn` or `disconnect`) says what happens to offenders.
+///
+/// Connections are pinged every `CHAT_PING_SECS` seconds (or never, if it is
+/// `off`) and dropped after `CHAT_MAX_MISSED_PONGS` go unanswered. Setting
+/// `CHAT_IDLE_SECS` drops those that make no requests for that long.
+fn limits_from_env() -> ChatResult<Limits> {
+    use std::env::var;
+
+    fn rate(value: String) -> Result<Option<limits::Rate>, String> {
+        match value.as_str() {
+            "off" => Ok(None),
+            rate => rate.parse().map(Some),
+        }
+    }
+
+    fn secs(value: String) -> ChatResult<Option<Duration>> {
+        match value.as_str() {
+            "off" => Ok(None),
+            secs => Ok(Some(Duration::from_secs(secs.parse()?))),
+        }
+    }
+
+    let mut limits = Limits::default();
+    if let Ok(value) = var("CHAT_CONNECTION_RATE") {
+        limits.connection_rate = rate(value)?;
+    }
+    if let Ok(value) = var("CHAT_GROUP_RATE") {
+        limits.group_rate = rate(value)?;
+    }
+    if let Ok(bytes) = var("CHAT_MAX_LINE_BYTES") {
+        limits.max_line_bytes = bytes.parse()?;
+    }
+    if let Ok(bytes) = var("CHAT_MAX_MESSAGE_BYTES") {
+        limits.max_message_bytes = bytes.parse()?;
+    }
+    if let Ok(action) = var("CHAT_LIMIT_ACTION") {
+        limits.action = action.parse()?;
+    }
+    if let Ok(value) = var("CHAT_PING_SECS") {
+        limits.ping_interval = secs(value)?;
+    }
+    if let Ok(count) = var("CHAT_MAX_MISSED_PONGS") {
+        limits.max_missed_pongs = count.parse()?;
+    }
+    if let Ok(value) = var("CHAT_IDLE_SECS") {
+        limits.idle_timeout = secs(value)?;
+    }
+    Ok(limits)
+}
+
+/// TLS is enabled by setting `CHAT_TLS_CERT` and `CHAT_TLS_KEY` to the paths
+/// of a PEM certificate chain and private key.
+fn tls_acceptor_from_env() -> ChatResult<Option<TlsAcceptor>> {
+    use std::env::var_os;
+
+    let (cert, key) = match (var_os("CHAT_TLS_CERT"), var_os("CHAT_TLS_KEY")) {
+        (None, None) => return Ok(None),
+        (Some(cert), Some(key)) => (cert, key),
+        _ => {
+            return Err("CHAT_TLS_CERT and CHAT_TLS_KEY must be set together"
+                .into())
+        }
+    };
+
+    #[cfg(feature = "tls")]
+    return Ok(Some(async_chat::tls::acceptor(cert.as_ref(), key.as_ref())?));
+
+    #[cfg(not(feature = "tls"))]
+    {
+        let _ = (cert, key);
+        Err("this server was built without the `tls` feature".into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_override_the_config_file() {
+        let file: Settings = toml::from_str(
+            r#"
+            address = "localhost:8088"
+            queue_capacity = 50
+            delivery = "block:250"
+            max_groups = 10
+            log_level = "debug"
+            "#,
+        )
+        .unwrap();
+        let args = Settings::parse_from([
+            "server",
+            "--max-groups",
+            "20",
+            "--history",
+            "5",
+        ]);
+        let settings = args.or(file);
+        assert_eq!(settings.address.as_deref(), Some("localhost:8088"));
+        assert_eq!(settings.queue_capacity, Some(50));
+        let block = Delivery::Block { timeout_ms: 250 };
+        assert_eq!(settings.delivery, Some(block));
+        assert_eq!(settings.max_groups, Some(20));
+        assert_eq!(settings.history, Some(5));
+        assert_eq!(settings.log_level, Some(LevelFilter::DEBUG));
+        assert_eq!(settings.max_members, None);
+
+        assert!(toml::from_str::<Settings>("config = \"other.toml\"").is_err());
+        assert!(toml::from_str::<Settings>("log_level = \"loud\"").is_err());
+    }
+
+    #[test]
+    fn places_are_checked_before_binding() {
+        let dir = std::env::temp_dir()
+            .join(format!("async-chat-places-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut config = ServerConfig {
+            websocket_address: Some("localhost:0".to_string()),
+            store: Some(StoreConfig::new(dir.join("logs"))),
+            ..ServerConfig::default()
+        };
+        check_places("localhost:8088", &config).unwrap();
+        assert!(dir.join("logs").is_dir());
+
+        assert!(check_places("localhost", &config).is_err());
+        config.metrics_address = Some("no port".to_string());
+        assert!(check_places("localhost:8088", &config).is_err());
+
+        // A store directory that can't be made.
+        config.metrics_address = None;
+        std::fs::write(dir.join("file"), "").unwrap();
+        config.store = Some(StoreConfig::new(dir.join("file")));
+        assert!(check_places("localhost:8088", &config).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
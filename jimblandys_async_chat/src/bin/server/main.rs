@@ -1,44 +0,0 @@
-//! Asynchronous chat server.
-#![warn(rust_2018_idioms)]
-#![allow(elided_lifetimes_in_paths)]
-
-use async_std::prelude::*;
-use async_chat::utils::ChatResult;
-use std::sync::Arc;
-
-mod connection;
-mod group;
-mod group_table;
-mod participants;
-
-use connection::work_connection;
-
-fn main() -> ChatResult<()> {
-    let address = std::env::args().nth(1).expect("Usage: server ADDRESS");
-
-    let chat_group_table = Arc::new(group_table::GroupTable::new());
-
-    async_std::task::block_on(async {
-        // This code was shown in the chapter introduction.
-        use async_std::{net, task};
-
-        let listener = net::TcpListener::bind(address).await?;
-
-        let mut new_connections = listener.incoming();
-        while let Some(socket_result) = new_connections.next().await {
-            let socket = socket_result?;
-            let groups = chat_group_table.clone();
-            task::spawn(async {
-                log_error(work_connection(socket, groups).await);
-            });
-        }
-
-        Ok(())
-    })
-}
-
-fn log_error(result: ChatResult<()>) {
-    if let Err(error) = result {
-        eprintln!("Error: {}", error);
-    }
-}
@@ -1,25 +1,188 @@
 #![warn(rust_2018_idioms)]
 #![allow(elided_lifetimes_in_paths)]
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
+use utils::WireFormat;
 
+pub mod server;
+#[cfg(feature = "tls")]
+pub mod tls;
 pub mod utils;
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub enum FromClient {
-    Join { group_name: Arc<String> },
+    /// Identify this connection. Must precede any other request; sending it
+    /// again renames the connection.
+    Hello {
+        nickname: Arc<String>,
+        /// The nickname's password or token, for servers that require one.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        password: Option<Arc<String>>,
+        /// How the rest of the conversation is framed, in both directions.
+        /// The first `Hello` is always JSON; this is ignored in later ones.
+        #[serde(default, skip_serializing_if = "WireFormat::is_default")]
+        format: WireFormat,
+    },
+    Join {
+        group_name: Arc<String>,
+        /// Messages posted before joining to replay first, if any.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        history: Option<History>,
+    },
+    Leave { group_name: Arc<String> },
     Post {
         group_name: Arc<String>,
         message: Arc<String>,
+        /// Chosen by the client to identify the post, if it wants it
+        /// acknowledged. Posting again with the same id from the same
+        /// nickname is acknowledged without posting the message twice, so
+        /// ids should not repeat.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+    },
+    ListGroups,
+    ListMembers { group_name: Arc<String> },
+    /// Send `message` to the user named `to` alone.
+    Whisper {
+        to: Arc<String>,
+        message: Arc<String>,
+    },
+
+    // The rest may only be sent by a group's operators: its owner, who is the
+    // first to join it, and whoever the owner or other operators have opped.
+
+    /// Make `nickname` an operator of the group.
+    Op {
+        group_name: Arc<String>,
+        nickname: Arc<String>,
+    },
+    /// Remove `nickname` from the group. They may join again.
+    Kick {
+        group_name: Arc<String>,
+        nickname: Arc<String>,
+    },
+    /// Remove `nickname` from the group, and refuse to let them join or post
+    /// until they are unbanned.
+    Ban {
+        group_name: Arc<String>,
+        nickname: Arc<String>,
+    },
+    Unban {
+        group_name: Arc<String>,
+        nickname: Arc<String>,
+    },
+    /// Let `nickname` join the group while it is invite-only.
+    Invite {
+        group_name: Arc<String>,
+        nickname: Arc<String>,
+    },
+    /// Restrict joining and posting to operators and invited users, or lift
+    /// the restriction.
+    SetInviteOnly {
+        group_name: Arc<String>,
+        invite_only: bool,
+    },
+    /// Choose what the group does when a member can't keep up with its
+    /// messages.
+    SetDelivery {
+        group_name: Arc<String>,
+        delivery: Delivery,
     },
+
+    /// Ask the server to reply with `Pong`, to check the connection.
+    Ping,
+    /// The reply to the server's `Ping`. Clients must answer promptly, or be
+    /// disconnected.
+    Pong,
+}
+
+/// Which of a group's past messages a `Join` asks to have replayed. The
+/// server keeps only a bounded number of recent messages, so a request may
+/// be answered with fewer than asked for. When `Since` reaches back further
+/// than that, the replay starts with an `Error` saying how many are missing.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum History {
+    /// The most recent `n` messages.
+    Last(usize),
+    /// Every message with a sequence number of at least this.
+    Since(u64),
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
+/// What a group does with a message when a member has fallen so far behind
+/// that it has no room to queue it.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub enum Delivery {
+    /// Skip the message for that member, who is told how many it missed
+    /// when the next one arrives.
+    #[default]
+    Drop,
+    /// Make the poster wait up to `timeout_ms` milliseconds for room, then
+    /// skip the message as `Drop` does.
+    Block { timeout_ms: u64 },
+    /// Disconnect the member.
+    Disconnect,
+}
+
+impl std::str::FromStr for Delivery {
+    type Err = String;
+
+    /// Parse `drop`, `disconnect`, or `block:MILLISECONDS`.
+    fn from_str(s: &str) -> Result<Delivery, String> {
+        match s {
+            "drop" => return Ok(Delivery::Drop),
+            "disconnect" => return Ok(Delivery::Disconnect),
+            _ => {}
+        }
+        s.strip_prefix("block:")
+            .and_then(|ms| ms.parse().ok())
+            .map(|timeout_ms| Delivery::Block { timeout_ms })
+            .ok_or_else(|| {
+                format!(
+                    "delivery must be 'drop', 'disconnect' or \
+                     'block:MILLISECONDS', not '{}'",
+                    s
+                )
+            })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub enum FromServer {
     Message {
         group_name: Arc<String>,
+        /// The message's position in its group; consecutive messages in a
+        /// group have consecutive numbers.
+        seq: u64,
+        sender: Arc<String>,
+        /// When the server received the post.
+        timestamp: DateTime<Utc>,
+        message: Arc<String>,
+    },
+    /// A `Whisper` addressed to this connection's user.
+    Private {
+        from: Arc<String>,
+        timestamp: DateTime<Utc>,
         message: Arc<String>,
     },
+    /// Sent once the connection has stopped receiving a group's messages.
+    Left { group_name: Arc<String> },
+    /// Reply to `ListGroups`: every group on the server, sorted.
+    Groups { group_names: Vec<Arc<String>> },
+    /// Reply to `ListMembers`: the nicknames in the group, sorted.
+    Members {
+        group_name: Arc<String>,
+        nicknames: Vec<Arc<String>>,
+    },
+    /// The server is going away, and will close the connection shortly.
+    Shutdown { reason: String },
+    /// Confirms that the `Post` with this `id` has been given the sequence
+    /// number `seq` in its group.
+    Ack { id: u64, seq: u64 },
+    /// Checks that the client is still there; answer with `Pong`.
+    Ping,
+    /// The reply to a client's `Ping`.
+    Pong,
     Error(String),
 }
 
@@ -32,6 +195,7 @@ fn test_fromclient_json() {
     let from_client = FromClient::Post {
         group_name: Arc::new("Dogs".to_string()),
         message: Arc::new("Samoyeds rock!".to_string()),
+        id: None,
     };
     println!("{}", Utc::now());
 
@@ -41,4 +205,47 @@ fn test_fromclient_json() {
 
     assert_eq!(serde_json::from_str::<FromClient>(&json).unwrap(),
                from_client);
+
+    let join: FromClient =
+        serde_json::from_str(r#"{"Join":{"group_name":"Dogs"}}"#).unwrap();
+    assert_eq!(join, FromClient::Join {
+        group_name: Arc::new("Dogs".to_string()),
+        history: None,
+    });
+
+    let hello: FromClient =
+        serde_json::from_str(r#"{"Hello":{"nickname":"jimb"}}"#).unwrap();
+    assert_eq!(hello, FromClient::Hello {
+        nickname: Arc::new("jimb".to_string()),
+        password: None,
+        format: WireFormat::JsonLines,
+    });
+}
+
+#[test]
+fn test_fromserver_json() {
+    let from_server = FromServer::Message {
+        group_name: Arc::new("Dogs".to_string()),
+        seq: 7,
+        sender: Arc::new("jimb".to_string()),
+        timestamp: "2020-06-01T12:30:00Z".parse().unwrap(),
+        message: Arc::new("Samoyeds rock!".to_string()),
+    };
+
+    let json = serde_json::to_string(&from_server).unwrap();
+    assert_eq!(json,
+               r#"{"Message":{"group_name":"Dogs","seq":7,"sender":"jimb","timestamp":"2020-06-01T12:30:00Z","message":"Samoyeds rock!"}}"#);
+
+    assert_eq!(serde_json::from_str::<FromServer>(&json).unwrap(),
+               from_server);
+}
+
+#[test]
+fn test_delivery_from_str() {
+    assert_eq!("drop".parse::<Delivery>(), Ok(Delivery::Drop));
+    assert_eq!("disconnect".parse::<Delivery>(), Ok(Delivery::Disconnect));
+    assert_eq!("block:250".parse::<Delivery>(),
+               Ok(Delivery::Block { timeout_ms: 250 }));
+    assert!("block".parse::<Delivery>().is_err());
+    assert!("block:soon".parse::<Delivery>().is_err());
 }
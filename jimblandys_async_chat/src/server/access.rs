@@ -0,0 +1,193 @@
+//! Who may join, post to, and moderate a group.
+//!
+//! Users are identified by nickname, so these rules only mean as much as the
+//! server's nicknames do: little, unless it is run with an accounts file.
+//!
+//! A group's access outlives the group itself: the group table keeps it by
+//! name, and the store saves it, so that emptying a group doesn't hand its
+//! ownership to whoever joins next.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Access {
+    /// The first user to join the group. The owner is always an operator,
+    /// and cannot be kicked or banned.
+    owner: Option<Arc<String>>,
+    ops: HashSet<Arc<String>>,
+    invite_only: bool,
+    invited: HashSet<Arc<String>>,
+    banned: HashSet<Arc<String>>,
+}
+
+impl Access {
+    pub fn new() -> Access {
+        Access::default()
+    }
+
+    pub fn is_op(&self, nickname: &String) -> bool {
+        self.owner.as_deref() == Some(nickname) || self.ops.contains(nickname)
+    }
+
+    /// Check that `nickname` may join the group.
+    pub fn admit(&self, group_name: &str, nickname: &String) -> Result<(), String> {
+        self.check_member(group_name, nickname)
+    }
+
+    /// Make `nickname`, who has just joined the group, its owner if it has
+    /// none yet. Return `true` if they became its owner.
+    pub fn claim(&mut self, nickname: &Arc<String>) -> bool {
+        if self.owner.is_some() {
+            return false;
+        }
+        self.owner = Some(nickname.clone());
+        true
+    }
+
+    /// Check that `nickname` may post to the group.
+    pub fn check_post(
+        &self,
+        group_name: &str,
+        nickname: &String,
+    ) -> Result<(), String> {
+        self.check_member(group_name, nickname)
+    }
+
+    fn check_member(&self, group_name: &str, nickname: &String) -> Result<(), String> {
+        if self.banned.contains(nickname) {
+            return Err(format!("You are banned from '{}'", group_name));
+        }
+        if self.invite_only
+            && !self.is_op(nickname)
+            && !self.invited.contains(nickname)
+        {
+            return Err(format!("'{}' is invite-only", group_name));
+        }
+        Ok(())
+    }
+
+    pub fn check_op(&self, group_name: &str, by: &String) -> Result<(), String> {
+        if !self.is_op(by) {
+            return Err(format!("You are not an operator of '{}'", group_name));
+        }
+        Ok(())
+    }
+
+    /// Check that `by` may kick or ban `nickname`.
+    pub fn check_kick(
+        &self,
+        group_name: &str,
+        by: &String,
+        nickname: &String,
+    ) -> Result<(), String> {
+        self.check_op(group_name, by)?;
+        if self.owner.as_deref() == Some(nickname) {
+            return Err(format!("'{}' owns '{}'", nickname, group_name));
+        }
+        Ok(())
+    }
+
+    pub fn op(
+        &mut self,
+        group_name: &str,
+        by: &String,
+        nickname: Arc<String>,
+    ) -> Result<(), String> {
+        self.check_op(group_name, by)?;
+        self.banned.remove(&nickname);
+        self.ops.insert(nickname);
+        Ok(())
+    }
+
+    pub fn ban(
+        &mut self,
+        group_name: &str,
+        by: &String,
+        nickname: Arc<String>,
+    ) -> Result<(), String> {
+        self.check_kick(group_name, by, &nickname)?;
+        self.ops.remove(&nickname);
+        self.invited.remove(&nickname);
+        self.banned.insert(nickname);
+        Ok(())
+    }
+
+    pub fn unban(
+        &mut self,
+        group_name: &str,
+        by: &String,
+        nickname: &String,
+    ) -> Result<(), String> {
+        self.check_op(group_name, by)?;
+        if !self.banned.remove(nickname) {
+            return Err(format!("'{}' is not banned from '{}'", nickname, group_name));
+        }
+        Ok(())
+    }
+
+    pub fn invite(
+        &mut self,
+        group_name: &str,
+        by: &String,
+        nickname: Arc<String>,
+    ) -> Result<(), String> {
+        self.check_op(group_name, by)?;
+        self.banned.remove(&nickname);
+        self.invited.insert(nickname);
+        Ok(())
+    }
+
+    pub fn set_invite_only(
+        &mut self,
+        group_name: &str,
+        by: &String,
+        invite_only: bool,
+    ) -> Result<(), String> {
+        self.check_op(group_name, by)?;
+        self.invite_only = invite_only;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nick(name: &str) -> Arc<String> {
+        Arc::new(name.to_string())
+    }
+
+    #[test]
+    fn owner_ops_invites_and_bans() {
+        let (owner, op, guest) = (nick("jimb"), nick("jorendorff"), nick("ltindall"));
+        let mut access = Access::new();
+
+        access.admit("dogs", &owner).unwrap();
+        assert!(access.claim(&owner));
+        access.admit("dogs", &guest).unwrap();
+        assert!(!access.claim(&guest));
+        assert!(access.is_op(&owner));
+        assert!(!access.is_op(&guest));
+
+        // Only operators moderate, and nobody moderates the owner.
+        assert!(access.op("dogs", &guest, guest.clone()).is_err());
+        access.op("dogs", &owner, op.clone()).unwrap();
+        assert!(access.check_kick("dogs", &op, &owner).is_err());
+        access.check_kick("dogs", &op, &guest).unwrap();
+
+        access.ban("dogs", &op, guest.clone()).unwrap();
+        assert!(access.admit("dogs", &guest).is_err());
+        assert!(access.check_post("dogs", &guest).is_err());
+        access.unban("dogs", &op, &guest).unwrap();
+        access.check_post("dogs", &guest).unwrap();
+
+        access.set_invite_only("dogs", &owner, true).unwrap();
+        assert!(access.admit("dogs", &guest).is_err());
+        access.admit("dogs", &op).unwrap();
+        access.invite("dogs", &op, guest.clone()).unwrap();
+        access.admit("dogs", &guest).unwrap();
+    }
+}
@@ -0,0 +1,109 @@
+//! The nicknames users may claim, and the passwords that prove it.
+//!
+//! An accounts file has one account per line: a nickname, whitespace, and
+//! the hex SHA-256 digest of its password or token, as printed by
+//! `printf %s PASSWORD | sha256sum`. Blank lines and lines starting with `#`
+//! are ignored.
+
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+#[derive(Clone)]
+pub struct Accounts {
+    digests: HashMap<String, [u8; 32]>,
+}
+
+impl Accounts {
+    pub fn load(path: &Path) -> io::Result<Accounts> {
+        Accounts::parse(&std::fs::read_to_string(path)?).map_err(|message| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), message),
+            )
+        })
+    }
+
+    fn parse(text: &str) -> Result<Accounts, String> {
+        let mut digests = HashMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let (nickname, digest) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(nickname), Some(digest), None) => (nickname, digest),
+                _ => {
+                    return Err(format!(
+                        "line {}: expected NICKNAME SHA256-HEX",
+                        number + 1
+                    ))
+                }
+            };
+            let digest = decode_hex(digest).ok_or_else(|| {
+                format!("line {}: malformed SHA-256 digest", number + 1)
+            })?;
+            digests.insert(nickname.to_string(), digest);
+        }
+        Ok(Accounts { digests })
+    }
+
+    /// Check that `password` is `nickname`'s.
+    pub fn authenticate(
+        &self,
+        nickname: &str,
+        password: Option<&str>,
+    ) -> Result<(), String> {
+        let expected = self.digests.get(nickname);
+        let actual: [u8; 32] =
+            Sha256::digest(password.unwrap_or("").as_bytes()).into();
+        match (expected, password) {
+            (Some(expected), Some(_)) if constant_time_eq(expected, &actual) => {
+                Ok(())
+            }
+            _ => Err(format!("Wrong password for '{}'", nickname)),
+        }
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0; 32];
+    for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let pair = std::str::from_utf8(pair).ok()?;
+        *byte = u8::from_str_radix(pair, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Compare digests without revealing, through timing, how much of a guess
+/// was right.
+fn constant_time_eq(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authenticate_against_digests() {
+        // printf %s hunter2 | sha256sum
+        let accounts = Accounts::parse(
+            "# comment\n\n\
+             jimb f52fbd32b2b3b86ff88ef6c490628285f482af15ddcb29541f94bcf526a3f6c7\n",
+        )
+        .unwrap();
+        assert_eq!(accounts.authenticate("jimb", Some("hunter2")), Ok(()));
+        assert!(accounts.authenticate("jimb", Some("hunter3")).is_err());
+        assert!(accounts.authenticate("jimb", None).is_err());
+        assert!(accounts.authenticate("ltindall", Some("hunter2")).is_err());
+
+        assert!(Accounts::parse("jimb").is_err());
+        assert!(Accounts::parse("jimb 1234").is_err());
+    }
+}
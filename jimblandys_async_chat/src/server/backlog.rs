@@ -0,0 +1,130 @@
+//! A group's recent messages, kept for replay to members who join late.
+
+use crate::{FromServer, History};
+use std::collections::VecDeque;
+
+pub struct Backlog {
+    next_seq: u64,
+    capacity: usize,
+    /// Messages with sequence numbers `next_seq - messages.len()` up to, but
+    /// not including, `next_seq`.
+    messages: VecDeque<FromServer>,
+}
+
+impl Backlog {
+    pub fn new(capacity: usize) -> Backlog {
+        Backlog {
+            next_seq: 0,
+            capacity,
+            messages: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// The sequence number the next message posted should carry.
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    /// Record `packet`, which must carry sequence number `next_seq()`,
+    /// forgetting the oldest message if the backlog is full.
+    pub fn push(&mut self, packet: FromServer) {
+        if self.capacity == 0 {
+            self.next_seq += 1;
+            return;
+        }
+        if self.messages.len() == self.capacity {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(packet);
+        self.next_seq += 1;
+    }
+
+    /// Record a message read back from a log, which must come after every
+    /// message already recorded. Gaps, left by compaction, are allowed.
+    pub fn restore(&mut self, packet: FromServer) {
+        if let FromServer::Message { seq, .. } = packet {
+            if seq >= self.next_seq {
+                if seq > self.next_seq {
+                    self.messages.clear();
+                }
+                self.next_seq = seq;
+                self.push(packet);
+            }
+        }
+    }
+
+    /// How many of the messages `request` asks for are too old to have been
+    /// retained, so that a replay would leave a gap before its first.
+    pub fn missing(&self, request: History) -> u64 {
+        match request {
+            History::Last(_) => 0,
+            History::Since(seq) => {
+                let first = self.next_seq - self.messages.len() as u64;
+                first.saturating_sub(seq)
+            }
+        }
+    }
+
+    /// The retained messages `request` asks for, oldest first.
+    pub fn replay(&self, request: History) -> Vec<FromServer> {
+        let skip = match request {
+            History::Last(n) => self.messages.len().saturating_sub(n),
+            History::Since(seq) => {
+                let first = self.next_seq - self.messages.len() as u64;
+                seq.saturating_sub(first).min(self.messages.len() as u64)
+                    as usize
+            }
+        };
+        self.messages.iter().skip(skip).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn message(seq: u64) -> FromServer {
+        FromServer::Message {
+            group_name: Arc::new("dogs".to_string()),
+            seq,
+            sender: Arc::new("jimb".to_string()),
+            timestamp: chrono::Utc::now(),
+            message: Arc::new(format!("woof {}", seq)),
+        }
+    }
+
+    fn seqs(packets: Vec<FromServer>) -> Vec<u64> {
+        packets
+            .into_iter()
+            .map(|packet| match packet {
+                FromServer::Message { seq, .. } => seq,
+                other => panic!("unexpected packet {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replay_is_bounded_and_contiguous() {
+        let mut backlog = Backlog::new(3);
+        for seq in 0..5 {
+            backlog.push(message(backlog.next_seq()));
+            assert_eq!(backlog.next_seq(), seq + 1);
+        }
+
+        assert_eq!(seqs(backlog.replay(History::Last(2))), vec![3, 4]);
+        assert_eq!(seqs(backlog.replay(History::Last(10))), vec![2, 3, 4]);
+        assert_eq!(seqs(backlog.replay(History::Last(0))), Vec::<u64>::new());
+        assert_eq!(seqs(backlog.replay(History::Since(0))), vec![2, 3, 4]);
+        assert_eq!(seqs(backlog.replay(History::Since(3))), vec![3, 4]);
+        assert_eq!(seqs(backlog.replay(History::Since(5))), Vec::<u64>::new());
+        assert_eq!(seqs(backlog.replay(History::Since(99))), Vec::<u64>::new());
+
+        // Only a request reaching back past what was kept misses anything.
+        assert_eq!(backlog.missing(History::Since(0)), 2);
+        assert_eq!(backlog.missing(History::Since(1)), 1);
+        assert_eq!(backlog.missing(History::Since(2)), 0);
+        assert_eq!(backlog.missing(History::Since(99)), 0);
+        assert_eq!(backlog.missing(History::Last(10)), 0);
+    }
+}
@@ -0,0 +1,744 @@
+use std::net::SocketAddr;
+
+use crate::utils::{self, ChatResult, FrameTooLong, WireFormat};
+/// Handle a single client's connection.
+use crate::{FromClient, FromServer};
+use async_std::io::{BufReader, Read, Write};
+use async_std::net::TcpStream;
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use chrono::Utc;
+use std::time::Duration;
+use tracing::{debug, info, Instrument, Span};
+
+use crate::server::group::Group;
+use crate::server::group_table::GroupTable;
+use crate::server::heartbeat::{self, Liveness};
+use crate::server::limits::{LimitAction, Limits, TokenBucket};
+use crate::server::users::UserTable;
+use crate::server::ServerState;
+
+/// A TCP stream whose `close` shuts down its sending direction, so the client
+/// sees the end of the stream. (`async_std`'s own `close` only flushes.)
+pub struct TcpConnection(pub TcpStream);
+
+impl Read for TcpConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl Write for TcpConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Poll::Ready(self.0.shutdown(std::net::Shutdown::Write))
+    }
+}
+
+/// Serve the client at `peer` over `socket`, which may be a plain TCP stream
+/// or one wrapped in TLS.
+pub async fn work_connection<S>(
+    socket: S,
+    peer: SocketAddr,
+    state: Arc<ServerState>,
+) -> ChatResult<()>
+where
+    S: Read + Write + Send + Unpin + 'static,
+{
+    let (reader, writer) = futures_lite::io::split(socket);
+    let mut buffered = BufReader::new(reader);
+
+    // The first packet is always JSON. If it's a `Hello`, it says how the
+    // rest of the conversation is framed.
+    let max_line_bytes = state.limits.max_line_bytes;
+    let first = utils::receive_as_json(&mut buffered, max_line_bytes)
+        .next()
+        .await;
+    let format = match &first {
+        Some(Ok(FromClient::Hello { format, .. })) => *format,
+        _ => WireFormat::JsonLines,
+    };
+
+    let to_client = ToClient::Stream(format, Box::new(writer));
+    let outbound = Arc::new(Outbound::new(peer, to_client));
+    let rest = utils::receive_packets(format, buffered, max_line_bytes);
+    let from_client = futures_lite::stream::iter(first).chain(rest);
+
+    serve_connection(from_client, outbound, state).await
+}
+
+/// How long to wait for a departing client to take the rest of its data.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Carry out the requests in `from_client` until it ends, then tidy up after
+/// the connection. This is the part of a connection's life that doesn't
+/// depend on how it reaches the server.
+pub async fn serve_connection(
+    from_client: impl Stream<Item = ChatResult<FromClient>> + Unpin,
+    outbound: Arc<Outbound>,
+    state: Arc<ServerState>,
+) -> ChatResult<()> {
+    // Everything logged on this connection's behalf, including by the
+    // subscriber tasks for the groups it joins, falls within this span.
+    let span = tracing::info_span!(
+        "connection",
+        peer = %outbound.id,
+        nickname = tracing::field::Empty,
+    );
+    tend_connection(from_client, outbound, &state).instrument(span).await
+}
+
+async fn tend_connection(
+    from_client: impl Stream<Item = ChatResult<FromClient>> + Unpin,
+    outbound: Arc<Outbound>,
+    state: &ServerState,
+) -> ChatResult<()> {
+    let ServerState { groups, users, limits, connections, .. } = state;
+    connections.insert(outbound.clone());
+    info!("connected");
+
+    let liveness = Liveness::new();
+    let result = serve(
+        from_client,
+        outbound.clone(),
+        groups,
+        users,
+        limits,
+        &liveness,
+    )
+    .race(heartbeat::watch(&outbound, &liveness, limits))
+    .race(disconnected(&outbound))
+    .await;
+    groups.leave_all(&outbound);
+    if let Some(nickname) = outbound.nickname() {
+        users.unregister(&nickname);
+    }
+    connections.remove(outbound.id);
+
+    let closed = outbound.close(CLOSE_TIMEOUT).await;
+    info!(?closed, "disconnected");
+    result
+}
+
+/// Once something else asks for `outbound` to be disconnected, tell the
+/// client why, if it will listen, and return the reason as an error.
+async fn disconnected(outbound: &Outbound) -> ChatResult<()> {
+    let reason = outbound.disconnected().await;
+    let notice = FromServer::Error(reason.clone());
+    let _ = outbound.send(notice).timeout(CLOSE_TIMEOUT).await;
+    Err(reason.into())
+}
+
+async fn serve(
+    mut from_client: impl Stream<Item = ChatResult<FromClient>> + Unpin,
+    outbound: Arc<Outbound>,
+    groups: &Arc<GroupTable>,
+    users: &UserTable,
+    limits: &Limits,
+    liveness: &Liveness,
+) -> ChatResult<()> {
+    let mut flood = limits.connection_rate.map(TokenBucket::new);
+    while let Some(request_result) = from_client.next().await {
+        let request = match request_result {
+            Ok(request) => request,
+            Err(error) => match error.downcast_ref::<FrameTooLong>() {
+                Some(too_long) => {
+                    let complaint = format!("Request too long: {}", too_long);
+                    over_limit(&outbound, limits, complaint).await?;
+                    continue;
+                }
+                None => return Err(error),
+            },
+        };
+
+        // Heartbeats don't count against the rate limit, or as activity.
+        let heartbeat = matches!(request, FromClient::Ping | FromClient::Pong);
+        if !heartbeat {
+            liveness.request();
+        }
+        if let (Some(bucket), false) = (&mut flood, heartbeat) {
+            if !bucket.try_take() {
+                let complaint = "Too many requests; slow down".to_string();
+                over_limit(&outbound, limits, complaint).await?;
+                continue;
+            }
+        }
+        if let FromClient::Post { message, .. } | FromClient::Whisper { message, .. } =
+            &request
+        {
+            if message.len() > limits.max_message_bytes {
+                let complaint = format!(
+                    "Message longer than {} bytes",
+                    limits.max_message_bytes
+                );
+                over_limit(&outbound, limits, complaint).await?;
+                continue;
+            }
+        }
+
+        let result = match (request, outbound.nickname()) {
+            (FromClient::Ping, _) => {
+                outbound.send(FromServer::Pong).await?;
+                Ok(())
+            }
+
+            (FromClient::Pong, _) => {
+                liveness.pong();
+                Ok(())
+            }
+
+            (FromClient::Hello { nickname, password, .. }, previous) => users
+                .register(
+                    nickname.clone(),
+                    password.as_ref().map(|password| password.as_str()),
+                    previous.as_ref(),
+                    outbound.clone(),
+                )
+                .map(|()| {
+                    Span::current().record("nickname", nickname.as_str());
+                    outbound.set_nickname(nickname)
+                }),
+
+            (_, None) => {
+                Err("Say Hello with a nickname before joining or posting"
+                    .to_string())
+            }
+
+            (
+                FromClient::Join {
+                    group_name,
+                    history,
+                },
+                Some(_),
+            ) => {
+                groups.join(group_name, outbound.clone(), history).await
+            }
+
+            (FromClient::Leave { group_name }, Some(_)) => {
+                match groups.get(&group_name) {
+                    Some(group) => group.leave(&outbound),
+                    None => {
+                        Err(format!("Group '{}' does not exist", group_name))
+                    }
+                }
+            }
+
+            (
+                FromClient::Post {
+                    group_name,
+                    message,
+                    id,
+                },
+                Some(sender),
+            ) => match groups.get(&group_name) {
+                Some(group) => {
+                    // Check access first, so that posts the group would refuse
+                    // anyway don't use up its rate limit.
+                    let allowed =
+                        group.access().check_post(&group_name, &sender);
+                    if allowed.is_ok() && !group.take_post_token() {
+                        let complaint = format!(
+                            "'{}' is busy; message not posted",
+                            group_name
+                        );
+                        over_limit(&outbound, limits, complaint).await?;
+                        continue;
+                    }
+                    let posted = match allowed {
+                        Ok(()) => group.post(sender, message, id).await,
+                        Err(error) => Err(error),
+                    };
+                    match (posted, id) {
+                        (Ok(seq), Some(id)) => {
+                            outbound.send(FromServer::Ack { id, seq }).await?;
+                            Ok(())
+                        }
+                        (posted, _) => posted.map(|_seq| ()),
+                    }
+                }
+                None => Err(format!("Group '{}' does not exist", group_name)),
+            },
+
+            (FromClient::ListGroups, Some(_)) => {
+                let group_names = groups.names();
+                outbound.send(FromServer::Groups { group_names }).await?;
+                Ok(())
+            }
+
+            (FromClient::ListMembers { group_name }, Some(_)) => {
+                match groups.get(&group_name) {
+                    Some(group) => {
+                        let nicknames = group.members();
+                        outbound
+                            .send(FromServer::Members {
+                                group_name,
+                                nicknames,
+                            })
+                            .await?;
+                        Ok(())
+                    }
+                    None => {
+                        Err(format!("Group '{}' does not exist", group_name))
+                    }
+                }
+            }
+
+            (FromClient::Whisper { to, message }, Some(from)) => {
+                match users.get(&to) {
+                    Some(recipient) => {
+                        let packet = FromServer::Private {
+                            from,
+                            timestamp: Utc::now(),
+                            message,
+                        };
+                        // Waiting for room in a slow reader's queue would
+                        // stop us reading our own client's requests.
+                        recipient.try_send(packet).map_err(|error| match error {
+                            TrySendError::Full(()) => format!(
+                                "'{}' is too busy; message not delivered",
+                                to
+                            ),
+                            TrySendError::Closed(()) => {
+                                format!("Could not deliver message to '{}'", to)
+                            }
+                        })
+                    }
+                    None => Err(format!("User '{}' is not online", to)),
+                }
+            }
+
+            (FromClient::Op { group_name, nickname }, Some(by)) => {
+                existing_group(groups, &group_name).and_then(|group| {
+                    group.change_access(|access| {
+                        access.op(&group_name, &by, nickname)
+                    })
+                })
+            }
+
+            (FromClient::Kick { group_name, nickname }, Some(by)) => {
+                expel(groups, users, group_name, &by, nickname, false).await
+            }
+
+            (FromClient::Ban { group_name, nickname }, Some(by)) => {
+                expel(groups, users, group_name, &by, nickname, true).await
+            }
+
+            (FromClient::Unban { group_name, nickname }, Some(by)) => {
+                existing_group(groups, &group_name).and_then(|group| {
+                    group.change_access(|access| {
+                        access.unban(&group_name, &by, &nickname)
+                    })
+                })
+            }
+
+            (FromClient::Invite { group_name, nickname }, Some(by)) => {
+                existing_group(groups, &group_name).and_then(|group| {
+                    group.change_access(|access| {
+                        access.invite(&group_name, &by, nickname)
+                    })
+                })
+            }
+
+            (
+                FromClient::SetInviteOnly {
+                    group_name,
+                    invite_only,
+                },
+                Some(by),
+            ) => existing_group(groups, &group_name).and_then(|group| {
+                group.change_access(|access| {
+                    access.set_invite_only(&group_name, &by, invite_only)
+                })
+            }),
+
+            (FromClient::SetDelivery { group_name, delivery }, Some(by)) => {
+                existing_group(groups, &group_name)
+                    .and_then(|group| group.set_delivery(&by, delivery))
+            }
+        };
+
+        if let Err(message) = result {
+            let report = FromServer::Error(message);
+            outbound.send(report).await?;
+        }
+    }
+    Ok(())
+}
+/// Deal with a request that broke one of `limits`, as they direct. Return an
+/// error if the connection should be closed.
+async fn over_limit(
+    outbound: &Outbound,
+    limits: &Limits,
+    complaint: String,
+) -> ChatResult<()> {
+    match limits.action {
+        LimitAction::Drop => Ok(()),
+        LimitAction::Warn => outbound.send(FromServer::Error(complaint)).await,
+        LimitAction::Disconnect => {
+            let _ = outbound.send(FromServer::Error(complaint.clone())).await;
+            Err(complaint.into())
+        }
+    }
+}
+
+fn existing_group(
+    groups: &GroupTable,
+    group_name: &String,
+) -> Result<Arc<Group>, String> {
+    groups
+        .get(group_name)
+        .ok_or_else(|| format!("Group '{}' does not exist", group_name))
+}
+
+/// Kick `nickname` out of `group_name` on behalf of `by`, banning them too if
+/// `ban` is set. A user can be banned whether or not they are present.
+async fn expel(
+    groups: &GroupTable,
+    users: &UserTable,
+    group_name: Arc<String>,
+    by: &String,
+    nickname: Arc<String>,
+    ban: bool,
+) -> Result<(), String> {
+    let group = existing_group(groups, &group_name)?;
+    if ban {
+        group.change_access(|access| {
+            access.ban(&group_name, by, nickname.clone())
+        })?;
+    } else {
+        group.access().check_kick(&group_name, by, &nickname)?;
+    }
+
+    let target = match users.get(&nickname) {
+        Some(target) => target,
+        None if ban => return Ok(()),
+        None => return Err(format!("User '{}' is not online", nickname)),
+    };
+    match group.expel(&nickname, &target) {
+        Ok(()) => {}
+        Err(_) if ban => return Ok(()),
+        Err(error) => return Err(error),
+    }
+
+    let verb = if ban { "banned" } else { "kicked" };
+    let notice = format!("You were {} from '{}' by {}", verb, group_name, by);
+    // Like a whisper, the notice mustn't wait on a slow reader; one with no
+    // room for it still sees the `Left`.
+    let _ = target.try_send(FromServer::Error(notice));
+    Ok(())
+}
+
+use async_std::channel::{self, Receiver, Sender, TrySendError};
+use async_std::task;
+use async_tungstenite::tungstenite::{Error as WsError, Message};
+use futures_util::{Sink, SinkExt};
+use tokio::sync::oneshot;
+
+/// The sending half of a client connection.
+pub enum ToClient {
+    /// A byte stream, framed as the client asked in its first `Hello`.
+    Stream(WireFormat, Box<dyn Write + Send + Unpin>),
+    /// A WebSocket, carrying each packet as JSON in a text message.
+    WebSocket(Box<dyn Sink<Message, Error = WsError> + Send + Unpin>),
+}
+
+impl ToClient {
+    async fn send(&mut self, packet: &FromServer) -> ChatResult<()> {
+        match self {
+            ToClient::Stream(format, writer) => {
+                utils::send_packet(format, writer, packet).await?;
+                writer.flush().await?;
+            }
+            ToClient::WebSocket(sink) => {
+                let json = serde_json::to_string(packet)?;
+                sink.send(Message::text(json)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn close(&mut self) -> ChatResult<()> {
+        match self {
+            ToClient::Stream(_, writer) => {
+                futures_lite::AsyncWriteExt::close(writer).await?
+            }
+            ToClient::WebSocket(sink) => sink.close().await?,
+        }
+        Ok(())
+    }
+}
+
+/// How many packets may wait for a connection's writer task.
+const OUTGOING_CAPACITY: usize = 64;
+
+/// Work for a connection's writer task.
+enum Outgoing {
+    Packet(FromServer),
+    /// Close the connection once everything before this has been written,
+    /// and say how that went.
+    Close(oneshot::Sender<ChatResult<()>>),
+}
+
+/// The connection's writer task: write each packet from `outgoing` in full,
+/// in order, until asked to close or the connection fails.
+async fn write_packets(mut to_client: ToClient, outgoing: Receiver<Outgoing>) {
+    while let Ok(next) = outgoing.recv().await {
+        match next {
+            Outgoing::Packet(packet) => {
+                if let Err(error) = to_client.send(&packet).await {
+                    debug!(%error, "cannot write to client");
+                    break;
+                }
+            }
+            Outgoing::Close(closed) => {
+                let _ = closed.send(to_client.close().await);
+                break;
+            }
+        }
+    }
+    // Refuse anything sent from now on.
+    outgoing.close();
+}
+
+/// The sending half of a client connection.
+///
+/// Only the connection's writer task writes to the client, so whoever sends
+/// may give up waiting without leaving half a packet on the wire: all that
+/// is abandoned is a place in the queue.
+pub struct Outbound {
+    pub (crate) id: SocketAddr,
+    nickname: std::sync::Mutex<Option<Arc<String>>>,
+    outgoing: Sender<Outgoing>,
+    /// The writer task, until it is cancelled.
+    writer: std::sync::Mutex<Option<task::JoinHandle<()>>>,
+    /// Carries the reason the connection should be closed, once something
+    /// other than the client decides it should. Closed once one is given.
+    disconnect: Sender<String>,
+    disconnect_reason: Receiver<String>,
+}
+
+impl Outbound {
+    pub fn new(id: SocketAddr, to_client: ToClient) -> Outbound {
+        let (disconnect, disconnect_reason) = channel::bounded(1);
+        let (outgoing, to_write) = channel::bounded(OUTGOING_CAPACITY);
+        let writer = task::spawn(write_packets(to_client, to_write));
+        Outbound {
+            outgoing,
+            writer: std::sync::Mutex::new(Some(writer)),
+            nickname: std::sync::Mutex::new(None),
+            id,
+            disconnect,
+            disconnect_reason,
+        }
+    }
+
+    /// The nickname this connection said Hello with, if it has.
+    pub fn nickname(&self) -> Option<Arc<String>> {
+        self.nickname.lock().unwrap().clone()
+    }
+
+    pub fn set_nickname(&self, nickname: Arc<String>) {
+        *self.nickname.lock().unwrap() = Some(nickname);
+    }
+
+    /// Queue `packet` to be written to the client, waiting for room if need
+    /// be. Fails once the connection has failed or been closed.
+    pub async fn send(&self, packet: FromServer) -> ChatResult<()> {
+        self.outgoing
+            .send(Outgoing::Packet(packet))
+            .await
+            .map_err(|_| "connection closed")?;
+        Ok(())
+    }
+
+    /// Queue `packet` to be written to the client if there is room for it
+    /// now. Fails if there isn't, or once the connection has failed or been
+    /// closed.
+    pub fn try_send(&self, packet: FromServer) -> Result<(), TrySendError<()>> {
+        self.outgoing
+            .try_send(Outgoing::Packet(packet))
+            .map_err(|error| match error {
+                TrySendError::Full(_) => TrySendError::Full(()),
+                TrySendError::Closed(_) => TrySendError::Closed(()),
+            })
+    }
+
+    /// Ask for the connection to be closed, telling the client `reason`.
+    /// Return `false` if that has already been asked for.
+    pub fn disconnect(&self, reason: String) -> bool {
+        let first = self.disconnect.try_send(reason).is_ok();
+        self.disconnect.close();
+        first
+    }
+
+    pub fn is_disconnecting(&self) -> bool {
+        self.disconnect.is_closed()
+    }
+
+    /// Resolve with the reason given to `disconnect`, once it is called.
+    pub async fn disconnected(&self) -> String {
+        self.disconnect_reason.recv().await.unwrap_or_default()
+    }
+
+    /// Finish sending what is queued, and let the client know there will be
+    /// nothing more. If that takes longer than `timeout`, drop the connection
+    /// without finishing.
+    pub async fn close(&self, timeout: Duration) -> ChatResult<()> {
+        let (closing, closed) = oneshot::channel();
+        let result = async {
+            self.outgoing
+                .send(Outgoing::Close(closing))
+                .await
+                .map_err(|_| "connection closed")?;
+            closed.await.map_err(|_| "connection closed")?
+        }
+        .timeout(timeout)
+        .await;
+        match result {
+            Ok(result) => result,
+            Err(timed_out) => {
+                let writer = self.writer.lock().unwrap().take();
+                if let Some(writer) = writer {
+                    writer.cancel().await;
+                }
+                Err(timed_out.into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A client that takes one byte at a time, with a pause after each.
+    struct Trickle {
+        written: Arc<std::sync::Mutex<Vec<u8>>>,
+        paused: bool,
+    }
+
+    impl Write for Trickle {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.paused = !self.paused;
+            if !self.paused {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            self.written.lock().unwrap().push(buf[0]);
+            Poll::Ready(Ok(1))
+        }
+
+        fn poll_flush(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn abandoned_sends_leave_whole_packets() {
+        task::block_on(async {
+            let written = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let trickle = Trickle {
+                written: written.clone(),
+                paused: false,
+            };
+            let to_client =
+                ToClient::Stream(WireFormat::JsonLines, Box::new(trickle));
+            let outbound = Outbound::new(([127, 0, 0, 1], 1).into(), to_client);
+
+            // Give up on every other send as soon as it has to wait.
+            for i in 0..20 {
+                let packet = FromServer::Error(format!("packet {}", i));
+                let patience = if i % 2 == 0 {
+                    Duration::ZERO
+                } else {
+                    Duration::from_secs(5)
+                };
+                let _ = outbound.send(packet).timeout(patience).await;
+            }
+            outbound.close(Duration::from_secs(5)).await.unwrap();
+
+            let written = written.lock().unwrap().clone();
+            for line in String::from_utf8(written).unwrap().lines() {
+                serde_json::from_str::<FromServer>(line).unwrap();
+            }
+        });
+    }
+
+    /// A client that never takes anything.
+    struct Stuck;
+
+    impl Write for Stuck {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Pending
+        }
+
+        fn poll_flush(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Poll::Pending
+        }
+
+        fn poll_close(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn full_queues_refuse_instead_of_waiting() {
+        let to_client =
+            ToClient::Stream(WireFormat::JsonLines, Box::new(Stuck));
+        let outbound = Outbound::new(([127, 0, 0, 1], 1).into(), to_client);
+        // The writer task may have taken one packet off the queue to get
+        // stuck on.
+        let refused = (0..OUTGOING_CAPACITY + 2).find_map(|i| {
+            let packet = FromServer::Error(format!("packet {}", i));
+            outbound.try_send(packet).err()
+        });
+        assert!(matches!(refused, Some(TrySendError::Full(()))));
+    }
+}
@@ -0,0 +1,76 @@
+//! The ids of a group's recent posts, so that a post a client sends again,
+//! not having heard it was acknowledged, isn't posted twice.
+//!
+//! Ids are chosen by clients, so they are only distinct per nickname. Only
+//! the most recent are remembered. The group table keeps them after the
+//! group is gone, and a restarted server reads them back from the groups'
+//! logs.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+/// How many recent posts' ids each group remembers, to recognize posts
+/// that clients send again.
+pub const RECENT_POSTS: usize = 1000;
+
+pub struct RecentPosts {
+    capacity: usize,
+    /// The sequence number each post was given, by sender and id.
+    seqs: HashMap<(Arc<String>, u64), u64>,
+    /// The keys of `seqs`, oldest first.
+    order: VecDeque<(Arc<String>, u64)>,
+}
+
+impl RecentPosts {
+    pub fn new(capacity: usize) -> RecentPosts {
+        RecentPosts {
+            capacity,
+            seqs: HashMap::new(),
+            order: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// The sequence number given to `sender`'s post `id`, if we remember it.
+    pub fn get(&self, sender: &Arc<String>, id: u64) -> Option<u64> {
+        self.seqs.get(&(sender.clone(), id)).copied()
+    }
+
+    /// Remember that `sender`'s post `id` was given `seq`, forgetting the
+    /// oldest post if we are remembering as many as we can.
+    pub fn insert(&mut self, sender: Arc<String>, id: u64, seq: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.order.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seqs.remove(&oldest);
+            }
+        }
+        let key = (sender, id);
+        if self.seqs.insert(key.clone(), seq).is_none() {
+            self.order.push_back(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remembers_recent_posts_by_sender() {
+        let jimb = Arc::new("jimb".to_string());
+        let ltindall = Arc::new("ltindall".to_string());
+        let mut recent = RecentPosts::new(2);
+        recent.insert(jimb.clone(), 7, 0);
+        recent.insert(ltindall.clone(), 7, 1);
+        assert_eq!(recent.get(&jimb, 7), Some(0));
+        assert_eq!(recent.get(&ltindall, 7), Some(1));
+        assert_eq!(recent.get(&jimb, 8), None);
+
+        recent.insert(jimb.clone(), 8, 2);
+        assert_eq!(recent.get(&jimb, 7), None);
+        assert_eq!(recent.get(&ltindall, 7), Some(1));
+        assert_eq!(recent.get(&jimb, 8), Some(2));
+    }
+}
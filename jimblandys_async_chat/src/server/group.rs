@@ -0,0 +1,476 @@
+//! A chat group.
+//!
+//! Each member has its own bounded queue of messages, which its subscriber
+//! task forwards to the member's connection. When a member falls so far
+//! behind that its queue is full, the group's `Delivery` policy decides what
+//! becomes of the next message.
+
+use async_std::channel::{Receiver, TrySendError};
+use async_std::prelude::FutureExt as _;
+use async_std::task;
+use crate::server::access::Access;
+use crate::server::backlog::Backlog;
+use crate::server::dedup::RecentPosts;
+use crate::server::group_table::GroupTable;
+use crate::server::limits::{Rate, TokenBucket};
+use crate::server::participants::{GroupMembers, MemberId, Membership};
+use crate::server::store::GroupLog;
+use crate::server::connection::Outbound;
+use crate::server::metrics::Metrics;
+use crate::{Delivery, History};
+use chrono::Utc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tracing::{debug, trace, warn, Instrument};
+
+/// How every group on the server behaves.
+#[derive(Clone, Debug)]
+pub struct GroupSettings {
+    /// How fast posts are accepted, if that is limited.
+    pub post_rate: Option<Rate>,
+    /// How many messages may wait in each member's queue.
+    pub queue_capacity: usize,
+    /// What new groups do when a member's queue is full.
+    pub delivery: Delivery,
+    /// How many recent messages are kept for replay.
+    pub history: usize,
+    /// How many members a group may have, if that is limited.
+    pub max_members: Option<usize>,
+}
+
+pub struct Group {
+    name: Arc<String>,
+    participants: GroupMembers,
+    queue_capacity: usize,
+    delivery: Mutex<Delivery>,
+    /// Held while a message is delivered, so that posts reach each member's
+    /// queue in order, even while a poster waits for room.
+    posting: async_std::sync::Mutex<()>,
+    /// Held while a message is sequenced and the members to deliver it to
+    /// are chosen, and while a member joins, so that a joiner's replay and
+    /// queue agree on where history ends and live messages begin.
+    backlog: Mutex<Backlog>,
+    /// Set, with `backlog` locked, once the group table has let the group
+    /// go. Posts that find it set have nowhere to go.
+    removed: AtomicBool,
+    /// Where posts are persisted, if anywhere. Appended to only while
+    /// `backlog` is locked, so the log's order matches the sequence numbers.
+    log: Option<GroupLog>,
+    /// The ids of recent posts. Locked only while `backlog` is, so that a
+    /// post sent twice at once is only sequenced once. Shared with the group
+    /// table, like `access`.
+    recent_posts: Arc<Mutex<RecentPosts>>,
+    /// Shared with the group table, which keeps it after the group is gone.
+    access: Arc<Mutex<Access>>,
+    /// Limits how fast messages may be posted, if it is limited.
+    flood: Option<Mutex<TokenBucket>>,
+    max_members: Option<usize>,
+    metrics: Arc<Metrics>,
+}
+impl Group {
+    /// Create a group as `settings` direct, resuming from `persisted`
+    /// history and log if given, governed by `access`, remembering posts in
+    /// `recent_posts`, and counting its traffic in `metrics`.
+    pub fn new(
+        name: Arc<String>,
+        persisted: Option<(Backlog, GroupLog)>,
+        access: Arc<Mutex<Access>>,
+        recent_posts: Arc<Mutex<RecentPosts>>,
+        settings: &GroupSettings,
+        metrics: Arc<Metrics>,
+    ) -> Group {
+        let (backlog, log) = match persisted {
+            Some((backlog, log)) => (backlog, Some(log)),
+            None => (Backlog::new(settings.history), None),
+        };
+        Group {
+            name,
+            participants: GroupMembers::new(),
+            queue_capacity: settings.queue_capacity,
+            delivery: Mutex::new(settings.delivery),
+            posting: async_std::sync::Mutex::new(()),
+            backlog: Mutex::new(backlog),
+            removed: AtomicBool::new(false),
+            log,
+            recent_posts,
+            access,
+            flood: settings
+                .post_rate
+                .map(|rate| Mutex::new(TokenBucket::new(rate))),
+            max_members: settings.max_members,
+            metrics,
+        }
+    }
+
+    pub fn name(&self) -> &Arc<String> {
+        &self.name
+    }
+
+    /// The group's owner, operators, invitations and bans.
+    pub fn access(&self) -> MutexGuard<'_, Access> {
+        self.access.lock().unwrap()
+    }
+
+    /// Apply `change` to the group's access, and save the result to the
+    /// group's log if it succeeds.
+    pub fn change_access(
+        &self,
+        change: impl FnOnce(&mut Access) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let mut access = self.access();
+        change(&mut access)?;
+        self.save_access(&access);
+        Ok(())
+    }
+
+    fn save_access(&self, access: &Access) {
+        if let Some(log) = &self.log {
+            log.save_access(access);
+        }
+    }
+
+    /// Add `outbound` to the group and start forwarding the group's messages
+    /// to it, until it leaves or its connection fails. The messages `history`
+    /// asks for are sent first.
+    ///
+    /// This is only called by `table.join`, with the table locked, and the
+    /// member is removed through `table.remove_member`.
+    pub fn join_and_leave_cycle(
+        self: &Arc<Self>,
+        table: Arc<GroupTable>,
+        outbound: Arc<Outbound>,
+        history: Option<History>,
+    ) -> Result<(), String> {
+        // Hold the access rules until the join is done, so that only a user
+        // who actually joins can become the group's owner.
+        let nickname = outbound.nickname();
+        let mut access = self.access();
+        if let Some(nickname) = &nickname {
+            access.admit(&self.name, nickname)?;
+        }
+        let (membership, replay, first_live_seq) = {
+            let backlog = self.backlog.lock().unwrap();
+            let membership = self.participants.join(
+                self.name.as_str(),
+                outbound.clone(),
+                self.max_members,
+                self.queue_capacity,
+            )?;
+            // A replay that can't reach back as far as asked starts by
+            // saying so, rather than leaving the client to miss the gap.
+            let mut replay = Vec::new();
+            if let Some(request) = history {
+                let missing = backlog.missing(request);
+                if missing > 0 {
+                    replay.push(FromServer::Error(format!(
+                        "{} messages from {} are too old to replay.",
+                        missing, self.name
+                    )));
+                }
+                replay.extend(backlog.replay(request));
+            }
+            (membership, replay, backlog.next_seq())
+        };
+        if let Some(nickname) = &nickname {
+            if access.claim(nickname) {
+                self.save_access(&access);
+            }
+        }
+        drop(access);
+
+        table.subscriber_started();
+        let span = tracing::info_span!("group", name = %self.name);
+        task::spawn(
+            handle_subscriber(
+                self.clone(),
+                table,
+                Subscription {
+                    replay,
+                    first_live_seq,
+                    membership,
+                },
+                outbound,
+            )
+            .instrument(span),
+        );
+        Ok(())
+    }
+
+    pub fn leave(&self, outbound: &Outbound) -> Result<(), String> {
+        self.participants.request_leave(self.name.as_str(), outbound.id)
+    }
+
+    /// Make `target`, whose nickname is `nickname`, leave the group. The
+    /// caller is responsible for checking that this is allowed.
+    pub fn expel(&self, nickname: &str, target: &Outbound) -> Result<(), String> {
+        self.participants
+            .request_leave(self.name.as_str(), target.id)
+            .map_err(|_| {
+                format!("'{}' is not a member of '{}'", nickname, self.name)
+            })
+    }
+
+    /// Remove `member`, returning how many members remain.
+    pub fn remove_member(&self, member: MemberId) -> usize {
+        self.participants.leave(&self.name, member)
+    }
+
+    pub fn members(&self) -> Vec<Arc<String>> {
+        self.participants.nicknames()
+    }
+
+    /// Make every member leave, as the server shuts down.
+    pub fn request_leave_all(&self) {
+        self.participants.request_leave_all();
+    }
+
+    /// Note that the group table has let the group go. Wait for any post
+    /// being sequenced to finish first, so that every post is either
+    /// sequenced before this or refused.
+    pub fn set_removed(&self) {
+        let _backlog = self.backlog.lock().unwrap();
+        self.removed.store(true, Ordering::SeqCst);
+    }
+
+    pub fn member_count(&self) -> usize {
+        self.participants.len()
+    }
+
+    /// Change what happens to messages for members who fall behind, on
+    /// behalf of the operator `by`.
+    pub fn set_delivery(
+        &self,
+        by: &String,
+        delivery: Delivery,
+    ) -> Result<(), String> {
+        self.access().check_op(&self.name, by)?;
+        *self.delivery.lock().unwrap() = delivery;
+        debug!(group = %self.name, ?delivery, "delivery policy changed");
+        Ok(())
+    }
+
+    /// Use up one post's worth of the group's rate limit. Return `false` if
+    /// the group is receiving posts faster than it allows.
+    pub fn take_post_token(&self) -> bool {
+        match &self.flood {
+            Some(bucket) => bucket.lock().unwrap().try_take(),
+            None => true,
+        }
+    }
+
+    /// Sequence a message and queue it for every member, as the group's
+    /// delivery policy directs for those with no room for it. Return its
+    /// sequence number.
+    ///
+    /// If `sender` has recently posted with the same `id`, don't post it
+    /// again; just return the number it was given then. If the group table
+    /// has let the group go since the caller found it, refuse the post.
+    pub async fn post(
+        &self,
+        sender: Arc<String>,
+        message: Arc<String>,
+        id: Option<u64>,
+    ) -> Result<u64, String> {
+        self.access().check_post(&self.name, &sender)?;
+        let _posting = self.posting.lock().await;
+        let (seq, packet, members) = {
+            let mut backlog = self.backlog.lock().unwrap();
+            if self.removed.load(Ordering::SeqCst) {
+                return Err(format!("Group '{}' does not exist", self.name));
+            }
+            let mut recent_posts = self.recent_posts.lock().unwrap();
+            if let Some(id) = id {
+                if let Some(seq) = recent_posts.get(&sender, id) {
+                    debug!(group = %self.name, id, seq, "repeated post");
+                    return Ok(seq);
+                }
+                recent_posts.insert(sender.clone(), id, backlog.next_seq());
+            }
+            let seq = backlog.next_seq();
+            let packet = FromServer::Message {
+                group_name: self.name.clone(),
+                seq,
+                sender,
+                timestamp: Utc::now(),
+                message,
+            };
+            if let Some(log) = &self.log {
+                log.append(seq, packet.clone(), id);
+            }
+            backlog.push(packet.clone());
+            (seq, packet, self.participants.queues())
+        };
+        self.metrics.posted.fetch_add(1, Ordering::Relaxed);
+
+        let delivery = *self.delivery.lock().unwrap();
+        // Blocking waits for all the members together, not each in turn.
+        let patience = match delivery {
+            Delivery::Block { timeout_ms } => Duration::from_millis(timeout_ms),
+            _ => Duration::ZERO,
+        };
+        let deadline = Instant::now() + patience;
+        let dropped = &self.metrics.dropped;
+        for (outbound, queue) in members {
+            // A closed queue belongs to a member on its way out.
+            let packet = match queue.try_send(packet.clone()) {
+                Ok(()) | Err(TrySendError::Closed(_)) => continue,
+                Err(TrySendError::Full(packet)) => packet,
+            };
+            match delivery {
+                Delivery::Block { .. } => {
+                    let wait = deadline - Instant::now().min(deadline);
+                    if queue.send(packet).timeout(wait).await.is_ok() {
+                        continue;
+                    }
+                }
+                Delivery::Disconnect => {
+                    let reason = format!(
+                        "Too far behind in '{}'; disconnecting",
+                        self.name
+                    );
+                    if outbound.disconnect(reason) {
+                        warn!(member = %outbound.id, "disconnecting laggard");
+                        let disconnected = &self.metrics.disconnected;
+                        disconnected.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                Delivery::Drop => {}
+            }
+            dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(seq)
+    }
+}
+
+/// What a new member receives: the replayed history, then every message
+/// from `first_live_seq` on.
+struct Subscription {
+    replay: Vec<FromServer>,
+    first_live_seq: u64,
+    membership: Membership,
+}
+
+use crate::FromServer;
+use futures_lite::future::FutureExt;
+use tokio::sync::oneshot;
+
+async fn handle_subscriber(
+    group: Arc<Group>,
+    table: Arc<GroupTable>,
+    subscription: Subscription,
+    outbound: Arc<Outbound>,
+) {
+    let group_name = group.name.clone();
+    let member_id = outbound.id;
+    let Membership { leave_requested, queue } = subscription.membership;
+    let mut left = false;
+    if replay(subscription.replay, &outbound, &group.metrics).await {
+        left = loop_subscriber(
+            group_name.clone(),
+            queue,
+            subscription.first_live_seq,
+            leave_requested,
+            outbound.clone(),
+            &group.metrics,
+        )
+        .await;
+    }
+    table.remove_member(&group, member_id);
+    // A connection being dropped for falling behind may never read this.
+    if left && !outbound.is_disconnecting() {
+        let _ = outbound.send(FromServer::Left { group_name }).await;
+    }
+    table.subscriber_finished();
+}
+
+/// Send the replayed history to `outbound`. Return `false` if the connection
+/// failed.
+async fn replay(
+    packets: Vec<FromServer>,
+    outbound: &Outbound,
+    metrics: &Metrics,
+) -> bool {
+    for packet in packets {
+        if let Err(error) = outbound.send(packet).await {
+            metrics.send_errors.fetch_add(1, Ordering::Relaxed);
+            debug!(%error, "replay failed");
+            return false;
+        }
+    }
+    true
+}
+
+/// Forward the group's messages from `queue` to `outbound`, telling it
+/// about any it missed along the way. Return `true` if the loop ended
+/// because the member asked to leave.
+async fn loop_subscriber(
+    group_name: Arc<String>,
+    queue: Receiver<FromServer>,
+    mut next_seq: u64,
+    mut leave_requested: oneshot::Receiver<()>,
+    outbound: Arc<Outbound>,
+    metrics: &Metrics,
+) -> bool {
+    loop {
+        let received = async { Some(queue.recv().await) }
+            .or(async {
+                let _ = (&mut leave_requested).await;
+                None
+            })
+            .await;
+        let packet = match received {
+            Some(Ok(packet)) => packet,
+            Some(Err(_closed)) => {
+                debug!("group closed");
+                return false;
+            }
+            None => {
+                debug!("leaving");
+                return true;
+            }
+        };
+
+        // Messages skipped by the delivery policy leave a gap in the
+        // sequence numbers.
+        let mut packets = Vec::with_capacity(2);
+        if let FromServer::Message { seq, .. } = packet {
+            if seq > next_seq {
+                let missed = seq - next_seq;
+                warn!(missed, "member fell behind");
+                packets.push(FromServer::Error(format!(
+                    "Dropped {} messages from {}.",
+                    missed, group_name
+                )));
+            }
+            next_seq = seq + 1;
+        }
+        trace!("forwarding message");
+        packets.push(packet);
+
+        for packet in packets {
+            // A connection that has stopped reading can hold up a send
+            // indefinitely; leaving shouldn't have to wait for it.
+            let is_message = matches!(packet, FromServer::Message { .. });
+            let sent = async { Some(outbound.send(packet).await) }
+                .or(async {
+                    let _ = (&mut leave_requested).await;
+                    None
+                })
+                .await;
+            match sent {
+                Some(Ok(())) => {
+                    if is_message {
+                        metrics.delivered.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                Some(Err(error)) => {
+                    metrics.send_errors.fetch_add(1, Ordering::Relaxed);
+                    debug!(%error, "send failed");
+                    return false;
+                }
+                None => return true,
+            }
+        }
+    }
+}
@@ -0,0 +1,546 @@
+use crate::server::access::Access;
+use crate::server::connection::Outbound;
+use crate::server::dedup::{RecentPosts, RECENT_POSTS};
+use crate::server::group::{Group, GroupSettings};
+use crate::server::metrics::Metrics;
+use crate::server::participants::MemberId;
+use crate::server::store::Store;
+use crate::History;
+use async_std::channel::{self, Receiver, Sender};
+use futures_lite::future::block_on;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+/// How many groups that have emptied to remember the access rules and
+/// recent posts of, besides those of the groups there are.
+const MAX_RETIRED: usize = 1000;
+
+/// Every group on the server.
+///
+/// A group exists while it has members, so its comings and goings hinge on
+/// joins and leaves. Both happen with `groups` locked, so that a group can't
+/// be removed for want of members just as someone joins it.
+pub struct GroupTable {
+    groups: Mutex<HashMap<Arc<String>, Arc<Group>>>,
+    /// Groups being created, whose logs are still being opened, by name.
+    /// Each receiver reports, by closing, when its group is in `groups` or
+    /// has failed to be. Locked only while `groups` is.
+    opening: Mutex<HashMap<Arc<String>, Receiver<()>>>,
+    /// Every group's access rules, by name. These outlive the groups, so a
+    /// group that empties and is created again keeps its owner and bans.
+    access: Mutex<HashMap<Arc<String>, Arc<Mutex<Access>>>>,
+    /// The ids of every group's recent posts, by name. These outlive the
+    /// groups too, so a post sent again after its group was recreated is
+    /// still recognized.
+    recent_posts: Mutex<HashMap<Arc<String>, Arc<Mutex<RecentPosts>>>>,
+    /// The names of groups that have emptied, oldest first, whose entries in
+    /// `access` and `recent_posts` are kept. Past `max_retired` of them, the
+    /// oldest are forgotten; a store still has them, if there is one.
+    /// Locked only while `groups` is.
+    retired: Mutex<VecDeque<Arc<String>>>,
+    max_retired: usize,
+    store: Option<Store>,
+    settings: GroupSettings,
+    /// How many groups there may be, if that is limited.
+    max_groups: Option<usize>,
+    /// How many subscriber tasks are running. A task outlives its membership
+    /// long enough to tell the client it has left.
+    subscribers: AtomicUsize,
+    /// Shared with every group, which counts its traffic here.
+    metrics: Arc<Metrics>,
+}
+
+impl GroupTable {
+    pub fn new(
+        store: Option<Store>,
+        settings: GroupSettings,
+        max_groups: Option<usize>,
+        metrics: Arc<Metrics>,
+    ) -> GroupTable {
+        GroupTable {
+            groups: Mutex::new(HashMap::new()),
+            opening: Mutex::new(HashMap::new()),
+            access: Mutex::new(HashMap::new()),
+            recent_posts: Mutex::new(HashMap::new()),
+            retired: Mutex::new(VecDeque::new()),
+            max_retired: MAX_RETIRED,
+            store,
+            settings,
+            max_groups,
+            subscribers: AtomicUsize::new(0),
+            metrics,
+        }
+    }
+
+    /// Recreate every group found in the store, with its history, recent
+    /// posts and access rules.
+    pub fn restore(&self) -> io::Result<()> {
+        if let Some(store) = &self.store {
+            for name in store.group_names()? {
+                // Nothing else is running yet, so there is no one to hold
+                // up by waiting here.
+                let group = Arc::new(block_on(self.create(name.clone())));
+                self.groups.lock().unwrap().insert(name, group);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, name: &String) -> Option<Arc<Group>> {
+        self.groups.lock()
+            .unwrap()
+            .get(name)
+            .cloned()
+    }
+
+    /// The names of every group, sorted.
+    pub fn names(&self) -> Vec<Arc<String>> {
+        let mut names: Vec<_> = self.groups.lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Every group's name and number of members, sorted by name.
+    pub fn member_counts(&self) -> Vec<(Arc<String>, usize)> {
+        let mut counts: Vec<_> = self
+            .all()
+            .iter()
+            .map(|group| (group.name().clone(), group.member_count()))
+            .collect();
+        counts.sort();
+        counts
+    }
+
+    fn all(&self) -> Vec<Arc<Group>> {
+        self.groups.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Make every member of every group leave.
+    pub fn request_leave_all(&self) {
+        for group in self.all() {
+            group.request_leave_all();
+        }
+    }
+
+    /// Make `outbound` leave every group it is a member of.
+    pub fn leave_all(&self, outbound: &Outbound) {
+        for group in self.all() {
+            let _ = group.leave(outbound);
+        }
+    }
+
+    pub fn subscriber_started(&self) {
+        self.subscribers.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn subscriber_finished(&self) {
+        self.subscribers.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// How many subscriber tasks have yet to finish.
+    pub fn subscriber_count(&self) -> usize {
+        self.subscribers.load(Ordering::SeqCst)
+    }
+
+    /// Flush every group's log to stable storage.
+    pub async fn sync(&self) -> io::Result<()> {
+        match &self.store {
+            Some(store) => store.sync().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Add `outbound` to the group named `name`, creating the group if need
+    /// be, and send it the messages `history` asks for.
+    pub async fn join(
+        self: &Arc<Self>,
+        name: Arc<String>,
+        outbound: Arc<Outbound>,
+        history: Option<History>,
+    ) -> Result<(), String> {
+        if name.is_empty() {
+            return Err("Group names cannot be empty".to_string());
+        }
+        loop {
+            // The guards must be gone before we wait for anything, so the
+            // block just decides what to do.
+            let next = {
+                let groups = self.groups.lock().unwrap();
+                let mut opening = self.opening.lock().unwrap();
+                if let Some(group) = groups.get(&name) {
+                    return group.join_and_leave_cycle(
+                        self.clone(),
+                        outbound,
+                        history,
+                    );
+                }
+                match opening.get(&name) {
+                    Some(opened) => Err(opened.clone()),
+                    None => {
+                        let count = groups.len() + opening.len();
+                        if self.max_groups.is_some_and(|max| count >= max) {
+                            return Err(format!(
+                                "Too many groups; cannot create '{}'",
+                                name
+                            ));
+                        }
+                        let (done, opened) = channel::bounded(1);
+                        opening.insert(name.clone(), opened);
+                        // The group's rules and posts are in use again.
+                        self.retired.lock().unwrap().retain(|n| *n != name);
+                        Ok(done)
+                    }
+                }
+            };
+            match next {
+                Ok(done) => {
+                    let opening = Opening {
+                        table: self,
+                        name: &name,
+                        _done: done,
+                    };
+                    return self.join_new(opening, outbound, history).await;
+                }
+                // Someone else is creating the group; join it once they
+                // have.
+                Err(opened) => {
+                    let _ = opened.recv().await;
+                }
+            }
+        }
+    }
+
+    /// Create the group `opening` holds the place of, add it to the table,
+    /// and make `outbound` its first member.
+    async fn join_new(
+        self: &Arc<Self>,
+        opening: Opening<'_>,
+        outbound: Arc<Outbound>,
+        history: Option<History>,
+    ) -> Result<(), String> {
+        // Waiting for the store to open the group's log mustn't hold up
+        // every other join and leave, so this happens with `groups`
+        // unlocked. Other joins of this name wait for us meanwhile.
+        let name = opening.name.clone();
+        let group = Arc::new(self.create(name.clone()).await);
+        let mut groups = self.groups.lock().unwrap();
+        groups.insert(name.clone(), group.clone());
+        drop(opening);
+        let joined =
+            group.join_and_leave_cycle(self.clone(), outbound, history);
+        if joined.is_err() {
+            groups.remove(&name);
+            group.set_removed();
+            self.retire(name);
+        }
+        joined
+    }
+
+    /// Remove `member` from `group`, and the group from the table if that
+    /// leaves it empty.
+    pub fn remove_member(&self, group: &Arc<Group>, member: MemberId) {
+        let mut groups = self.groups.lock().unwrap();
+        if group.remove_member(member) > 0 {
+            return;
+        }
+        let current = groups.get(group.name());
+        if current.is_some_and(|current| Arc::ptr_eq(current, group)) {
+            groups.remove(group.name());
+            group.set_removed();
+            self.retire(group.name().clone());
+        }
+    }
+
+    /// Note that the group `name` has gone, forgetting the access rules and
+    /// recent posts of the groups that went longest ago if there are too
+    /// many. The caller must hold the `groups` lock.
+    fn retire(&self, name: Arc<String>) {
+        let mut retired = self.retired.lock().unwrap();
+        retired.push_back(name);
+        while retired.len() > self.max_retired {
+            if let Some(oldest) = retired.pop_front() {
+                self.access.lock().unwrap().remove(&oldest);
+                self.recent_posts.lock().unwrap().remove(&oldest);
+            }
+        }
+    }
+
+    /// Build a group, picking up its history from the store if there is one,
+    /// and its access rules and recent posts from any earlier group of the
+    /// same name.
+    async fn create(&self, name: Arc<String>) -> Group {
+        let history = self.settings.history;
+        let (persisted, logged_posts) = match &self.store {
+            Some(store) => match store.open_group(&name, history).await {
+                Ok((backlog, recent_posts, log)) => {
+                    (Some((backlog, log)), Some(recent_posts))
+                }
+                Err(error) => {
+                    warn!(
+                        group = %name,
+                        %error,
+                        "cannot open log; not persisting this group"
+                    );
+                    (None, None)
+                }
+            },
+            None => (None, None),
+        };
+        // Access rules forgotten since the group last emptied, or never yet
+        // read, are still in the store. Opening the log above waited for
+        // any earlier save to finish.
+        let known = self.access.lock().unwrap().contains_key(&name);
+        let saved = match (&self.store, &persisted) {
+            (Some(store), Some(_)) if !known => {
+                store.load_access(&name).unwrap_or_else(|error| {
+                    warn!(group = %name, %error, "cannot load access rules");
+                    None
+                })
+            }
+            _ => None,
+        };
+        let access = self
+            .access
+            .lock()
+            .unwrap()
+            .entry(name.clone())
+            .or_insert_with(|| Arc::new(Mutex::new(saved.unwrap_or_default())))
+            .clone();
+        // An earlier group of this name knows at least as much as its log.
+        let recent_posts = self
+            .recent_posts
+            .lock()
+            .unwrap()
+            .entry(name.clone())
+            .or_insert_with(|| {
+                let recent_posts = logged_posts
+                    .unwrap_or_else(|| RecentPosts::new(RECENT_POSTS));
+                Arc::new(Mutex::new(recent_posts))
+            })
+            .clone();
+        Group::new(
+            name,
+            persisted,
+            access,
+            recent_posts,
+            &self.settings,
+            self.metrics.clone(),
+        )
+    }
+}
+
+/// The place of a group being created. Dropping it, once the group is in
+/// the table or has failed to be made, lets other joins of that name go on.
+struct Opening<'a> {
+    table: &'a GroupTable,
+    name: &'a Arc<String>,
+    _done: Sender<()>,
+}
+
+impl Drop for Opening<'_> {
+    fn drop(&mut self) {
+        self.table.opening.lock().unwrap().remove(self.name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::connection::ToClient;
+    use crate::server::store::StoreConfig;
+    use crate::Delivery;
+    use async_std::task;
+    use std::time::Duration;
+
+    fn outbound(port: u16) -> Arc<Outbound> {
+        let sink = Box::new(async_std::io::sink());
+        let to_client = ToClient::Stream(Default::default(), sink);
+        Arc::new(Outbound::new(([127, 0, 0, 1], port).into(), to_client))
+    }
+
+    fn settings() -> GroupSettings {
+        GroupSettings {
+            post_rate: None,
+            queue_capacity: 1000,
+            delivery: Delivery::Drop,
+            history: 100,
+            max_members: None,
+        }
+    }
+
+    #[test]
+    fn groups_last_exactly_as_long_as_their_members() {
+        task::block_on(async {
+            let table = Arc::new(GroupTable::new(
+                None,
+                settings(),
+                None,
+                Arc::default(),
+            ));
+            let dogs = Arc::new("dogs".to_string());
+            let (alice, bob) = (outbound(1), outbound(2));
+
+            table.join(dogs.clone(), alice.clone(), None).await.unwrap();
+            let first = table.get(&dogs).unwrap();
+            first.leave(&alice).unwrap();
+            while table.get(&dogs).is_some() {
+                task::sleep(Duration::from_millis(10)).await;
+            }
+
+            table.join(dogs.clone(), bob.clone(), None).await.unwrap();
+            let second = table.get(&dogs).unwrap();
+            assert!(!Arc::ptr_eq(&first, &second));
+
+            // News from the old group, emptied again, mustn't take the new
+            // one down with it.
+            table.remove_member(&first, alice.id);
+            assert!(Arc::ptr_eq(&table.get(&dogs).unwrap(), &second));
+
+            // A post that found the old group just before the table let it
+            // go is refused, rather than acknowledged and lost.
+            let alice_name = Arc::new("alice".to_string());
+            let message = Arc::new("woof".to_string());
+            assert_eq!(
+                first.post(alice_name, message, Some(1)).await,
+                Err("Group 'dogs' does not exist".to_string())
+            );
+        });
+    }
+
+    #[test]
+    fn only_a_member_can_become_owner() {
+        task::block_on(async {
+            let mut full = settings();
+            full.max_members = Some(0);
+            let table =
+                Arc::new(GroupTable::new(None, full, None, Arc::default()));
+            let dogs = Arc::new("dogs".to_string());
+            let alice_name = Arc::new("alice".to_string());
+            let alice = outbound(1);
+            alice.set_nickname(alice_name.clone());
+
+            assert_eq!(
+                table.join(dogs.clone(), alice, None).await,
+                Err("'dogs' is full".to_string())
+            );
+            let access = table.access.lock().unwrap()[&dogs].clone();
+            assert!(!access.lock().unwrap().is_op(&alice_name));
+        });
+    }
+
+    #[test]
+    fn only_so_many_emptied_groups_are_remembered() {
+        task::block_on(async {
+            let mut table =
+                GroupTable::new(None, settings(), None, Arc::default());
+            table.max_retired = 1;
+            let table = Arc::new(table);
+            let alice = outbound(1);
+            alice.set_nickname(Arc::new("alice".to_string()));
+            let join_and_leave = |name: &str| {
+                let name = Arc::new(name.to_string());
+                let (table, alice) = (table.clone(), alice.clone());
+                async move {
+                    let joined = table.join(name.clone(), alice.clone(), None);
+                    joined.await.unwrap();
+                    table.get(&name).unwrap().leave(&alice).unwrap();
+                    while table.get(&name).is_some() {
+                        task::sleep(Duration::from_millis(10)).await;
+                    }
+                }
+            };
+            let remembered = |name: &str| {
+                let name = name.to_string();
+                let access = table.access.lock().unwrap().contains_key(&name);
+                let posts =
+                    table.recent_posts.lock().unwrap().contains_key(&name);
+                assert_eq!(access, posts);
+                access
+            };
+
+            // `dogs` empties first, but is in use again by the time the
+            // others empty, so it is `cats` that makes way for `birds`.
+            join_and_leave("dogs").await;
+            let dogs = Arc::new("dogs".to_string());
+            table.join(dogs, outbound(2), None).await.unwrap();
+            join_and_leave("cats").await;
+            join_and_leave("birds").await;
+            assert!(remembered("dogs"));
+            assert!(!remembered("cats"));
+            assert!(remembered("birds"));
+            assert_eq!(table.retired.lock().unwrap().len(), 1);
+        });
+    }
+
+    #[test]
+    fn forgotten_access_rules_come_back_from_the_store() {
+        task::block_on(async {
+            let dir = std::env::temp_dir()
+                .join(format!("async-chat-retired-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            let store = Store::open(StoreConfig::new(dir.clone())).unwrap();
+            let mut table =
+                GroupTable::new(Some(store), settings(), None, Arc::default());
+            table.max_retired = 0;
+            let table = Arc::new(table);
+            let dogs = Arc::new("dogs".to_string());
+            let (alice, bob) = (outbound(1), outbound(2));
+            let alice_name = Arc::new("alice".to_string());
+            alice.set_nickname(alice_name.clone());
+            bob.set_nickname(Arc::new("bob".to_string()));
+
+            table.join(dogs.clone(), alice.clone(), None).await.unwrap();
+            table.get(&dogs).unwrap().leave(&alice).unwrap();
+            while table.get(&dogs).is_some() {
+                task::sleep(Duration::from_millis(10)).await;
+            }
+            assert!(table.access.lock().unwrap().is_empty());
+
+            table.join(dogs.clone(), bob, None).await.unwrap();
+            let group = table.get(&dogs).unwrap();
+            assert!(group.access().is_op(&alice_name));
+
+            std::fs::remove_dir_all(&dir).unwrap();
+        });
+    }
+
+    #[test]
+    fn joins_meet_in_a_group_whose_log_is_being_opened() {
+        task::block_on(async {
+            let dir = std::env::temp_dir()
+                .join(format!("async-chat-opening-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            let store = Store::open(StoreConfig::new(dir.clone())).unwrap();
+            let table = Arc::new(GroupTable::new(
+                Some(store),
+                settings(),
+                Some(1),
+                Arc::default(),
+            ));
+            let dogs = Arc::new("dogs".to_string());
+
+            // Both joins arrive while the group's log is still being opened,
+            // and must end up in the same group, which counts only once
+            // against the limit of one.
+            let (alice, bob) = (outbound(1), outbound(2));
+            let (first, second) = futures_lite::future::zip(
+                table.join(dogs.clone(), alice, None),
+                table.join(dogs.clone(), bob, None),
+            )
+            .await;
+            first.unwrap();
+            second.unwrap();
+            assert_eq!(table.get(&dogs).unwrap().member_count(), 2);
+            assert!(table.opening.lock().unwrap().is_empty());
+
+            std::fs::remove_dir_all(&dir).unwrap();
+        });
+    }
+}
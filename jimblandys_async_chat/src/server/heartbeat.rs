@@ -0,0 +1,227 @@
+//! Noticing connections that have gone quiet.
+//!
+//! A client whose machine crashes or drops off the network never closes its
+//! connection, so without probing we would wait on it forever. The server
+//! pings every connection periodically and drops those that stop answering,
+//! and can also drop connections that answer but never ask for anything.
+
+use crate::utils::ChatResult;
+use crate::FromServer;
+use async_std::prelude::FutureExt as _;
+use async_std::task;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::server::connection::Outbound;
+use crate::server::limits::Limits;
+
+/// What we have heard from a connection lately.
+pub struct Liveness {
+    missed_pongs: AtomicU32,
+    last_request: Mutex<Instant>,
+}
+
+impl Liveness {
+    pub fn new() -> Liveness {
+        Liveness {
+            missed_pongs: AtomicU32::new(0),
+            last_request: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Note that the client answered a ping.
+    pub fn pong(&self) {
+        self.missed_pongs.store(0, Ordering::SeqCst);
+    }
+
+    /// Note that the client asked for something.
+    pub fn request(&self) {
+        *self.last_request.lock().unwrap() = Instant::now();
+    }
+}
+
+/// Ping `outbound` and watch `liveness` as `limits` direct. Return an error
+/// once the connection should be dropped; until then, never return.
+pub async fn watch(
+    outbound: &Outbound,
+    liveness: &Liveness,
+    limits: &Limits,
+) -> ChatResult<()> {
+    ping(outbound, liveness, limits)
+        .race(watch_idle(outbound, liveness, limits))
+        .await
+}
+
+async fn ping(
+    outbound: &Outbound,
+    liveness: &Liveness,
+    limits: &Limits,
+) -> ChatResult<()> {
+    let interval = match limits.ping_interval {
+        Some(interval) => interval,
+        None => return futures_lite::future::pending().await,
+    };
+    loop {
+        task::sleep(interval).await;
+        let missed = liveness.missed_pongs.load(Ordering::SeqCst);
+        if missed >= limits.max_missed_pongs {
+            return Err(format!(
+                "{} missed {} pongs; dropping connection",
+                outbound.id, missed
+            )
+            .into());
+        }
+        // A ping that can't even be queued within the interval is as good
+        // as a missed pong. Giving up only gives up its place in the queue;
+        // the connection's writer never stops partway through a packet.
+        let sent = outbound.send(FromServer::Ping).timeout(interval).await;
+        if let Ok(sent) = sent {
+            sent?;
+        }
+        liveness.missed_pongs.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+async fn watch_idle(
+    outbound: &Outbound,
+    liveness: &Liveness,
+    limits: &Limits,
+) -> ChatResult<()> {
+    let timeout = match limits.idle_timeout {
+        Some(timeout) => timeout,
+        None => return futures_lite::future::pending().await,
+    };
+    loop {
+        let idle = liveness.last_request.lock().unwrap().elapsed();
+        if idle >= timeout {
+            let notice =
+                FromServer::Error("Closing idle connection".to_string());
+            let _ = outbound.send(notice).timeout(timeout).await;
+            return Err(
+                format!("{} was idle for {:?}", outbound.id, idle).into()
+            );
+        }
+        task::sleep(timeout - idle).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::connection::{work_connection, TcpConnection};
+    use crate::server::group_table::GroupTable;
+    use crate::server::{ServerConfig, ServerState};
+    use crate::{utils, FromClient};
+    use async_std::io::BufReader;
+    use async_std::net::{TcpListener, TcpStream};
+    use async_std::prelude::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Serve one connection with `limits`, with the client saying Hello and
+    /// joining a group. Return the group table and the client's socket.
+    async fn connect(limits: Limits) -> (Arc<GroupTable>, TcpStream) {
+        let config = ServerConfig { limits, ..ServerConfig::default() };
+        let state = Arc::new(ServerState::open(&config).unwrap());
+        let groups = state.groups.clone();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        task::spawn(async move {
+            let (socket, peer) = listener.accept().await.unwrap();
+            let _ = work_connection(TcpConnection(socket), peer, state).await;
+        });
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        for packet in [
+            FromClient::Hello {
+                nickname: Arc::new("jimb".to_string()),
+                password: None,
+                format: Default::default(),
+            },
+            FromClient::Join {
+                group_name: Arc::new("dogs".to_string()),
+                history: None,
+            },
+        ] {
+            utils::send_as_json(&mut client, &packet).await.unwrap();
+        }
+        while groups.subscriber_count() == 0 {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        (groups, client)
+    }
+
+    async fn no_members_left(groups: &GroupTable) {
+        async {
+            while groups.subscriber_count() > 0 {
+                task::sleep(Duration::from_millis(10)).await;
+            }
+        }
+        .timeout(Duration::from_secs(5))
+        .await
+        .expect("membership outlived its connection");
+    }
+
+    #[test]
+    fn unanswered_pings_end_the_connection() {
+        task::block_on(async {
+            let limits = Limits {
+                ping_interval: Some(Duration::from_millis(50)),
+                max_missed_pongs: 2,
+                ..Limits::default()
+            };
+            let (groups, client) = connect(limits).await;
+
+            let replies: Vec<FromServer> =
+                utils::receive_as_json(BufReader::new(client), 1 << 16)
+                    .map(Result::unwrap)
+                    .collect()
+                    .timeout(Duration::from_secs(5))
+                    .await
+                    .unwrap();
+            // The connection may close before the `Left` gets through.
+            assert_eq!(&replies[..2], &[FromServer::Ping, FromServer::Ping]);
+            assert!(replies[2..]
+                .iter()
+                .all(|reply| matches!(reply, FromServer::Left { .. })));
+            no_members_left(&groups).await;
+        });
+    }
+
+    #[test]
+    fn answered_pings_keep_the_connection_until_idle() {
+        task::block_on(async {
+            let limits = Limits {
+                ping_interval: Some(Duration::from_millis(50)),
+                max_missed_pongs: 1,
+                idle_timeout: Some(Duration::from_millis(500)),
+                ..Limits::default()
+            };
+            let (groups, client) = connect(limits).await;
+
+            let mut to_server = client.clone();
+            let mut from_server =
+                utils::receive_as_json(BufReader::new(client), 1 << 16);
+            let mut pings = 0;
+            while let Some(reply) = from_server.next().await {
+                match reply.unwrap() {
+                    FromServer::Ping => {
+                        pings += 1;
+                        utils::send_as_json(&mut to_server, &FromClient::Pong)
+                            .await
+                            .unwrap();
+                    }
+                    FromServer::Error(message) => {
+                        assert_eq!(message, "Closing idle connection");
+                    }
+                    FromServer::Left { .. } => {}
+                    other => panic!("unexpected packet {:?}", other),
+                }
+            }
+            // Answering kept the connection open well past one missed pong.
+            assert!(pings >= 5, "only {} pings", pings);
+            no_members_left(&groups).await;
+        });
+    }
+}
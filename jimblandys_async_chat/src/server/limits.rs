@@ -0,0 +1,239 @@
+//! Flood protection: how fast clients may send, how much, and what happens
+//! to those that overdo it. Also how long they may stay silent.
+
+use std::time::{Duration, Instant};
+
+/// A sustained rate, allowing bursts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rate {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+impl std::str::FromStr for Rate {
+    type Err = String;
+
+    /// Parse `PER_SECOND` or `PER_SECOND/BURST`. The burst defaults to twice
+    /// the rate, and at least one.
+    fn from_str(s: &str) -> Result<Rate, String> {
+        let number = |field: &str| {
+            field
+                .parse::<f64>()
+                .ok()
+                .filter(|n| n.is_finite() && *n > 0.0)
+                .ok_or_else(|| {
+                    format!("rate must be RATE or RATE/BURST, not '{}'", s)
+                })
+        };
+        let (per_second, burst) = match s.find('/') {
+            Some(slash) => (number(&s[..slash])?, number(&s[slash + 1..])?),
+            None => {
+                let per_second = number(s)?;
+                (per_second, (per_second * 2.0).max(1.0))
+            }
+        };
+        let rate = Rate { per_second, burst };
+        rate.validate()?;
+        Ok(rate)
+    }
+}
+
+impl Rate {
+    /// Check that the rate lets anything through at all: a bucket that can
+    /// never hold a whole token refuses everything.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.per_second.is_finite() && self.per_second > 0.0) {
+            return Err(format!(
+                "rate must be a positive number per second, not {}",
+                self.per_second
+            ));
+        }
+        if !(self.burst.is_finite() && self.burst >= 1.0) {
+            return Err(format!("burst must be at least 1, not {}", self.burst));
+        }
+        Ok(())
+    }
+}
+
+pub struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// A bucket that starts full.
+    pub fn new(rate: Rate) -> TokenBucket {
+        TokenBucket {
+            rate,
+            tokens: rate.burst,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Take a token if there is one.
+    pub fn try_take(&mut self) -> bool {
+        self.try_take_at(Instant::now())
+    }
+
+    fn try_take_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated);
+        self.updated = now;
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate.per_second)
+            .min(self.rate.burst);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// What to do about a request that breaks a limit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LimitAction {
+    /// Ignore the request.
+    Drop,
+    /// Ignore the request, and say why with `FromServer::Error`.
+    Warn,
+    /// Say why, and close the connection.
+    Disconnect,
+}
+
+impl std::str::FromStr for LimitAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LimitAction, String> {
+        match s {
+            "drop" => Ok(LimitAction::Drop),
+            "warn" => Ok(LimitAction::Warn),
+            "disconnect" => Ok(LimitAction::Disconnect),
+            _ => Err(format!(
+                "limit action must be 'drop', 'warn' or 'disconnect', not '{}'",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Limits {
+    /// How fast each connection may send requests of any kind.
+    pub connection_rate: Option<Rate>,
+    /// How fast messages may be posted to each group, by everyone together.
+    pub group_rate: Option<Rate>,
+    /// The longest request line or frame, in bytes.
+    pub max_line_bytes: usize,
+    /// The longest message in a `Post` or `Whisper`, in bytes.
+    pub max_message_bytes: usize,
+    pub action: LimitAction,
+    /// How often to ping each connection, if at all.
+    pub ping_interval: Option<Duration>,
+    /// How many pings in a row may go unanswered before the connection is
+    /// dropped.
+    pub max_missed_pongs: u32,
+    /// Drop connections that make no requests, pings and pongs aside, for
+    /// this long.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            connection_rate: Some(Rate { per_second: 20.0, burst: 50.0 }),
+            group_rate: Some(Rate { per_second: 50.0, burst: 100.0 }),
+            max_line_bytes: 64 << 10,
+            max_message_bytes: 4 << 10,
+            action: LimitAction::Warn,
+            ping_interval: Some(Duration::from_secs(30)),
+            max_missed_pongs: 3,
+            idle_timeout: None,
+        }
+    }
+}
+
+impl Limits {
+    /// Check that the limits make sense together.
+    pub fn validate(&self) -> Result<(), String> {
+        let rates = [self.connection_rate, self.group_rate];
+        for rate in rates.iter().flatten() {
+            rate.validate()?;
+        }
+        // With no pongs to spare, every connection would be dropped at its
+        // first ping.
+        if self.max_missed_pongs == 0 {
+            return Err("maximum missed pongs must be at least 1".into());
+        }
+        if self.max_line_bytes == 0 {
+            return Err("maximum line length must be at least 1 byte".into());
+        }
+        if self.max_message_bytes == 0 {
+            return Err("maximum message length must be at least 1 byte".into());
+        }
+        // A message travels inside a request line, so a message limit above
+        // the line limit could never be reached.
+        if self.max_message_bytes > self.max_line_bytes {
+            return Err(format!(
+                "maximum message length ({} bytes) must not exceed \
+                 maximum line length ({} bytes)",
+                self.max_message_bytes, self.max_line_bytes
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_refills_at_rate() {
+        let rate: Rate = "2/3".parse().unwrap();
+        let mut bucket = TokenBucket::new(rate);
+        let start = bucket.updated;
+
+        assert!((0..3).all(|_| bucket.try_take_at(start)));
+        assert!(!bucket.try_take_at(start));
+        assert!(!bucket.try_take_at(start + Duration::from_millis(400)));
+        assert!(bucket.try_take_at(start + Duration::from_millis(500)));
+
+        // Idle time refills only up to the burst size.
+        let later = start + Duration::from_secs(60);
+        assert!((0..3).all(|_| bucket.try_take_at(later)));
+        assert!(!bucket.try_take_at(later));
+    }
+
+    #[test]
+    fn parse_rates() {
+        assert_eq!("10".parse(), Ok(Rate { per_second: 10.0, burst: 20.0 }));
+        assert_eq!("0.1".parse(), Ok(Rate { per_second: 0.1, burst: 1.0 }));
+        assert!("0".parse::<Rate>().is_err());
+        assert!("5/".parse::<Rate>().is_err());
+        assert!("5/0.5".parse::<Rate>().is_err());
+        assert_eq!("5/1".parse(), Ok(Rate { per_second: 5.0, burst: 1.0 }));
+    }
+
+    #[test]
+    fn limits_must_fit_together() {
+        assert_eq!(Limits::default().validate(), Ok(()));
+
+        let limits = Limits {
+            group_rate: Some(Rate { per_second: 5.0, burst: 0.5 }),
+            ..Limits::default()
+        };
+        assert!(limits.validate().is_err());
+
+        let limits = Limits { max_missed_pongs: 0, ..Limits::default() };
+        assert!(limits.validate().is_err());
+
+        let limits = Limits { max_line_bytes: 0, ..Limits::default() };
+        assert!(limits.validate().is_err());
+
+        let limits = Limits {
+            max_message_bytes: Limits::default().max_line_bytes + 1,
+            ..Limits::default()
+        };
+        assert!(limits.validate().is_err());
+    }
+}
@@ -0,0 +1,222 @@
+//! Counters describing the server's traffic, served over HTTP in
+//! Prometheus's text format.
+//!
+//! The endpoint is deliberately minimal: it answers `GET /metrics` and
+//! nothing else, one request per connection.
+
+use async_std::future::timeout;
+use async_std::io::{BufReader, Read};
+use async_std::net::{TcpListener, TcpStream};
+use async_std::prelude::*;
+use async_std::task;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, Instrument};
+
+use crate::server::ServerState;
+use crate::utils::ChatResult;
+
+/// Running totals, since the server started.
+#[derive(Default)]
+pub struct Metrics {
+    /// Messages accepted into a group.
+    pub posted: AtomicU64,
+    /// Messages sent on to a group's members, counting each member.
+    pub delivered: AtomicU64,
+    /// Messages members missed because they fell too far behind.
+    pub dropped: AtomicU64,
+    /// Sends to members that failed.
+    pub send_errors: AtomicU64,
+    /// Members disconnected for falling behind.
+    pub disconnected: AtomicU64,
+}
+
+/// The current metrics for `state`, in Prometheus's text exposition format.
+pub fn render(state: &ServerState) -> String {
+    let metrics = &state.metrics;
+    let mut text = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+        let _ = writeln!(text, "# HELP {} {}", name, help);
+        let _ = writeln!(text, "# TYPE {} {}", name, kind);
+        let _ = writeln!(text, "{} {}", name, value);
+    };
+
+    metric(
+        "chat_connected_clients",
+        "gauge",
+        "Open client connections.",
+        state.connections.len() as u64,
+    );
+    metric(
+        "chat_messages_posted_total",
+        "counter",
+        "Messages posted to groups.",
+        metrics.posted.load(Ordering::Relaxed),
+    );
+    metric(
+        "chat_messages_delivered_total",
+        "counter",
+        "Messages delivered to group members.",
+        metrics.delivered.load(Ordering::Relaxed),
+    );
+    metric(
+        "chat_messages_dropped_total",
+        "counter",
+        "Messages group members missed by falling behind.",
+        metrics.dropped.load(Ordering::Relaxed),
+    );
+    metric(
+        "chat_send_errors_total",
+        "counter",
+        "Failed sends to group members.",
+        metrics.send_errors.load(Ordering::Relaxed),
+    );
+    metric(
+        "chat_slow_members_disconnected_total",
+        "counter",
+        "Members disconnected for falling behind.",
+        metrics.disconnected.load(Ordering::Relaxed),
+    );
+
+    let groups = state.groups.member_counts();
+    metric("chat_groups", "gauge", "Groups in existence.", groups.len() as u64);
+    let _ = writeln!(text, "# HELP chat_group_members Members of each group.");
+    let _ = writeln!(text, "# TYPE chat_group_members gauge");
+    for (name, members) in groups {
+        let _ = writeln!(
+            text,
+            "chat_group_members{{group=\"{}\"}} {}",
+            escape_label(&name),
+            members
+        );
+    }
+    text
+}
+
+/// Escape `value` for use between quotes as a label value.
+fn escape_label(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Serve the metrics endpoint on `listener` forever.
+pub async fn listen(
+    listener: TcpListener,
+    state: Arc<ServerState>,
+) -> ChatResult<()> {
+    let mut new_connections = listener.incoming();
+    while let Some(socket_result) = new_connections.next().await {
+        let socket = socket_result?;
+        let state = state.clone();
+        let peer = socket.peer_addr().ok();
+        let span = tracing::info_span!("metrics", peer = ?peer);
+        task::spawn(
+            async move {
+                if let Err(error) = answer(socket, &state).await {
+                    debug!(%error, "metrics request failed");
+                }
+            }
+            .instrument(span),
+        );
+    }
+    Ok(())
+}
+
+/// The most a request, headers and all, may take up.
+const MAX_REQUEST_BYTES: u64 = 8 << 10;
+
+/// How long a client has to send its whole request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Read one HTTP request from `socket` and answer it.
+async fn answer(socket: TcpStream, state: &ServerState) -> ChatResult<()> {
+    let request = timeout(REQUEST_TIMEOUT, read_request(&socket)).await??;
+
+    let mut words = request.split_whitespace();
+    let (status, content_type, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            render(state),
+        ),
+        (Some("GET"), Some(_)) => {
+            ("404 Not Found", "text/plain", "Not found\n".to_string())
+        }
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Only GET is supported\n".to_string(),
+        ),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    let mut socket = &socket;
+    socket.write_all(response.as_bytes()).await?;
+    socket.flush().await?;
+    Ok(())
+}
+
+/// Read an HTTP request's headers from `inbound`, and return its request
+/// line.
+async fn read_request<R>(inbound: R) -> ChatResult<String>
+where
+    R: Read + Unpin,
+{
+    let mut lines = BufReader::new(inbound.take(MAX_REQUEST_BYTES)).lines();
+    let request = lines.next().await.ok_or("no request")??;
+    // Skip the headers; we need nothing from them. A request that runs out
+    // before they end is too long, or cut short.
+    while let Some(line) = lines.next().await {
+        if line?.is_empty() {
+            return Ok(request);
+        }
+    }
+    Err("request headers too long or incomplete".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::Cursor;
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(escape_label("dogs"), "dogs");
+        assert_eq!(escape_label("say \"hi\"\n\\o/"), "say \\\"hi\\\"\\n\\\\o/");
+    }
+
+    #[test]
+    fn requests_must_be_short_and_complete() {
+        task::block_on(async {
+            let request = "GET /metrics HTTP/1.1\r\nHost: test\r\n\r\n";
+            assert_eq!(
+                read_request(Cursor::new(request)).await.unwrap(),
+                "GET /metrics HTTP/1.1"
+            );
+
+            let endless = format!(
+                "GET /metrics HTTP/1.1\r\nX-Padding: {}\r\n\r\n",
+                "x".repeat(MAX_REQUEST_BYTES as usize)
+            );
+            assert!(read_request(Cursor::new(endless)).await.is_err());
+            let cut_short = "GET /metrics HTTP/1.1\r\nHost: test\r\n";
+            assert!(read_request(Cursor::new(cut_short)).await.is_err());
+        });
+    }
+}
@@ -0,0 +1,256 @@
+//! The chat server, as a library, so that it can be started from tests as
+//! well as from the `server` binary.
+
+use async_std::net::{TcpListener, TcpStream};
+use async_std::prelude::*;
+use async_std::task;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::utils::ChatResult;
+use crate::Delivery;
+
+pub mod accounts;
+pub mod limits;
+pub mod store;
+
+mod access;
+mod backlog;
+mod connection;
+mod dedup;
+mod group;
+mod group_table;
+mod heartbeat;
+mod metrics;
+mod participants;
+mod shutdown;
+mod users;
+mod websocket;
+
+pub use shutdown::signalled;
+
+use accounts::Accounts;
+use connection::{work_connection, TcpConnection};
+use group::GroupSettings;
+use group_table::GroupTable;
+use limits::Limits;
+use metrics::Metrics;
+use shutdown::Connections;
+use store::{Store, StoreConfig};
+use users::UserTable;
+
+#[cfg(feature = "tls")]
+pub type TlsAcceptor = crate::tls::TlsAcceptor;
+
+/// Stands in for the acceptor when TLS support is not compiled in.
+#[cfg(not(feature = "tls"))]
+#[derive(Clone)]
+pub enum TlsAcceptor {}
+
+/// How the server should run. `ServerConfig::default()` is a plain TCP
+/// server with no persistence, no accounts, and the default limits.
+#[derive(Clone)]
+pub struct ServerConfig {
+    /// Where to keep group logs, if anywhere.
+    pub store: Option<StoreConfig>,
+    pub limits: Limits,
+    /// If present, only these nicknames may be claimed, with their passwords.
+    pub accounts: Option<Accounts>,
+    /// Speak TLS on the main listener, if present.
+    pub tls: Option<TlsAcceptor>,
+    /// Where browsers may connect over WebSocket, if anywhere.
+    pub websocket_address: Option<String>,
+    /// How many messages may wait to be sent to each member of a group
+    /// before its delivery policy applies.
+    pub queue_capacity: usize,
+    /// What new groups do when a member's queue is full. Operators can
+    /// change it for their groups.
+    pub delivery: Delivery,
+    /// How many groups there may be at once, if that is limited.
+    pub max_groups: Option<usize>,
+    /// How many members each group may have, if that is limited.
+    pub max_members: Option<usize>,
+    /// How many recent messages each group keeps for replay.
+    pub history: usize,
+    /// Where to serve metrics over HTTP, if anywhere.
+    pub metrics_address: Option<String>,
+    /// How long to wait for connections to wind down when shutting down.
+    pub shutdown_timeout: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            store: None,
+            limits: Limits::default(),
+            accounts: None,
+            tls: None,
+            websocket_address: None,
+            queue_capacity: 1000,
+            delivery: Delivery::Drop,
+            max_groups: None,
+            max_members: None,
+            history: 100,
+            metrics_address: None,
+            shutdown_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Check that the settings make sense together, so that mistakes are
+    /// reported before the server starts taking connections.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.queue_capacity == 0 {
+            return Err("queue capacity must be at least 1".into());
+        }
+        if self.max_groups == Some(0) {
+            return Err("maximum number of groups must be at least 1".into());
+        }
+        if self.max_members == Some(0) {
+            return Err("maximum number of members must be at least 1".into());
+        }
+        if let Some(store) = &self.store {
+            if store.max_segment_bytes == 0 {
+                return Err("log segments must be at least 1 byte".into());
+            }
+        }
+        self.limits.validate()
+    }
+}
+
+/// Everything the server's connections share.
+pub(crate) struct ServerState {
+    pub groups: Arc<GroupTable>,
+    pub users: UserTable,
+    pub limits: Limits,
+    pub connections: Connections,
+    pub metrics: Arc<Metrics>,
+}
+
+impl ServerState {
+    /// Open the store `config` names, if any, and restore its groups.
+    fn open(config: &ServerConfig) -> ChatResult<ServerState> {
+        config.validate()?;
+        let store = match &config.store {
+            Some(store_config) => Some(Store::open(store_config.clone())?),
+            None => None,
+        };
+        let settings = GroupSettings {
+            post_rate: config.limits.group_rate,
+            queue_capacity: config.queue_capacity,
+            delivery: config.delivery,
+            history: config.history,
+            max_members: config.max_members,
+        };
+        let metrics = Arc::new(Metrics::default());
+        let groups = Arc::new(GroupTable::new(
+            store,
+            settings,
+            config.max_groups,
+            metrics.clone(),
+        ));
+        groups.restore()?;
+        Ok(ServerState {
+            groups,
+            users: UserTable::new(config.accounts.clone()),
+            limits: config.limits.clone(),
+            connections: Connections::new(),
+            metrics,
+        })
+    }
+}
+
+/// Serve clients arriving on `listener` as `config` directs, until the
+/// listener fails.
+pub async fn run_server(
+    listener: TcpListener,
+    config: ServerConfig,
+) -> ChatResult<()> {
+    run_server_until(listener, config, futures_lite::future::pending()).await
+}
+
+/// Like `run_server`, but once `stop` resolves, stop taking new connections
+/// and wind down the ones we have.
+pub async fn run_server_until(
+    listener: TcpListener,
+    config: ServerConfig,
+    stop: impl Future<Output = ()>,
+) -> ChatResult<()> {
+    let state = Arc::new(ServerState::open(&config)?);
+
+    let ws_listener = match &config.websocket_address {
+        Some(ws_address) => {
+            let ws_listener = TcpListener::bind(ws_address).await?;
+            Some(task::spawn(websocket::listen(ws_listener, state.clone())))
+        }
+        None => None,
+    };
+    let metrics_listener = match &config.metrics_address {
+        Some(metrics_address) => {
+            let listener = TcpListener::bind(metrics_address).await?;
+            Some(task::spawn(metrics::listen(listener, state.clone())))
+        }
+        None => None,
+    };
+
+    let accept = async {
+        let mut new_connections = listener.incoming();
+        while let Some(socket_result) = new_connections.next().await {
+            let socket = socket_result?;
+            let peer = socket.peer_addr()?;
+            let tls = config.tls.clone();
+            let state = state.clone();
+            task::spawn(async move {
+                log_error(serve_socket(socket, peer, tls, state).await);
+            });
+        }
+        Ok(())
+    };
+    let stop = async {
+        stop.await;
+        ChatResult::Ok(())
+    };
+    accept.race(stop).await?;
+
+    drop(listener);
+    if let Some(ws_listener) = ws_listener {
+        ws_listener.cancel().await;
+    }
+    if let Some(metrics_listener) = metrics_listener {
+        metrics_listener.cancel().await;
+    }
+    shutdown::drain(
+        &state,
+        "The server is shutting down",
+        config.shutdown_timeout,
+    )
+    .await;
+    Ok(())
+}
+
+async fn serve_socket(
+    socket: TcpStream,
+    peer: SocketAddr,
+    tls: Option<TlsAcceptor>,
+    state: Arc<ServerState>,
+) -> ChatResult<()> {
+    let socket = TcpConnection(socket);
+    match tls {
+        #[cfg(feature = "tls")]
+        Some(acceptor) => {
+            let socket = acceptor.accept(socket).await?;
+            work_connection(socket, peer, state).await
+        }
+        #[cfg(not(feature = "tls"))]
+        Some(never) => match never {},
+        None => work_connection(socket, peer, state).await,
+    }
+}
+
+fn log_error(result: ChatResult<()>) {
+    if let Err(error) = result {
+        tracing::error!(%error, "connection failed");
+    }
+}
@@ -0,0 +1,129 @@
+use std::{sync::{Arc, Mutex}, collections::HashMap, net::SocketAddr};
+
+use async_std::channel::{self, Receiver, Sender};
+use tokio::sync::oneshot;
+use tracing::debug;
+
+use crate::server::connection::Outbound;
+use crate::FromServer;
+
+/// A group's members. Joining and leaving go through `GroupTable`, which
+/// decides when the group itself comes and goes.
+pub struct GroupMembers {
+    members: Mutex<HashMap<MemberId, Member>>,
+}
+
+pub type MemberId = SocketAddr;
+
+struct Member {
+    outbound: Arc<Outbound>,
+    /// Firing this asks the member's subscriber task to stop. Taken once the
+    /// request has been made.
+    leave: Option<oneshot::Sender<()>>,
+    /// Messages waiting for the member's subscriber task to forward them.
+    queue: Sender<FromServer>,
+}
+
+/// What a member's subscriber task works from.
+pub struct Membership {
+    /// Fires when `request_leave` is called for the member.
+    pub leave_requested: oneshot::Receiver<()>,
+    /// The group's messages, as they are posted.
+    pub queue: Receiver<FromServer>,
+}
+
+impl GroupMembers {
+    pub fn new() -> GroupMembers {
+        GroupMembers {
+            members: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Add `outbound` to the group, unless it already has `max_members`,
+    /// with room to queue `queue_capacity` messages for it.
+    pub fn join(
+        &self,
+        group_name: &str,
+        outbound: Arc<Outbound>,
+        max_members: Option<usize>,
+        queue_capacity: usize,
+    ) -> Result<Membership, String> {
+        let mut guard = self.members.lock().unwrap();
+        let member = outbound.id;
+        if guard.contains_key(&member) {
+            return Err(format!("You have already joined '{}'", group_name));
+        }
+        if max_members.is_some_and(|max| guard.len() >= max) {
+            return Err(format!("'{}' is full", group_name));
+        }
+        let (leave, leave_requested) = oneshot::channel();
+        let (sender, queue) = channel::bounded(queue_capacity);
+        guard.insert(
+            member,
+            Member { outbound, leave: Some(leave), queue: sender },
+        );
+        debug!(group = group_name, members = guard.len(), "joined");
+        Ok(Membership { leave_requested, queue })
+    }
+
+    /// Ask `member`'s subscriber task to stop. The member is removed once it
+    /// has, by `leave`.
+    pub fn request_leave(
+        &self,
+        group_name: &str,
+        member: MemberId,
+    ) -> Result<(), String> {
+        let mut guard = self.members.lock().unwrap();
+        match guard.get_mut(&member) {
+            Some(entry) => {
+                if let Some(leave) = entry.leave.take() {
+                    let _ = leave.send(());
+                }
+                Ok(())
+            }
+            None => Err(format!("You are not a member of '{}'", group_name)),
+        }
+    }
+
+    /// Ask every member's subscriber task to stop.
+    pub fn request_leave_all(&self) {
+        let mut guard = self.members.lock().unwrap();
+        for member in guard.values_mut() {
+            if let Some(leave) = member.leave.take() {
+                let _ = leave.send(());
+            }
+        }
+    }
+
+    /// Every member, with the queue for its messages.
+    pub fn queues(&self) -> Vec<(Arc<Outbound>, Sender<FromServer>)> {
+        let guard = self.members.lock().unwrap();
+        guard
+            .values()
+            .map(|member| (member.outbound.clone(), member.queue.clone()))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.lock().unwrap().len()
+    }
+
+    /// Remove `member`, returning how many members remain.
+    pub fn leave(&self, group_name: &String, member: MemberId) -> usize {
+        let mut guard = self.members.lock().unwrap();
+        let removed = guard.remove(&member).is_some();
+        debug!(group = %group_name, removed, members = guard.len(), "left");
+        guard.len()
+    }
+
+    /// The nicknames of the current members, sorted.
+    pub fn nicknames(&self) -> Vec<Arc<String>> {
+        let guard = self.members.lock().unwrap();
+        let mut nicknames: Vec<_> = guard
+            .values()
+            .filter_map(|member| member.outbound.nickname())
+            .collect();
+        nicknames.sort();
+        nicknames
+    }
+}
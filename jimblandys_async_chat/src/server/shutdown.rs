@@ -0,0 +1,210 @@
+//! Stopping the server without leaving clients or logs in the lurch.
+
+use crate::utils::ChatResult;
+use crate::FromServer;
+use async_std::prelude::FutureExt as _;
+use futures_util::future::join_all;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+use crate::server::connection::Outbound;
+use crate::server::ServerState;
+
+/// Every open connection, whether or not it has said Hello.
+pub struct Connections(Mutex<HashMap<SocketAddr, Arc<Outbound>>>);
+
+impl Connections {
+    pub fn new() -> Connections {
+        Connections(Mutex::new(HashMap::new()))
+    }
+
+    pub fn insert(&self, outbound: Arc<Outbound>) {
+        self.0.lock().unwrap().insert(outbound.id, outbound);
+    }
+
+    pub fn remove(&self, id: SocketAddr) {
+        self.0.lock().unwrap().remove(&id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    fn all(&self) -> Vec<Arc<Outbound>> {
+        self.0.lock().unwrap().values().cloned().collect()
+    }
+}
+
+/// Resolve when the process receives SIGINT or SIGTERM. A second signal
+/// exits at once, for when draining takes too long for someone's taste.
+pub fn signalled() -> ChatResult<impl std::future::Future<Output = ()>> {
+    let (sender, receiver) = async_std::channel::bounded(1);
+    ctrlc::set_handler(move || {
+        if sender.try_send(()).is_err() {
+            std::process::exit(130);
+        }
+    })?;
+    Ok(async move {
+        let _ = receiver.recv().await;
+    })
+}
+
+/// Tell every client the server is going away, end every group membership,
+/// close every connection, and then make sure everything logged is on disk.
+/// All but the last step share `timeout`, and deal with every connection at
+/// once, so that a few stalled clients can't hold up shutdown for long.
+pub async fn drain(state: &ServerState, reason: &str, timeout: Duration) {
+    let groups = &state.groups;
+    let outbounds = state.connections.all();
+    info!(connections = outbounds.len(), "shutting down");
+    let deadline = Instant::now() + timeout;
+    let remaining = || deadline.saturating_duration_since(Instant::now());
+
+    let notices = outbounds.iter().map(|outbound| {
+        outbound.send(FromServer::Shutdown {
+            reason: reason.to_string(),
+        })
+    });
+    if join_all(notices).timeout(remaining()).await.is_err() {
+        warn!("gave up telling every client about the shutdown");
+    }
+
+    groups.request_leave_all();
+    loop {
+        let remaining = groups.subscriber_count();
+        if remaining == 0 {
+            break;
+        }
+        if Instant::now() >= deadline {
+            warn!(remaining, "gave up waiting for subscribers to finish");
+            break;
+        }
+        async_std::task::sleep(Duration::from_millis(20)).await;
+    }
+
+    // Each close gives up on its connection, dropping it, when time is up.
+    let closes = outbounds.iter().map(|outbound| outbound.close(remaining()));
+    join_all(closes).await;
+
+    if let Err(error) = groups.sync().await {
+        error!(%error, "cannot sync logs");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::connection::{work_connection, TcpConnection, ToClient};
+    use crate::server::ServerConfig;
+    use crate::{utils, FromClient};
+    use async_std::io::BufReader;
+    use async_std::net::{TcpListener, TcpStream};
+    use async_std::prelude::*;
+    use async_std::task;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// A client that never takes anything we send it.
+    struct Stalled;
+
+    impl async_std::io::Write for Stalled {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Pending
+        }
+
+        fn poll_flush(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Poll::Pending
+        }
+
+        fn poll_close(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn drain_notifies_clients_and_ends_memberships() {
+        task::block_on(async {
+            let state =
+                Arc::new(ServerState::open(&ServerConfig::default()).unwrap());
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let server = {
+                let state = state.clone();
+                task::spawn(async move {
+                    let (socket, peer) = listener.accept().await.unwrap();
+                    let result =
+                        work_connection(TcpConnection(socket), peer, state);
+                    crate::server::log_error(result.await);
+                })
+            };
+
+            let mut client = TcpStream::connect(address).await.unwrap();
+            let group_name = Arc::new("dogs".to_string());
+            for packet in [
+                FromClient::Hello {
+                    nickname: Arc::new("jimb".to_string()),
+                    password: None,
+                    format: Default::default(),
+                },
+                FromClient::Join { group_name: group_name.clone(), history: None },
+            ] {
+                utils::send_as_json(&mut client, &packet).await.unwrap();
+            }
+            while state.groups.subscriber_count() == 0 {
+                task::sleep(Duration::from_millis(10)).await;
+            }
+
+            drain(&state, "bye", Duration::from_secs(5)).await;
+            assert_eq!(state.groups.subscriber_count(), 0);
+
+            let replies: Vec<FromServer> =
+                utils::receive_as_json(BufReader::new(client), 1 << 16)
+                    .map(Result::unwrap)
+                    .collect()
+                    .await;
+            assert_eq!(
+                replies,
+                vec![
+                    FromServer::Shutdown { reason: "bye".to_string() },
+                    FromServer::Left { group_name },
+                ]
+            );
+            server.await;
+        });
+    }
+
+    #[test]
+    fn stalled_clients_share_one_deadline() {
+        task::block_on(async {
+            let state =
+                Arc::new(ServerState::open(&ServerConfig::default()).unwrap());
+            for port in 1..=5 {
+                let to_client = ToClient::Stream(
+                    Default::default(),
+                    Box::new(Stalled),
+                );
+                let id = ([127, 0, 0, 1], port).into();
+                state.connections.insert(Arc::new(Outbound::new(id, to_client)));
+            }
+
+            // Waiting on each client in turn would take five times as long.
+            let started = Instant::now();
+            drain(&state, "bye", Duration::from_millis(200)).await;
+            assert!(started.elapsed() < Duration::from_millis(600));
+        });
+    }
+}
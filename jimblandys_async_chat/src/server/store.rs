@@ -0,0 +1,649 @@
+//! Durable, append-only message logs for chat groups.
+//!
+//! Each group gets a directory under the store's root, holding one or more
+//! segment files. A segment is named after the sequence number of its first
+//! message and holds one `FromServer::Message` per line, as JSON, with the
+//! id its sender gave the post, if any, so that a restarted server still
+//! recognizes posts sent again. New
+//! messages go to the newest segment; once it grows past a size or age limit
+//! a fresh one is started, and segments holding only messages too old to be
+//! replayed are deleted. Beside the segments, `access.json` holds the
+//! group's owner, operators, invitations and bans.
+//!
+//! All of a store's file I/O, fsyncs included, happens on one writer thread,
+//! in the order it was asked for, so that none of it holds up the executor.
+
+use crate::FromServer;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tracing::{error, warn};
+
+use crate::server::access::Access;
+use crate::server::backlog::Backlog;
+use crate::server::dedup::{RecentPosts, RECENT_POSTS};
+use serde::{Deserialize, Serialize};
+
+/// The file in a group's directory that holds its access rules.
+const ACCESS_FILE: &str = "access.json";
+
+/// One line of a segment. Lines logged before posts had ids have no `id`.
+#[derive(Deserialize, Serialize)]
+struct Logged {
+    #[serde(flatten)]
+    packet: FromServer,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
+}
+
+/// When appended messages are flushed to stable storage.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FsyncPolicy {
+    /// After every message.
+    Always,
+    /// When a message is appended at least this long after the last sync,
+    /// and when a segment is closed.
+    Interval(Duration),
+    /// Only when a segment is closed; otherwise leave it to the OS.
+    Never,
+}
+
+impl std::str::FromStr for FsyncPolicy {
+    type Err = String;
+
+    /// Parse `always`, `never`, or a number of seconds.
+    fn from_str(s: &str) -> Result<FsyncPolicy, String> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "never" => Ok(FsyncPolicy::Never),
+            secs => secs
+                .parse::<f64>()
+                .ok()
+                .filter(|secs| secs.is_finite() && *secs >= 0.0)
+                .map(|secs| FsyncPolicy::Interval(Duration::from_secs_f64(secs)))
+                .ok_or_else(|| {
+                    format!(
+                        "fsync policy must be 'always', 'never' or a number \
+                         of seconds, not '{}'",
+                        s
+                    )
+                }),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct StoreConfig {
+    pub dir: PathBuf,
+    pub fsync: FsyncPolicy,
+    /// Start a new segment once the current one reaches this many bytes.
+    pub max_segment_bytes: u64,
+    /// Start a new segment once the current one has been open this long.
+    pub max_segment_age: Option<Duration>,
+}
+
+impl StoreConfig {
+    pub fn new(dir: PathBuf) -> StoreConfig {
+        StoreConfig {
+            dir,
+            fsync: FsyncPolicy::Interval(Duration::from_secs(1)),
+            max_segment_bytes: 1 << 20,
+            max_segment_age: None,
+        }
+    }
+}
+
+pub struct Store {
+    config: StoreConfig,
+    /// Queues work for the writer thread.
+    writer: mpsc::Sender<Job>,
+    next_log_id: AtomicU64,
+}
+
+/// Work for a store's writer thread. Logs are identified by an id rather
+/// than their group's name, since a group that empties and is recreated
+/// opens a new log before its old one is closed.
+enum Job {
+    Open {
+        id: u64,
+        group_name: Arc<String>,
+        dir: PathBuf,
+        capacity: usize,
+        reply: oneshot::Sender<io::Result<(Backlog, RecentPosts)>>,
+    },
+    Append {
+        id: u64,
+        seq: u64,
+        logged: Logged,
+    },
+    SaveAccess {
+        id: u64,
+        json: String,
+    },
+    Close {
+        id: u64,
+    },
+    Sync {
+        reply: oneshot::Sender<io::Result<()>>,
+    },
+}
+
+impl Store {
+    pub fn open(config: StoreConfig) -> io::Result<Store> {
+        fs::create_dir_all(&config.dir)?;
+        let (writer, jobs) = mpsc::channel();
+        let writer_config = config.clone();
+        thread::Builder::new()
+            .name("store writer".to_string())
+            .spawn(move || write_logs(writer_config, jobs))?;
+        Ok(Store {
+            config,
+            writer,
+            next_log_id: AtomicU64::new(0),
+        })
+    }
+
+    /// The names of every group with a log.
+    pub fn group_names(&self) -> io::Result<Vec<Arc<String>>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.config.dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let dir_name = entry.file_name();
+            match dir_name.to_str().and_then(decode_group_name) {
+                Some(name) => names.push(Arc::new(name)),
+                None => warn!(
+                    path = ?entry.path(),
+                    "ignoring unrecognized directory in store"
+                ),
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Open `group_name`'s log, creating it if need be, and replay it into a
+    /// backlog that retains `capacity` messages and the ids of the posts it
+    /// holds. This waits for the writer thread to finish whatever it was
+    /// asked to do first, so the replay includes everything already appended
+    /// to the group's earlier logs.
+    pub async fn open_group(
+        &self,
+        group_name: &Arc<String>,
+        capacity: usize,
+    ) -> io::Result<(Backlog, RecentPosts, GroupLog)> {
+        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
+        let (reply, replied) = oneshot::channel();
+        self.submit(Job::Open {
+            id,
+            group_name: group_name.clone(),
+            dir: self.config.dir.join(encode_group_name(group_name)),
+            capacity,
+            reply,
+        })?;
+        // Made before waiting, so that the log is closed again if we give up
+        // waiting, or the open fails.
+        let log = GroupLog {
+            id,
+            writer: self.writer.clone(),
+        };
+        let (backlog, recent_posts) =
+            replied.await.map_err(|_| writer_gone())??;
+        Ok((backlog, recent_posts, log))
+    }
+
+    /// The access rules last saved for `group_name`, if any were.
+    pub fn load_access(&self, group_name: &str) -> io::Result<Option<Access>> {
+        let path = self
+            .config
+            .dir
+            .join(encode_group_name(group_name))
+            .join(ACCESS_FILE);
+        match fs::read(path) {
+            Ok(json) => Ok(Some(serde_json::from_slice(&json)?)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Flush everything appended to any log so far to stable storage.
+    pub async fn sync(&self) -> io::Result<()> {
+        let (reply, replied) = oneshot::channel();
+        self.submit(Job::Sync { reply })?;
+        replied.await.map_err(|_| writer_gone())?
+    }
+
+    fn submit(&self, job: Job) -> io::Result<()> {
+        self.writer.send(job).map_err(|_| writer_gone())
+    }
+}
+
+fn writer_gone() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "store writer thread has exited")
+}
+
+/// The writing end of one group's log. Appends are queued for the store's
+/// writer thread, which reports any that fail.
+pub struct GroupLog {
+    id: u64,
+    writer: mpsc::Sender<Job>,
+}
+
+impl GroupLog {
+    /// Append `packet`, which must be a `FromServer::Message` carrying
+    /// sequence number `seq`, and which its sender gave the id `post_id`.
+    pub fn append(&self, seq: u64, packet: FromServer, post_id: Option<u64>) {
+        let _ = self.writer.send(Job::Append {
+            id: self.id,
+            seq,
+            logged: Logged {
+                packet,
+                id: post_id,
+            },
+        });
+    }
+
+    /// Save `access` as the group's access rules, replacing any saved before.
+    pub fn save_access(&self, access: &Access) {
+        match serde_json::to_string(access) {
+            Ok(json) => {
+                let _ = self.writer.send(Job::SaveAccess { id: self.id, json });
+            }
+            Err(error) => error!(%error, "cannot serialize access rules"),
+        }
+    }
+}
+
+impl Drop for GroupLog {
+    fn drop(&mut self) {
+        let _ = self.writer.send(Job::Close { id: self.id });
+    }
+}
+
+/// Carry out `jobs` until every `Store` and `GroupLog` sending them is gone.
+fn write_logs(config: StoreConfig, jobs: mpsc::Receiver<Job>) {
+    let mut logs = HashMap::new();
+    for job in jobs {
+        match job {
+            Job::Open {
+                id,
+                group_name,
+                dir,
+                capacity,
+                reply,
+            } => {
+                let opened = Segments::open(&config, group_name, dir, capacity)
+                    .map(|(backlog, recent_posts, segments)| {
+                        logs.insert(id, segments);
+                        (backlog, recent_posts)
+                    });
+                let _ = reply.send(opened);
+            }
+            Job::Append { id, seq, logged } => {
+                if let Some(segments) = logs.get_mut(&id) {
+                    if let Err(error) = segments.append(seq, &logged) {
+                        error!(
+                            group = %segments.group_name,
+                            %error,
+                            "cannot log message"
+                        );
+                    }
+                }
+            }
+            Job::SaveAccess { id, json } => {
+                if let Some(segments) = logs.get_mut(&id) {
+                    if let Err(error) = segments.save_access(&json) {
+                        error!(
+                            group = %segments.group_name,
+                            %error,
+                            "cannot save access rules"
+                        );
+                    }
+                }
+            }
+            Job::Close { id } => {
+                logs.remove(&id);
+            }
+            Job::Sync { reply } => {
+                let mut result = Ok(());
+                for segments in logs.values_mut() {
+                    if let Err(error) = segments.sync() {
+                        result = Err(error);
+                    }
+                }
+                let _ = reply.send(result);
+            }
+        }
+    }
+}
+
+/// One group's segment files, as the writer thread sees them.
+struct Segments {
+    config: StoreConfig,
+    group_name: Arc<String>,
+    dir: PathBuf,
+    /// How many of the most recent messages must survive compaction.
+    keep: u64,
+    /// First sequence numbers of the segments on disk, oldest first.
+    segments: Vec<u64>,
+    current: Option<File>,
+    current_bytes: u64,
+    opened_at: Instant,
+    synced_at: Instant,
+}
+
+impl Segments {
+    fn open(
+        config: &StoreConfig,
+        group_name: Arc<String>,
+        dir: PathBuf,
+        capacity: usize,
+    ) -> io::Result<(Backlog, RecentPosts, Segments)> {
+        fs::create_dir_all(&dir)?;
+
+        let mut backlog = Backlog::new(capacity);
+        let mut recent_posts = RecentPosts::new(RECENT_POSTS);
+        let segments = list_segments(&dir)?;
+        for (_, path) in &segments {
+            replay_segment(path, &mut backlog, &mut recent_posts)?;
+        }
+
+        let log = Segments {
+            config: config.clone(),
+            group_name,
+            dir,
+            keep: capacity as u64,
+            segments: segments.into_iter().map(|(start, _)| start).collect(),
+            current: None,
+            current_bytes: 0,
+            opened_at: Instant::now(),
+            synced_at: Instant::now(),
+        };
+        Ok((backlog, recent_posts, log))
+    }
+
+    fn append(&mut self, seq: u64, logged: &Logged) -> io::Result<()> {
+        if self.current.is_none() || self.segment_full() {
+            self.rotate(seq)?;
+        }
+
+        let mut line = serde_json::to_string(logged)?;
+        line.push('\n');
+        let file = self.current.as_mut().expect("segment opened by rotate");
+        file.write_all(line.as_bytes())?;
+        self.current_bytes += line.len() as u64;
+
+        let due = match self.config.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::Interval(interval) => {
+                self.synced_at.elapsed() >= interval
+            }
+            FsyncPolicy::Never => false,
+        };
+        if due {
+            file.sync_data()?;
+            self.synced_at = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Replace the saved access rules with `json`. Write them to a fresh file
+    /// and rename it into place, so that a crash leaves either the old rules
+    /// or the new ones.
+    fn save_access(&self, json: &str) -> io::Result<()> {
+        let temporary = self.dir.join(format!("{}.tmp", ACCESS_FILE));
+        let mut file = File::create(&temporary)?;
+        file.write_all(json.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(temporary, self.dir.join(ACCESS_FILE))
+    }
+
+    /// Flush everything appended so far to stable storage.
+    fn sync(&mut self) -> io::Result<()> {
+        if let Some(file) = &self.current {
+            file.sync_data()?;
+            self.synced_at = Instant::now();
+        }
+        Ok(())
+    }
+
+    fn segment_full(&self) -> bool {
+        if self.current_bytes >= self.config.max_segment_bytes {
+            return true;
+        }
+        match self.config.max_segment_age {
+            Some(age) => self.current_bytes > 0 && self.opened_at.elapsed() >= age,
+            None => false,
+        }
+    }
+
+    /// Close the current segment, if any, and start one whose first message
+    /// is `seq`. Then delete segments that hold nothing worth replaying.
+    fn rotate(&mut self, seq: u64) -> io::Result<()> {
+        if let Some(old) = self.current.take() {
+            old.sync_all()?;
+        }
+
+        // Reuse the newest segment if it is still empty, which is what we
+        // find at startup when the server stopped right after rotating.
+        let path = segment_path(&self.dir, seq);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        self.current_bytes = file.metadata()?.len();
+        if self.segments.last() != Some(&seq) {
+            self.segments.push(seq);
+        }
+        self.current = Some(file);
+        self.opened_at = Instant::now();
+
+        self.compact(seq)
+    }
+
+    /// Delete every segment whose messages all precede the `keep` most
+    /// recent ones, given that `next_seq` is the next message to be logged.
+    fn compact(&mut self, next_seq: u64) -> io::Result<()> {
+        let oldest_kept = next_seq.saturating_sub(self.keep);
+        // Segment `i` ends where segment `i + 1` begins.
+        let obsolete = self
+            .segments
+            .windows(2)
+            .take_while(|pair| pair[1] <= oldest_kept)
+            .count();
+        for start in self.segments.drain(..obsolete) {
+            fs::remove_file(segment_path(&self.dir, start))?;
+        }
+        Ok(())
+    }
+}
+
+fn segment_path(dir: &Path, start: u64) -> PathBuf {
+    dir.join(format!("{:020}.jsonl", start))
+}
+
+/// The segments in `dir`, oldest first.
+fn list_segments(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let start = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".jsonl"))
+            .and_then(|start| start.parse::<u64>().ok());
+        if let Some(start) = start {
+            segments.push((start, path));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+fn replay_segment(
+    path: &Path,
+    backlog: &mut Backlog,
+    recent_posts: &mut RecentPosts,
+) -> io::Result<()> {
+    for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        match serde_json::from_str::<Logged>(&line) {
+            Ok(Logged { packet, id }) => {
+                if let (
+                    FromServer::Message { seq, sender, .. },
+                    Some(id),
+                ) = (&packet, id)
+                {
+                    recent_posts.insert(sender.clone(), id, *seq);
+                }
+                backlog.restore(packet);
+            }
+            // Most likely a line torn by a crash mid-write.
+            Err(error) => warn!(
+                path = %path.display(),
+                line = number + 1,
+                %error,
+                "skipping unreadable logged message"
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Turn a group name into a directory name: ASCII letters, digits, `-` and
+/// `_` stand for themselves and every other byte becomes `%XX`. The empty
+/// name, which would otherwise be the store's own directory, becomes `%`.
+fn encode_group_name(name: &str) -> String {
+    if name.is_empty() {
+        return "%".to_string();
+    }
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn decode_group_name(encoded: &str) -> Option<String> {
+    match encoded {
+        "" => return None,
+        "%" => return Some(String::new()),
+        _ => {}
+    }
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task::block_on;
+
+    fn message(seq: u64) -> FromServer {
+        FromServer::Message {
+            group_name: Arc::new("dogs".to_string()),
+            seq,
+            sender: Arc::new("jimb".to_string()),
+            timestamp: chrono::Utc::now(),
+            message: Arc::new(format!("woof {}", seq)),
+        }
+    }
+
+    #[test]
+    fn group_names_round_trip() {
+        for name in &["dogs", "../etc", "🐕 chat", "%41", ""] {
+            let encoded = encode_group_name(name);
+            assert!(!encoded.is_empty());
+            assert!(encoded
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"-_%".contains(&b)));
+            assert_eq!(decode_group_name(&encoded).as_deref(), Some(*name));
+        }
+    }
+
+    #[test]
+    fn log_survives_restart_and_compacts() {
+        let dir = std::env::temp_dir()
+            .join(format!("async-chat-store-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut config = StoreConfig::new(dir.clone());
+        config.fsync = FsyncPolicy::Never;
+        // Small enough that every few messages start a new segment.
+        config.max_segment_bytes = 300;
+
+        let dogs = Arc::new("dogs".to_string());
+        let unnamed = Arc::new(String::new());
+        {
+            let store = Store::open(config.clone()).unwrap();
+            let (mut backlog, _recent, log) =
+                block_on(store.open_group(&dogs, 5)).unwrap();
+            for id in 0..20 {
+                let packet = message(backlog.next_seq());
+                log.append(backlog.next_seq(), packet.clone(), Some(id + 100));
+                backlog.push(packet);
+            }
+            let mut access = Access::new();
+            access.claim(&Arc::new("jimb".to_string()));
+            log.save_access(&access);
+
+            let (_backlog, _recent, unnamed_log) =
+                block_on(store.open_group(&unnamed, 5)).unwrap();
+            unnamed_log.append(0, message(0), None);
+            block_on(store.sync()).unwrap();
+        }
+
+        let store = Store::open(config).unwrap();
+        assert_eq!(store.group_names().unwrap(), vec![unnamed.clone(), dogs.clone()]);
+        let access = store.load_access(&dogs).unwrap().unwrap();
+        assert!(access.is_op(&"jimb".to_string()));
+        assert!(store.load_access(&unnamed).unwrap().is_none());
+        let (backlog, recent, _log) =
+            block_on(store.open_group(&dogs, 5)).unwrap();
+        assert_eq!(backlog.next_seq(), 20);
+        // The ids of posts still in the log are remembered.
+        let jimb = Arc::new("jimb".to_string());
+        assert_eq!(recent.get(&jimb, 119), Some(19));
+        assert_eq!(recent.get(&jimb, 115), Some(15));
+        // Lines logged before posts had ids still read back.
+        let old_line = serde_json::to_string(&message(3)).unwrap();
+        let logged: Logged = serde_json::from_str(&old_line).unwrap();
+        assert_eq!(logged.id, None);
+        let replayed: Vec<u64> = backlog
+            .replay(crate::History::Last(100))
+            .into_iter()
+            .map(|packet| match packet {
+                FromServer::Message { seq, .. } => seq,
+                other => panic!("unexpected packet {:?}", other),
+            })
+            .collect();
+        assert_eq!(replayed, vec![15, 16, 17, 18, 19]);
+
+        let segments = list_segments(&dir.join("dogs")).unwrap();
+        assert!(segments.len() < 10, "old segments not compacted: {:?}", segments);
+        assert!(segments[0].0 <= 15);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
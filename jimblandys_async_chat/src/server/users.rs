@@ -0,0 +1,67 @@
+//! The server-wide table of nicknames in use.
+
+use crate::server::accounts::Accounts;
+use crate::server::connection::Outbound;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+pub struct UserTable {
+    online: Mutex<HashMap<Arc<String>, Arc<Outbound>>>,
+    /// If present, only these nicknames may be claimed, and only with their
+    /// passwords. Otherwise any valid nickname is first come, first served.
+    accounts: Option<Accounts>,
+}
+
+impl UserTable {
+    pub fn new(accounts: Option<Accounts>) -> UserTable {
+        UserTable {
+            online: Mutex::new(HashMap::new()),
+            accounts,
+        }
+    }
+
+    /// Claim `nickname` for `outbound`, releasing `previous` if the
+    /// connection already had one.
+    pub fn register(
+        &self,
+        nickname: Arc<String>,
+        password: Option<&str>,
+        previous: Option<&Arc<String>>,
+        outbound: Arc<Outbound>,
+    ) -> Result<(), String> {
+        validate(&nickname)?;
+        if let Some(accounts) = &self.accounts {
+            accounts.authenticate(&nickname, password)?;
+        }
+        let mut guard = self.online.lock().unwrap();
+        if previous != Some(&nickname) && guard.contains_key(&nickname) {
+            return Err(format!("Nickname '{}' is already taken", nickname));
+        }
+        if let Some(previous) = previous {
+            guard.remove(previous);
+        }
+        guard.insert(nickname, outbound);
+        Ok(())
+    }
+
+    pub fn get(&self, nickname: &String) -> Option<Arc<Outbound>> {
+        self.online.lock().unwrap().get(nickname).cloned()
+    }
+
+    pub fn unregister(&self, nickname: &String) {
+        self.online.lock().unwrap().remove(nickname);
+    }
+}
+
+fn validate(nickname: &str) -> Result<(), String> {
+    if nickname.is_empty() || nickname.len() > 32 {
+        return Err("Nickname must be 1 to 32 bytes long".to_string());
+    }
+    if nickname.contains(|c: char| c.is_whitespace() || c == '@') {
+        return Err(format!(
+            "Nickname '{}' must not contain spaces or '@'",
+            nickname
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,237 @@
+//! A WebSocket gateway, so that browsers can chat too.
+//!
+//! WebSocket clients send and receive the same `FromClient` and `FromServer`
+//! packets as everyone else, as JSON, one per text message. They share the
+//! server's groups and nicknames with clients connected the usual way.
+
+use crate::utils::{ChatError, ChatResult, FrameTooLong};
+use crate::FromClient;
+use async_std::net::{TcpListener, TcpStream};
+use async_std::prelude::*;
+use async_std::task;
+use async_tungstenite::tungstenite::protocol::WebSocketConfig;
+use async_tungstenite::tungstenite::Message;
+use std::sync::Arc;
+
+use crate::server::connection::{serve_connection, Outbound, ToClient};
+use crate::server::ServerState;
+
+/// Accept WebSocket connections on `listener` forever.
+pub async fn listen(
+    listener: TcpListener,
+    state: Arc<ServerState>,
+) -> ChatResult<()> {
+    let mut new_connections = listener.incoming();
+    while let Some(socket_result) = new_connections.next().await {
+        let socket = socket_result?;
+        let state = state.clone();
+        task::spawn(async move {
+            crate::server::log_error(work_websocket(socket, state).await);
+        });
+    }
+    Ok(())
+}
+
+pub async fn work_websocket(
+    socket: TcpStream,
+    state: Arc<ServerState>,
+) -> ChatResult<()> {
+    let peer = socket.peer_addr()?;
+    let limits = &state.limits;
+    // Messages over our own limit get the configured treatment, below; this
+    // is a backstop against clients that go far beyond it.
+    let config = WebSocketConfig::default()
+        .max_message_size(Some(limits.max_line_bytes.saturating_mul(4)));
+    let websocket =
+        async_tungstenite::accept_async_with_config(socket, Some(config))
+            .await?;
+    let (to_client, from_client) = futures_util::StreamExt::split(websocket);
+
+    let max_message = limits.max_line_bytes;
+    let from_client = from_client.filter_map(move |message| match message {
+        Ok(Message::Text(text)) if text.len() > max_message => {
+            Some(Err(FrameTooLong { limit: max_message }.into()))
+        }
+        Ok(Message::Text(text)) => Some(
+            serde_json::from_str::<FromClient>(text.as_str())
+                .map_err(ChatError::from),
+        ),
+        Ok(Message::Binary(_)) => {
+            Some(Err("WebSocket clients must send text messages".into()))
+        }
+        // Pings are answered by tungstenite itself, and a close ends the
+        // stream.
+        Ok(_) => None,
+        Err(error) => Some(Err(error.into())),
+    });
+
+    let outbound = Arc::new(Outbound::new(
+        peer,
+        ToClient::WebSocket(Box::new(to_client)),
+    ));
+    serve_connection(from_client, outbound, state).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ServerConfig;
+    use crate::utils;
+    use crate::FromServer;
+    use async_std::io::BufReader;
+    use std::time::Duration;
+
+    fn text(s: &str) -> Arc<String> {
+        Arc::new(s.to_string())
+    }
+
+    #[test]
+    fn websocket_and_tcp_clients_share_groups() {
+        task::block_on(async {
+            let state =
+                Arc::new(ServerState::open(&ServerConfig::default()).unwrap());
+
+            let ws_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let ws_address = ws_listener.local_addr().unwrap();
+            task::spawn(listen(ws_listener, state.clone()));
+
+            let tcp_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let tcp_address = tcp_listener.local_addr().unwrap();
+            task::spawn(async move {
+                let (socket, peer) = tcp_listener.accept().await.unwrap();
+                let result = crate::server::connection::work_connection(
+                    crate::server::connection::TcpConnection(socket),
+                    peer,
+                    state,
+                );
+                crate::server::log_error(result.await);
+            });
+
+            // The browser's side.
+            let url = format!("ws://{}/", ws_address);
+            let (mut browser, _) =
+                async_tungstenite::async_std::connect_async(url)
+                    .await
+                    .unwrap();
+            let send_ws = |packet: FromClient| {
+                Message::text(serde_json::to_string(&packet).unwrap())
+            };
+            browser
+                .send(send_ws(FromClient::Hello {
+                    nickname: text("browser"),
+                    password: None,
+                    format: Default::default(),
+                }))
+                .await
+                .unwrap();
+            browser
+                .send(send_ws(FromClient::Join {
+                    group_name: text("dogs"),
+                    history: None,
+                }))
+                .await
+                .unwrap();
+
+            // Requests on a connection are handled in order, so once the
+            // member list shows the browser, its join has landed.
+            loop {
+                browser
+                    .send(send_ws(FromClient::ListMembers {
+                        group_name: text("dogs"),
+                    }))
+                    .await
+                    .unwrap();
+                let reply = browser
+                    .next()
+                    .timeout(Duration::from_secs(5))
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .unwrap();
+                let reply: FromServer =
+                    serde_json::from_str(reply.to_text().unwrap()).unwrap();
+                match reply {
+                    FromServer::Members { nicknames, .. }
+                        if nicknames.contains(&text("browser")) =>
+                    {
+                        break
+                    }
+                    FromServer::Members { .. } => {}
+                    other => panic!("unexpected packet {:?}", other),
+                }
+            }
+
+            // The terminal's side.
+            let terminal = TcpStream::connect(tcp_address).await.unwrap();
+            let mut to_server = terminal.clone();
+            let mut from_server = utils::receive_as_json::<_, FromServer>(
+                BufReader::new(terminal),
+                1 << 16,
+            );
+            for packet in [
+                FromClient::Hello {
+                    nickname: text("terminal"),
+                    password: None,
+                    format: Default::default(),
+                },
+                FromClient::Join {
+                    group_name: text("dogs"),
+                    history: None,
+                },
+                FromClient::Post {
+                    group_name: text("dogs"),
+                    message: text("woof"),
+                    id: None,
+                },
+            ] {
+                utils::send_as_json(&mut to_server, &packet).await.unwrap();
+            }
+
+            let received = browser
+                .next()
+                .timeout(Duration::from_secs(5))
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            let received: FromServer =
+                serde_json::from_str(received.to_text().unwrap()).unwrap();
+            match received {
+                FromServer::Message {
+                    sender, message, ..
+                } => {
+                    assert_eq!(sender.as_str(), "terminal");
+                    assert_eq!(message.as_str(), "woof");
+                }
+                other => panic!("unexpected packet {:?}", other),
+            }
+
+            browser
+                .send(send_ws(FromClient::Post {
+                    group_name: text("dogs"),
+                    message: text("bark"),
+                    id: None,
+                }))
+                .await
+                .unwrap();
+            loop {
+                let received = from_server
+                    .next()
+                    .timeout(Duration::from_secs(5))
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .unwrap();
+                if let FromServer::Message {
+                    sender, message, ..
+                } = received
+                {
+                    if sender.as_str() == "browser" {
+                        assert_eq!(message.as_str(), "bark");
+                        break;
+                    }
+                }
+            }
+        });
+    }
+}
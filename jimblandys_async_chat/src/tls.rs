@@ -0,0 +1,67 @@
+//! TLS configuration shared by the client and server, enabled by the `tls`
+//! feature.
+
+use crate::utils::ChatResult;
+use futures_rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use futures_rustls::rustls::{self, ClientConfig, RootCertStore, ServerConfig};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+pub use futures_rustls::{TlsAcceptor, TlsConnector};
+
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn load_certs(path: &Path) -> ChatResult<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(format!("no certificates in {}", path.display()).into());
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> ChatResult<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| format!("no private key in {}", path.display()).into())
+}
+
+/// An acceptor presenting the PEM certificate chain in `cert` and the
+/// private key in `key`.
+pub fn acceptor(cert: &Path, key: &Path) -> ChatResult<TlsAcceptor> {
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(load_certs(cert)?, load_key(key)?)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// A connector trusting only the PEM certificates in `ca_bundle`.
+pub fn connector(ca_bundle: &Path) -> ChatResult<TlsConnector> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca_bundle)? {
+        roots.add(cert)?;
+    }
+    let config = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// The name to verify the server's certificate against, taken from the host
+/// part of a `HOST:PORT` address.
+pub fn server_name(address: &str) -> ChatResult<ServerName<'static>> {
+    let host = match address.rfind(':') {
+        Some(colon) => &address[..colon],
+        None => address,
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    Ok(ServerName::try_from(host.to_string())?)
+}
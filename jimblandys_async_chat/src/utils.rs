@@ -5,31 +5,437 @@ use std::error::Error;
 pub type ChatError = Box<dyn Error + Send + Sync + 'static>;
 pub type ChatResult<T> = Result<T, ChatError>;
 
+use async_std::io::{BufRead, Write};
 use async_std::prelude::*;
-use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::future::Future;
 use std::marker::Unpin;
 
+/// A way of framing packets on the wire.
+pub trait Codec {
+    /// Serialize `packet` as a complete frame, ready to be written.
+    fn encode<P: Serialize>(&self, packet: &P) -> ChatResult<Vec<u8>>;
+
+    /// Read the next frame from `inbound`, returning its contents, or `None`
+    /// at the end of the stream. A frame longer than `max_frame` bytes is
+    /// skipped and reported as `FrameTooLong`.
+    fn read_frame<'a, S>(
+        &'a self,
+        inbound: &'a mut S,
+        max_frame: usize,
+    ) -> impl Future<Output = ChatResult<Option<Vec<u8>>>> + 'a
+    where
+        S: BufRead + Unpin;
+
+    /// Deserialize the contents of a frame returned by `read_frame`.
+    fn decode<P: DeserializeOwned>(&self, frame: &[u8]) -> ChatResult<P>;
+}
+
+/// One JSON document per line. Easy to read and type by hand.
+#[derive(Clone, Copy, Debug)]
+pub struct JsonLines;
+
+impl Codec for JsonLines {
+    fn encode<P: Serialize>(&self, packet: &P) -> ChatResult<Vec<u8>> {
+        let mut json = serde_json::to_vec(packet)?;
+        json.push(b'\n');
+        Ok(json)
+    }
+
+    fn read_frame<'a, S>(
+        &'a self,
+        inbound: &'a mut S,
+        max_frame: usize,
+    ) -> impl Future<Output = ChatResult<Option<Vec<u8>>>> + 'a
+    where
+        S: BufRead + Unpin,
+    {
+        read_line(inbound, max_frame)
+    }
+
+    fn decode<P: DeserializeOwned>(&self, frame: &[u8]) -> ChatResult<P> {
+        Ok(serde_json::from_slice(frame)?)
+    }
+}
+
+/// A CBOR document preceded by its length, as a big-endian `u32`. More
+/// compact than JSON, and indifferent to what the packets contain.
+#[derive(Clone, Copy, Debug)]
+pub struct LengthPrefixedCbor;
+
+impl Codec for LengthPrefixedCbor {
+    fn encode<P: Serialize>(&self, packet: &P) -> ChatResult<Vec<u8>> {
+        let mut frame = vec![0; 4];
+        ciborium::ser::into_writer(packet, &mut frame)?;
+        let length = u32::try_from(frame.len() - 4)
+            .map_err(|_| "packet too large to frame")?;
+        frame[..4].copy_from_slice(&length.to_be_bytes());
+        Ok(frame)
+    }
+
+    async fn read_frame<'a, S>(
+        &'a self,
+        inbound: &'a mut S,
+        max_frame: usize,
+    ) -> ChatResult<Option<Vec<u8>>>
+    where
+        S: BufRead + Unpin,
+    {
+        let buffered = futures_lite::AsyncBufReadExt::fill_buf(inbound).await?;
+        if buffered.is_empty() {
+            return Ok(None);
+        }
+        let mut length = [0; 4];
+        inbound.read_exact(&mut length).await?;
+        let length = u32::from_be_bytes(length) as usize;
+        if length > max_frame {
+            let mut rest = (&mut *inbound).take(length as u64);
+            async_std::io::copy(&mut rest, &mut async_std::io::sink()).await?;
+            return Err(FrameTooLong { limit: max_frame }.into());
+        }
+        let mut frame = vec![0; length];
+        inbound.read_exact(&mut frame).await?;
+        Ok(Some(frame))
+    }
+
+    fn decode<P: DeserializeOwned>(&self, frame: &[u8]) -> ChatResult<P> {
+        Ok(ciborium::de::from_reader(frame)?)
+    }
+}
+
+/// The codecs a connection may use, chosen by the client in its first
+/// `Hello`, which is always sent as JSON.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub enum WireFormat {
+    #[default]
+    JsonLines,
+    Cbor,
+}
+
+impl WireFormat {
+    pub fn is_default(&self) -> bool {
+        *self == WireFormat::default()
+    }
+}
+
+impl std::str::FromStr for WireFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<WireFormat, String> {
+        match s {
+            "json" => Ok(WireFormat::JsonLines),
+            "cbor" => Ok(WireFormat::Cbor),
+            _ => Err(format!("wire format must be 'json' or 'cbor', not '{}'", s)),
+        }
+    }
+}
+
+impl Codec for WireFormat {
+    fn encode<P: Serialize>(&self, packet: &P) -> ChatResult<Vec<u8>> {
+        match self {
+            WireFormat::JsonLines => JsonLines.encode(packet),
+            WireFormat::Cbor => LengthPrefixedCbor.encode(packet),
+        }
+    }
+
+    async fn read_frame<'a, S>(
+        &'a self,
+        inbound: &'a mut S,
+        max_frame: usize,
+    ) -> ChatResult<Option<Vec<u8>>>
+    where
+        S: BufRead + Unpin,
+    {
+        match self {
+            WireFormat::JsonLines => JsonLines.read_frame(inbound, max_frame).await,
+            WireFormat::Cbor => LengthPrefixedCbor.read_frame(inbound, max_frame).await,
+        }
+    }
+
+    fn decode<P: DeserializeOwned>(&self, frame: &[u8]) -> ChatResult<P> {
+        match self {
+            WireFormat::JsonLines => JsonLines.decode(frame),
+            WireFormat::Cbor => LengthPrefixedCbor.decode(frame),
+        }
+    }
+}
+
+/// Write `packet` to `outbound` as a frame of `codec`.
+pub async fn send_packet<C, S, P>(codec: &C, outbound: &mut S, packet: &P) -> ChatResult<()>
+where
+    C: Codec,
+    S: Write + Unpin,
+    P: Serialize,
+{
+    let frame = codec.encode(packet)?;
+    outbound.write_all(&frame).await?;
+    Ok(())
+}
+
 pub async fn send_as_json<S, P>(outbound: &mut S, packet: &P) -> ChatResult<()>
 where
     S: async_std::io::Write + Unpin,
     P: Serialize,
 {
-    let mut json = serde_json::to_string(&packet)?;
-    json.push('\n');
-    outbound.write_all(json.as_bytes()).await?;
-    Ok(())
+    send_packet(&JsonLines, outbound, packet).await
 }
 
-use serde::de::DeserializeOwned;
+/// The error `receive_packets` produces for a frame longer than it allows.
+#[derive(Debug)]
+pub struct FrameTooLong {
+    pub limit: usize,
+}
+
+impl std::fmt::Display for FrameTooLong {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "frame longer than {} bytes", self.limit)
+    }
+}
+
+impl Error for FrameTooLong {}
 
-pub fn receive_as_json<S, P>(inbound: S) -> impl Stream<Item = ChatResult<P>>
+/// Parse each frame of `inbound` as a `P`. A frame longer than `max_frame`
+/// bytes is skipped and reported as a `FrameTooLong` error, after which the
+/// stream carries on with the next frame; any other I/O error ends the
+/// stream.
+pub fn receive_packets<C, S, P>(codec: C, inbound: S, max_frame: usize)
+    -> impl Stream<Item = ChatResult<P>> + Unpin
+    where C: Codec,
+          S: BufRead + Unpin,
+          P: DeserializeOwned,
+{
+    let state = (codec, Some(inbound));
+    Box::pin(futures_lite::stream::unfold(state, move |(codec, inbound)| async move {
+        let mut inbound = inbound?;
+        match codec.read_frame(&mut inbound, max_frame).await {
+            Ok(Some(frame)) => {
+                let parsed = codec.decode::<P>(&frame);
+                Some((parsed, (codec, Some(inbound))))
+            }
+            Ok(None) => None,
+            Err(error) => {
+                let carry_on = error.is::<FrameTooLong>();
+                let inbound = if carry_on { Some(inbound) } else { None };
+                Some((Err(error), (codec, inbound)))
+            }
+        }
+    }))
+}
+
+pub fn receive_as_json<S, P>(inbound: S, max_line: usize)
+    -> impl Stream<Item = ChatResult<P>> + Unpin
     where S: async_std::io::BufRead + Unpin,
           P: DeserializeOwned,
 {
-    inbound.lines()
-        .map(|line_result| -> ChatResult<P> {
-            let line = line_result?;
-            let parsed = serde_json::from_str::<P>(&line)?;
-            Ok(parsed)
-        })
+    receive_packets(JsonLines, inbound, max_line)
+}
+
+/// Read a line of at most `max_line` bytes, not counting the line ending.
+/// Return `None` at end of file.
+async fn read_line<S>(inbound: &mut S, max_line: usize) -> ChatResult<Option<Vec<u8>>>
+    where S: async_std::io::BufRead + Unpin,
+{
+    let mut line = Vec::new();
+    // Leave room for a `\r\n` line ending.
+    let limit = (max_line as u64).saturating_add(2);
+    if (&mut *inbound).take(limit).read_until(b'\n', &mut line).await? == 0 {
+        return Ok(None);
+    }
+    let complete = line.last() == Some(&b'\n');
+    if complete {
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    }
+    if line.len() <= max_line {
+        // If there's no newline, this is the last line.
+        return Ok(Some(line));
+    }
+
+    if !complete {
+        // Discard the rest of the line, without buffering it all.
+        loop {
+            line.clear();
+            let read = (&mut *inbound).take(1 << 16).read_until(b'\n', &mut line).await?;
+            if read == 0 || line.last() == Some(&b'\n') {
+                break;
+            }
+        }
+    }
+    Err(FrameTooLong { limit: max_line }.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Delivery, FromClient, FromServer, History};
+    use async_std::io::Cursor;
+    use std::sync::Arc;
+
+    fn text(s: &str) -> Arc<String> {
+        Arc::new(s.to_string())
+    }
+
+    fn every_from_client() -> Vec<FromClient> {
+        let packets = vec![
+            FromClient::Hello { nickname: text("jimb"), password: None, format: WireFormat::Cbor },
+            FromClient::Hello { nickname: text("jimb"), password: Some(text("pw")), format: WireFormat::JsonLines },
+            FromClient::Join { group_name: text("dogs"), history: None },
+            FromClient::Join { group_name: text("dogs"), history: Some(History::Last(5)) },
+            FromClient::Join { group_name: text("dogs"), history: Some(History::Since(7)) },
+            FromClient::Leave { group_name: text("dogs") },
+            FromClient::Post { group_name: text("dogs"), message: text("line one\nline two"), id: None },
+            FromClient::Post { group_name: text("dogs"), message: text("woof"), id: Some(7) },
+            FromClient::ListGroups,
+            FromClient::ListMembers { group_name: text("dogs") },
+            FromClient::Whisper { to: text("ltindall"), message: text("psst") },
+            FromClient::Op { group_name: text("dogs"), nickname: text("jorendorff") },
+            FromClient::Kick { group_name: text("dogs"), nickname: text("troll") },
+            FromClient::Ban { group_name: text("dogs"), nickname: text("troll") },
+            FromClient::Unban { group_name: text("dogs"), nickname: text("troll") },
+            FromClient::Invite { group_name: text("dogs"), nickname: text("ltindall") },
+            FromClient::SetInviteOnly { group_name: text("dogs"), invite_only: true },
+            FromClient::SetDelivery { group_name: text("dogs"), delivery: Delivery::Block { timeout_ms: 250 } },
+            FromClient::Ping,
+            FromClient::Pong,
+        ];
+        // A reminder to add new variants to the list above.
+        for packet in &packets {
+            match packet {
+                FromClient::Hello { .. }
+                | FromClient::Join { .. }
+                | FromClient::Leave { .. }
+                | FromClient::Post { .. }
+                | FromClient::ListGroups
+                | FromClient::ListMembers { .. }
+                | FromClient::Whisper { .. }
+                | FromClient::Op { .. }
+                | FromClient::Kick { .. }
+                | FromClient::Ban { .. }
+                | FromClient::Unban { .. }
+                | FromClient::Invite { .. }
+                | FromClient::SetInviteOnly { .. }
+                | FromClient::SetDelivery { .. }
+                | FromClient::Ping
+                | FromClient::Pong => {}
+            }
+        }
+        packets
+    }
+
+    fn every_from_server() -> Vec<FromServer> {
+        let timestamp = "2020-06-01T12:30:00Z".parse().unwrap();
+        let packets = vec![
+            FromServer::Message {
+                group_name: text("dogs"),
+                seq: 7,
+                sender: text("jimb"),
+                timestamp,
+                message: text("Samoyeds rock!"),
+            },
+            FromServer::Private { from: text("jimb"), timestamp, message: text("psst") },
+            FromServer::Left { group_name: text("dogs") },
+            FromServer::Groups { group_names: vec![text("cats"), text("dogs")] },
+            FromServer::Members { group_name: text("dogs"), nicknames: vec![] },
+            FromServer::Shutdown { reason: "maintenance".to_string() },
+            FromServer::Ack { id: 7, seq: 12 },
+            FromServer::Ping,
+            FromServer::Pong,
+            FromServer::Error("oops".to_string()),
+        ];
+        for packet in &packets {
+            match packet {
+                FromServer::Message { .. }
+                | FromServer::Private { .. }
+                | FromServer::Left { .. }
+                | FromServer::Groups { .. }
+                | FromServer::Members { .. }
+                | FromServer::Shutdown { .. }
+                | FromServer::Ack { .. }
+                | FromServer::Ping
+                | FromServer::Pong
+                | FromServer::Error(_) => {}
+            }
+        }
+        packets
+    }
+
+    fn round_trip<P>(format: WireFormat, packets: Vec<P>)
+    where
+        P: Serialize + DeserializeOwned + PartialEq + std::fmt::Debug + Send,
+    {
+        let mut wire = Vec::new();
+        async_std::task::block_on(async {
+            for packet in &packets {
+                send_packet(&format, &mut wire, packet).await.unwrap();
+            }
+        });
+        let received: Vec<P> = async_std::task::block_on(
+            receive_packets(format, Cursor::new(wire), 1 << 10)
+                .map(Result::unwrap)
+                .collect(),
+        );
+        assert_eq!(received, packets);
+    }
+
+    #[test]
+    fn every_packet_round_trips() {
+        for format in [WireFormat::JsonLines, WireFormat::Cbor] {
+            round_trip(format, every_from_client());
+            round_trip(format, every_from_server());
+        }
+    }
+
+    #[test]
+    fn long_lines_are_skipped() {
+        let input = format!("1\n{}\n2\r\n[3]", "9".repeat(100));
+        let received: Vec<ChatResult<serde_json::Value>> = async_std::task::block_on(
+            receive_as_json(Cursor::new(input), 10).collect(),
+        );
+        assert_eq!(received.len(), 4);
+        assert_eq!(received[0].as_ref().unwrap(), 1);
+        assert_eq!(
+            received[1].as_ref().unwrap_err().downcast_ref::<FrameTooLong>().unwrap().limit,
+            10
+        );
+        assert_eq!(received[2].as_ref().unwrap(), 2);
+        assert_eq!(received[3].as_ref().unwrap(), &serde_json::json!([3]));
+    }
+
+    #[test]
+    fn line_endings_do_not_count_against_the_limit() {
+        let input = "1234\n5678\r\n12345\n67890\r\n1234";
+        let lines: Vec<ChatResult<Option<Vec<u8>>>> = async_std::task::block_on(async {
+            let mut inbound = Cursor::new(input);
+            let mut lines = Vec::new();
+            for _ in 0..6 {
+                lines.push(read_line(&mut inbound, 4).await);
+            }
+            lines
+        });
+        assert_eq!(lines[0].as_ref().unwrap().as_deref(), Some(&b"1234"[..]));
+        assert_eq!(lines[1].as_ref().unwrap().as_deref(), Some(&b"5678"[..]));
+        assert!(lines[2].as_ref().unwrap_err().is::<FrameTooLong>());
+        assert!(lines[3].as_ref().unwrap_err().is::<FrameTooLong>());
+        assert_eq!(lines[4].as_ref().unwrap().as_deref(), Some(&b"1234"[..]));
+        assert_eq!(lines[5].as_ref().unwrap(), &None);
+    }
+
+    #[test]
+    fn long_cbor_frames_are_skipped() {
+        let mut wire = Vec::new();
+        for message in &["short", &"long".repeat(100), "short again"] {
+            wire.extend(LengthPrefixedCbor.encode(&message.to_string()).unwrap());
+        }
+        let received: Vec<ChatResult<String>> = async_std::task::block_on(
+            receive_packets(LengthPrefixedCbor, Cursor::new(wire), 100).collect(),
+        );
+        assert_eq!(received.len(), 3);
+        assert_eq!(received[0].as_ref().unwrap(), "short");
+        assert!(received[1].as_ref().unwrap_err().is::<FrameTooLong>());
+        assert_eq!(received[2].as_ref().unwrap(), "short again");
+    }
 }
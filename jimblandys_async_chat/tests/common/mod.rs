@@ -0,0 +1,226 @@
+//! A server running in the test's own process, and scripted clients to talk
+//! to it.
+//!
+//! Clients read replies only when asked to, so a client that stops asking
+//! stops reading, just as a stalled client would.
+
+// Each test file uses its own selection of these.
+#![allow(dead_code)]
+
+use async_chat::server::{run_server, ServerConfig};
+use async_chat::utils::{self, ChatResult};
+use async_chat::{FromClient, FromServer};
+use async_std::io::BufReader;
+use async_std::net::{TcpListener, TcpStream};
+use async_std::prelude::*;
+use async_std::task;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long to wait for any one reply before failing the test.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn text(s: &str) -> Arc<String> {
+    Arc::new(s.to_string())
+}
+
+/// Start a server configured by `config` on an ephemeral port, and return its
+/// address. It runs until the test process exits.
+pub async fn start_server(config: ServerConfig) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    task::spawn(async move {
+        if let Err(error) = run_server(listener, config).await {
+            panic!("server failed: {}", error);
+        }
+    });
+    address
+}
+
+type Replies = Pin<Box<dyn Stream<Item = ChatResult<FromServer>> + Send>>;
+
+pub struct TestClient {
+    to_server: TcpStream,
+    from_server: Replies,
+    /// Replies read while looking for something else, oldest first.
+    pending: VecDeque<FromServer>,
+}
+
+impl TestClient {
+    /// Connect to `address` and say Hello as `nickname`.
+    pub async fn connect(address: SocketAddr, nickname: &str) -> TestClient {
+        let socket = TcpStream::connect(address).await.unwrap();
+        // The server sends small packets in quick succession; don't let
+        // delayed acknowledgements hold them up.
+        socket.set_nodelay(true).unwrap();
+        let reader = BufReader::new(socket.clone());
+        let from_server = Box::pin(utils::receive_as_json(reader, 1 << 20));
+        let mut client = TestClient {
+            to_server: socket,
+            from_server,
+            pending: VecDeque::new(),
+        };
+        client
+            .send(FromClient::Hello {
+                nickname: text(nickname),
+                password: None,
+                format: Default::default(),
+            })
+            .await;
+        client
+    }
+
+    pub async fn send(&mut self, request: FromClient) {
+        utils::send_as_json(&mut self.to_server, &request)
+            .await
+            .expect("sending to server");
+    }
+
+    /// Join `group_name`, returning once the server has acted on it.
+    pub async fn join(&mut self, group_name: &str) -> Result<(), String> {
+        self.send(FromClient::Join {
+            group_name: text(group_name),
+            history: None,
+        })
+        .await;
+        // Requests on one connection are handled in order, so any complaint
+        // about the join comes before the member list.
+        let members = self.members(group_name).await;
+        match members {
+            Ok(_) => Ok(()),
+            Err(error) => {
+                // That was the join's error. The list, or a complaint that
+                // there's no such group, is still on its way.
+                let _ = self.members_reply().await;
+                Err(error)
+            }
+        }
+    }
+
+    pub async fn leave(&mut self, group_name: &str) {
+        self.send(FromClient::Leave { group_name: text(group_name) }).await;
+    }
+
+    pub async fn post(&mut self, group_name: &str, message: &str) {
+        self.send(FromClient::Post {
+            group_name: text(group_name),
+            message: text(message),
+            id: None,
+        })
+        .await;
+    }
+
+    /// The nicknames of `group_name`'s members, as the server lists them.
+    pub async fn members(
+        &mut self,
+        group_name: &str,
+    ) -> Result<Vec<Arc<String>>, String> {
+        self.send(FromClient::ListMembers { group_name: text(group_name) })
+            .await;
+        self.members_reply().await
+    }
+
+    /// The next reply from the server.
+    pub async fn next(&mut self) -> FromServer {
+        if let Some(reply) = self.pending.pop_front() {
+            return reply;
+        }
+        self.from_server
+            .next()
+            .timeout(REPLY_TIMEOUT)
+            .await
+            .expect("timed out waiting for a reply")
+            .expect("connection closed")
+            .expect("reading from server")
+    }
+
+    /// The next reply that `wanted` accepts. Replies it passes over are kept
+    /// for later calls.
+    pub async fn expect(
+        &mut self,
+        mut wanted: impl FnMut(&FromServer) -> bool,
+    ) -> FromServer {
+        let mut skipped = Vec::new();
+        let reply = loop {
+            let reply = self.next().await;
+            if wanted(&reply) {
+                break reply;
+            }
+            skipped.push(reply);
+        };
+        for passed_over in skipped.into_iter().rev() {
+            self.pending.push_front(passed_over);
+        }
+        reply
+    }
+
+    /// The next reply, which must be a message. Return its group, sender and
+    /// text.
+    pub async fn expect_message(&mut self) -> (String, String, String) {
+        match self.next().await {
+            FromServer::Message { group_name, sender, message, .. } => (
+                group_name.to_string(),
+                sender.to_string(),
+                message.to_string(),
+            ),
+            other => panic!("expected a message, got {:?}", other),
+        }
+    }
+
+    /// The next reply, which must be an error. Return its text.
+    pub async fn expect_error(&mut self) -> String {
+        match self.next().await {
+            FromServer::Error(error) => error,
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    /// Check that nothing but the answer to a ping is waiting for us.
+    pub async fn expect_quiet(&mut self) {
+        self.send(FromClient::Ping).await;
+        match self.next().await {
+            FromServer::Pong => {}
+            other => panic!("expected nothing more, got {:?}", other),
+        }
+    }
+
+    /// Every reply until the server closes the connection, or sends
+    /// something unreadable.
+    pub async fn until_closed(mut self) -> Vec<FromServer> {
+        let mut replies: Vec<_> = self.pending.drain(..).collect();
+        while let Some(Ok(reply)) = self
+            .from_server
+            .next()
+            .timeout(REPLY_TIMEOUT)
+            .await
+            .expect("timed out waiting for the connection to close")
+        {
+            replies.push(reply);
+        }
+        replies
+    }
+
+    /// Close the connection.
+    pub fn disconnect(self) {
+        let _ = self.to_server.shutdown(std::net::Shutdown::Both);
+    }
+
+    async fn members_reply(&mut self) -> Result<Vec<Arc<String>>, String> {
+        let reply = self
+            .expect(|reply| {
+                matches!(
+                    reply,
+                    FromServer::Members { .. } | FromServer::Error(_)
+                )
+            })
+            .await;
+        match reply {
+            FromServer::Members { nicknames, .. } => Ok(nicknames),
+            FromServer::Error(error) => Err(error),
+            _ => unreachable!(),
+        }
+    }
+}
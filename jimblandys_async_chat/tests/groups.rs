@@ -0,0 +1,86 @@
+//! Hammer one group with joins and leaves from many clients at once, and
+//! check that everyone still ends up in the same group.
+
+mod common;
+
+use async_chat::server::ServerConfig;
+use async_chat::{FromClient, FromServer};
+use async_std::task;
+use common::{start_server, text, TestClient};
+
+const CLIENTS: usize = 4;
+const ROUNDS: usize = 100;
+
+#[test]
+fn concurrent_joins_and_leaves_share_one_group() {
+    let mut config = ServerConfig::default();
+    config.limits.connection_rate = None;
+    config.limits.group_rate = None;
+
+    task::block_on(async {
+        let address = start_server(config).await;
+
+        // Each round, a client joins, posts, and leaves. A client that
+        // joined the group everyone else can reach hears its own post; one
+        // that joined a group dropped from the table doesn't.
+        let mut hammers = Vec::new();
+        for i in 0..CLIENTS {
+            hammers.push(task::spawn(async move {
+                let nickname = format!("dog{}", i);
+                let mut client = TestClient::connect(address, &nickname).await;
+                for round in 0..ROUNDS {
+                    let message = text(&format!("dog{} round {}", i, round));
+                    // Don't wait for the join to land: the point is to
+                    // race it against everyone else's.
+                    let join = FromClient::Join {
+                        group_name: text("dogs"),
+                        history: None,
+                    };
+                    client.send(join).await;
+                    client.post("dogs", &message).await;
+                    client.leave("dogs").await;
+
+                    let mut heard_own = false;
+                    loop {
+                        match client.next().await {
+                            FromServer::Message { message: m, .. } => {
+                                heard_own |= m == message;
+                            }
+                            FromServer::Left { .. } => break,
+                            FromServer::Error(error) => {
+                                panic!("dog{} round {}: {}", i, round, error)
+                            }
+                            _ => {}
+                        }
+                    }
+                    assert!(
+                        heard_own,
+                        "dog{} missed its own post in round {}",
+                        i, round
+                    );
+                }
+                client
+            }));
+        }
+        let mut clients = Vec::new();
+        for hammer in hammers {
+            clients.push(hammer.await);
+        }
+
+        // Now have everyone join for good.
+        for client in &mut clients {
+            client.join("dogs").await.unwrap();
+        }
+
+        let members = clients[0].members("dogs").await.unwrap();
+        let expected: Vec<_> =
+            (0..CLIENTS).map(|i| text(&format!("dog{}", i))).collect();
+        assert_eq!(members, expected);
+
+        clients[0].post("dogs", "all here?").await;
+        for client in &mut clients {
+            let (_, _, message) = client.expect_message().await;
+            assert_eq!(message, "all here?");
+        }
+    })
+}
@@ -0,0 +1,528 @@
+//! Conversations between scripted clients and an in-process server.
+
+mod common;
+
+use async_chat::server::ServerConfig;
+use async_chat::{Delivery, FromClient, FromServer, History};
+use async_std::net::TcpStream;
+use async_std::prelude::*;
+use async_std::task;
+use common::{start_server, text, TestClient};
+use std::time::Duration;
+
+/// A server with flood protection off, so tests can go as fast as they like.
+fn unlimited() -> ServerConfig {
+    let mut config = ServerConfig::default();
+    config.limits.connection_rate = None;
+    config.limits.group_rate = None;
+    config
+}
+
+#[test]
+fn posts_reach_every_member_of_their_group_and_no_one_else() {
+    task::block_on(async {
+        let address = start_server(unlimited()).await;
+        let mut alice = TestClient::connect(address, "alice").await;
+        let mut bob = TestClient::connect(address, "bob").await;
+        let mut carol = TestClient::connect(address, "carol").await;
+
+        alice.join("dogs").await.unwrap();
+        alice.join("cats").await.unwrap();
+        bob.join("dogs").await.unwrap();
+        carol.join("cats").await.unwrap();
+
+        alice.post("dogs", "woof").await;
+        alice.post("cats", "meow").await;
+
+        let said = |group: &str, message: &str| {
+            (group.to_string(), "alice".to_string(), message.to_string())
+        };
+        let (woof, meow) = (said("dogs", "woof"), said("cats", "meow"));
+        assert_eq!(bob.expect_message().await, woof);
+        assert_eq!(carol.expect_message().await, meow);
+
+        // Alice hears both; the groups' messages may arrive in either order.
+        let mut heard =
+            vec![alice.expect_message().await, alice.expect_message().await];
+        heard.sort();
+        assert_eq!(heard, vec![meow, woof]);
+
+        for client in [&mut alice, &mut bob, &mut carol] {
+            client.expect_quiet().await;
+        }
+    });
+}
+
+#[test]
+fn members_that_fall_behind_are_told_what_they_missed() {
+    task::block_on(async {
+        let mut config = unlimited();
+        config.queue_capacity = 16;
+        let address = start_server(config).await;
+
+        let mut slow = TestClient::connect(address, "slow").await;
+        slow.join("dogs").await.unwrap();
+
+        // `slow` reads nothing while this goes on, so once the socket
+        // buffers between it and the server fill, its queue overflows.
+        let mut poster = TestClient::connect(address, "poster").await;
+        let posting = task::spawn(async move {
+            let message = "w".repeat(4000);
+            for _ in 0..4000 {
+                poster.post("dogs", &message).await;
+            }
+            // A member only learns it missed messages when the next one
+            // arrives, so keep marking the end until `slow` sees a mark.
+            loop {
+                poster.post("dogs", "done").await;
+                task::sleep(Duration::from_millis(50)).await;
+            }
+        });
+
+        let mut received = 0;
+        let mut dropped = 0;
+        let done = loop {
+            match slow.next().await {
+                FromServer::Message { message, seq, .. }
+                    if message.as_str() == "done" =>
+                {
+                    break seq
+                }
+                FromServer::Message { .. } => received += 1,
+                FromServer::Error(error) => {
+                    let count = error
+                        .strip_prefix("Dropped ")
+                        .and_then(|rest| {
+                            rest.strip_suffix(" messages from dogs.")
+                        })
+                        .unwrap_or_else(|| {
+                            panic!("unexpected error: {}", error)
+                        });
+                    dropped += count.parse::<u64>().unwrap();
+                }
+                other => panic!("unexpected packet {:?}", other),
+            }
+        };
+        posting.cancel().await;
+        assert!(dropped > 0, "no messages were dropped");
+        assert_eq!(received + dropped, done);
+    });
+}
+
+#[test]
+fn blocking_delivery_holds_posters_back_instead_of_dropping() {
+    task::block_on(async {
+        let mut config = unlimited();
+        config.queue_capacity = 4;
+        config.delivery = Delivery::Block { timeout_ms: 10_000 };
+        let address = start_server(config).await;
+
+        let mut slow = TestClient::connect(address, "slow").await;
+        slow.join("dogs").await.unwrap();
+
+        let mut poster = TestClient::connect(address, "poster").await;
+        let posting = task::spawn(async move {
+            let message = "w".repeat(4000);
+            for _ in 0..2000 {
+                poster.post("dogs", &message).await;
+            }
+            poster.post("dogs", "done").await;
+            poster
+        });
+
+        // Dawdle now and then, far longer than it takes to post a message.
+        let mut received = 0;
+        loop {
+            match slow.next().await {
+                FromServer::Message { message, .. }
+                    if message.as_str() == "done" =>
+                {
+                    break
+                }
+                FromServer::Message { .. } => received += 1,
+                other => panic!("unexpected packet {:?}", other),
+            }
+            if received % 100 == 0 {
+                task::sleep(Duration::from_millis(20)).await;
+            }
+        }
+        assert_eq!(received, 2000);
+        posting.await.expect_quiet().await;
+    });
+}
+
+#[test]
+fn members_that_fall_behind_can_be_disconnected() {
+    task::block_on(async {
+        let mut config = unlimited();
+        config.queue_capacity = 4;
+        let address = start_server(config).await;
+
+        // The first to join owns the group, and may choose its policy.
+        let mut slow = TestClient::connect(address, "slow").await;
+        slow.join("dogs").await.unwrap();
+        slow.send(FromClient::SetDelivery {
+            group_name: text("dogs"),
+            delivery: Delivery::Disconnect,
+        })
+        .await;
+        slow.expect_quiet().await;
+
+        let mut poster = TestClient::connect(address, "poster").await;
+        let message = "w".repeat(4000);
+        for _ in 0..4000 {
+            poster.post("dogs", &message).await;
+        }
+
+        let replies = slow.until_closed().await;
+        let notice = "Too far behind in 'dogs'; disconnecting".to_string();
+        assert!(replies.contains(&FromServer::Error(notice)));
+        let messages = replies
+            .iter()
+            .filter(|reply| matches!(reply, FromServer::Message { .. }))
+            .count();
+        assert!(messages < 4000, "no messages were held back");
+
+        // The server let go of the connection, and the nickname with it.
+        let mut new_slow = TestClient::connect(address, "slow").await;
+        assert_eq!(new_slow.join("dogs").await, Ok(()));
+    });
+}
+
+#[test]
+fn joining_twice_is_an_error() {
+    task::block_on(async {
+        let address = start_server(unlimited()).await;
+        let mut alice = TestClient::connect(address, "alice").await;
+
+        alice.join("dogs").await.unwrap();
+        assert_eq!(
+            alice.join("dogs").await,
+            Err("You have already joined 'dogs'".to_string())
+        );
+
+        // The first membership is unaffected.
+        assert_eq!(alice.members("dogs").await, Ok(vec![text("alice")]));
+        alice.post("dogs", "still here").await;
+        let (_, _, message) = alice.expect_message().await;
+        assert_eq!(message, "still here");
+    });
+}
+
+#[test]
+fn saying_hello_again_with_the_same_nickname_is_fine() {
+    task::block_on(async {
+        let address = start_server(unlimited()).await;
+        let mut alice = TestClient::connect(address, "alice").await;
+        let mut bob = TestClient::connect(address, "bob").await;
+
+        alice
+            .send(FromClient::Hello {
+                nickname: text("alice"),
+                password: None,
+                format: Default::default(),
+            })
+            .await;
+        alice.expect_quiet().await;
+        alice.join("dogs").await.unwrap();
+
+        // Someone else's nickname is still taken.
+        bob.send(FromClient::Hello {
+            nickname: text("alice"),
+            password: None,
+            format: Default::default(),
+        })
+        .await;
+        assert_eq!(
+            bob.expect_error().await,
+            "Nickname 'alice' is already taken"
+        );
+    });
+}
+
+#[test]
+fn groups_must_have_names() {
+    task::block_on(async {
+        let address = start_server(unlimited()).await;
+        let mut alice = TestClient::connect(address, "alice").await;
+
+        assert_eq!(
+            alice.join("").await,
+            Err("Group names cannot be empty".to_string())
+        );
+        alice.expect_quiet().await;
+    });
+}
+
+#[test]
+fn bans_outlast_the_group() {
+    task::block_on(async {
+        let address = start_server(unlimited()).await;
+        let mut alice = TestClient::connect(address, "alice").await;
+        let mut bob = TestClient::connect(address, "bob").await;
+
+        alice.join("dogs").await.unwrap();
+        alice
+            .send(FromClient::Ban {
+                group_name: text("dogs"),
+                nickname: text("bob"),
+            })
+            .await;
+        alice.leave("dogs").await;
+        alice.expect(|packet| matches!(packet, FromServer::Left { .. })).await;
+        assert_eq!(
+            alice.members("dogs").await,
+            Err("Group 'dogs' does not exist".to_string())
+        );
+
+        // Bob can't wait for the group to empty and claim it as its owner.
+        assert_eq!(
+            bob.join("dogs").await,
+            Err("You are banned from 'dogs'".to_string())
+        );
+        alice.join("dogs").await.unwrap();
+        alice
+            .send(FromClient::Unban {
+                group_name: text("dogs"),
+                nickname: text("bob"),
+            })
+            .await;
+        alice.expect_quiet().await;
+        bob.join("dogs").await.unwrap();
+    });
+}
+
+#[test]
+fn replays_that_cannot_reach_back_far_enough_say_so() {
+    task::block_on(async {
+        let config = ServerConfig { history: 3, ..unlimited() };
+        let address = start_server(config).await;
+        let mut alice = TestClient::connect(address, "alice").await;
+        let mut bob = TestClient::connect(address, "bob").await;
+
+        alice.join("dogs").await.unwrap();
+        for n in 0..5 {
+            alice.post("dogs", &format!("woof {}", n)).await;
+            alice.expect_message().await;
+        }
+
+        bob.send(FromClient::Join {
+            group_name: text("dogs"),
+            history: Some(History::Since(0)),
+        })
+        .await;
+        assert_eq!(
+            bob.expect_error().await,
+            "2 messages from dogs are too old to replay."
+        );
+        for n in 2..5 {
+            let (_, _, message) = bob.expect_message().await;
+            assert_eq!(message, format!("woof {}", n));
+        }
+        bob.expect_quiet().await;
+    });
+}
+
+#[test]
+fn disconnecting_ends_memberships_and_frees_the_nickname() {
+    task::block_on(async {
+        let address = start_server(unlimited()).await;
+        let mut alice = TestClient::connect(address, "alice").await;
+        let mut bob = TestClient::connect(address, "bob").await;
+
+        alice.join("dogs").await.unwrap();
+        bob.join("dogs").await.unwrap();
+        bob.join("cats").await.unwrap();
+        bob.disconnect();
+
+        // The server notices in its own time. With no one left in it, `cats`
+        // goes away.
+        let mut dogs = alice.members("dogs").await;
+        let mut cats = alice.members("cats").await;
+        for _ in 0..100 {
+            if dogs.as_ref().is_ok_and(|m| m.len() == 1) && cats.is_err() {
+                break;
+            }
+            task::sleep(Duration::from_millis(20)).await;
+            dogs = alice.members("dogs").await;
+            cats = alice.members("cats").await;
+        }
+        assert_eq!(dogs, Ok(vec![text("alice")]));
+        assert_eq!(cats, Err("Group 'cats' does not exist".to_string()));
+
+        // Someone else may now go by `bob`.
+        let mut new_bob = TestClient::connect(address, "bob").await;
+        new_bob.join("dogs").await.unwrap();
+        assert_eq!(
+            alice.members("dogs").await,
+            Ok(vec![text("alice"), text("bob")])
+        );
+        new_bob.expect_quiet().await;
+    });
+}
+
+#[test]
+fn groups_and_their_membership_can_be_capped() {
+    task::block_on(async {
+        let mut config = unlimited();
+        config.max_groups = Some(1);
+        config.max_members = Some(1);
+        let address = start_server(config).await;
+        let mut alice = TestClient::connect(address, "alice").await;
+        let mut bob = TestClient::connect(address, "bob").await;
+
+        alice.join("dogs").await.unwrap();
+        assert_eq!(
+            alice.join("cats").await,
+            Err("Too many groups; cannot create 'cats'".to_string())
+        );
+        assert_eq!(bob.join("dogs").await, Err("'dogs' is full".to_string()));
+
+        // Once alice is gone, there's room for bob.
+        alice.leave("dogs").await;
+        alice.expect(|reply| matches!(reply, FromServer::Left { .. })).await;
+        let mut joined = bob.join("dogs").await;
+        for _ in 0..100 {
+            if joined.is_ok() {
+                break;
+            }
+            task::sleep(Duration::from_millis(20)).await;
+            joined = bob.join("dogs").await;
+        }
+        assert_eq!(joined, Ok(()));
+    });
+}
+
+/// Fetch `/metrics` from `address` and return the response's body.
+async fn scrape(address: &str) -> String {
+    let mut socket = TcpStream::connect(address).await.unwrap();
+    socket
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: test\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    socket.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    body.to_string()
+}
+
+/// The value `metrics` gives for `name`, which must be present.
+fn sample(metrics: &str, name: &str) -> u64 {
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+        .unwrap_or_else(|| panic!("no sample for {} in:\n{}", name, metrics))
+        .parse()
+        .unwrap()
+}
+
+#[test]
+fn traffic_is_counted_on_the_metrics_endpoint() {
+    task::block_on(async {
+        // Find a free port for the endpoint.
+        let metrics_address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let mut config = unlimited();
+        config.metrics_address = Some(metrics_address.clone());
+        let address = start_server(config).await;
+
+        let mut alice = TestClient::connect(address, "alice").await;
+        let mut bob = TestClient::connect(address, "bob").await;
+        alice.join("dogs").await.unwrap();
+        bob.join("dogs").await.unwrap();
+        alice.post("dogs", "woof").await;
+        alice.expect_message().await;
+        bob.expect_message().await;
+
+        let metrics = scrape(&metrics_address).await;
+        assert_eq!(sample(&metrics, "chat_connected_clients"), 2);
+        assert_eq!(sample(&metrics, "chat_messages_posted_total"), 1);
+        assert_eq!(sample(&metrics, "chat_messages_delivered_total"), 2);
+        assert_eq!(sample(&metrics, "chat_messages_dropped_total"), 0);
+        assert_eq!(sample(&metrics, "chat_groups"), 1);
+        assert_eq!(sample(&metrics, "chat_group_members{group=\"dogs\"}"), 2);
+    });
+}
+
+#[test]
+fn posts_sent_again_are_acknowledged_but_not_repeated() {
+    task::block_on(async {
+        let address = start_server(unlimited()).await;
+        let mut bob = TestClient::connect(address, "bob").await;
+        bob.join("dogs").await.unwrap();
+
+        let post = |id| FromClient::Post {
+            group_name: text("dogs"),
+            message: text("woof"),
+            id: Some(id),
+        };
+        let mut alice = TestClient::connect(address, "alice").await;
+        alice.send(post(7)).await;
+        assert_eq!(alice.next().await, FromServer::Ack { id: 7, seq: 0 });
+
+        // As if the acknowledgement were lost with the connection, alice
+        // comes back and posts again.
+        alice.disconnect();
+        let mut alice = TestClient::connect(address, "alice").await;
+        let mut reconnected = alice.join("cats").await;
+        for _ in 0..100 {
+            if reconnected.is_ok() {
+                break;
+            }
+            task::sleep(Duration::from_millis(20)).await;
+            alice = TestClient::connect(address, "alice").await;
+            reconnected = alice.join("cats").await;
+        }
+        reconnected.unwrap();
+        alice.send(post(7)).await;
+        assert_eq!(alice.next().await, FromServer::Ack { id: 7, seq: 0 });
+        alice.send(post(8)).await;
+        assert_eq!(alice.next().await, FromServer::Ack { id: 8, seq: 1 });
+
+        // Bob sees each post once.
+        for expected in 0..2 {
+            match bob.next().await {
+                FromServer::Message { seq, .. } => assert_eq!(seq, expected),
+                other => panic!("expected a message, got {:?}", other),
+            }
+        }
+        bob.expect_quiet().await;
+    });
+}
+
+#[test]
+fn posts_sent_again_after_their_group_empties_are_not_repeated() {
+    task::block_on(async {
+        let address = start_server(unlimited()).await;
+        let mut alice = TestClient::connect(address, "alice").await;
+        let mut bob = TestClient::connect(address, "bob").await;
+        bob.join("dogs").await.unwrap();
+
+        let post = FromClient::Post {
+            group_name: text("dogs"),
+            message: text("woof"),
+            id: Some(7),
+        };
+        alice.send(post.clone()).await;
+        assert_eq!(alice.next().await, FromServer::Ack { id: 7, seq: 0 });
+        let (_, sender, _) = bob.expect_message().await;
+        assert_eq!(sender, "alice");
+
+        // The group empties and is created again before alice, not having
+        // heard her post was acknowledged, sends it again.
+        bob.leave("dogs").await;
+        bob.expect(|packet| matches!(packet, FromServer::Left { .. })).await;
+        while alice.members("dogs").await.is_ok() {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        bob.join("dogs").await.unwrap();
+
+        alice.send(post).await;
+        assert_eq!(alice.next().await, FromServer::Ack { id: 7, seq: 0 });
+        bob.expect_quiet().await;
+    });
+}
@@ -0,0 +1,112 @@
+//! Talk to the server over TLS, using a certificate generated on the spot.
+#![cfg(feature = "tls")]
+
+use async_chat::utils::{self, ChatResult};
+use async_chat::{tls, FromClient, FromServer};
+use async_std::io::BufReader;
+use async_std::net::TcpStream;
+use async_std::prelude::*;
+use async_std::task;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Kills the server when the test ends, however it ends.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn write_self_signed_cert(dir: &Path) -> (PathBuf, PathBuf) {
+    let certified =
+        rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let cert = dir.join("cert.pem");
+    let key = dir.join("key.pem");
+    std::fs::write(&cert, certified.cert.pem()).unwrap();
+    std::fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+    (cert, key)
+}
+
+fn free_port() -> u16 {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+async fn connect(port: u16) -> TcpStream {
+    for _ in 0..100 {
+        if let Ok(socket) = TcpStream::connect(("127.0.0.1", port)).await {
+            return socket;
+        }
+        task::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("server never started listening on port {}", port);
+}
+
+#[test]
+fn hello_join_post_over_tls() -> ChatResult<()> {
+    let dir = std::env::temp_dir()
+        .join(format!("async-chat-tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let (cert, key) = write_self_signed_cert(&dir);
+
+    let port = free_port();
+    let _server = Server(
+        Command::new(env!("CARGO_BIN_EXE_server"))
+            .arg(format!("127.0.0.1:{}", port))
+            .env("CHAT_TLS_CERT", &cert)
+            .env("CHAT_TLS_KEY", &key)
+            .env_remove("CHAT_LOG_DIR")
+            .spawn()?,
+    );
+
+    let result = task::block_on(async {
+        let connector = tls::connector(&cert)?;
+        let socket = connect(port).await;
+        let socket = connector
+            .connect(tls::server_name(&format!("localhost:{}", port))?, socket)
+            .await?;
+        let (reader, mut writer) = futures_lite::io::split(socket);
+        let mut replies = utils::receive_as_json(BufReader::new(reader), 1 << 20);
+
+        let group_name = Arc::new("secrets".to_string());
+        for request in [
+            FromClient::Hello {
+                nickname: Arc::new("alice".to_string()),
+                password: None,
+                format: Default::default(),
+            },
+            FromClient::Join { group_name: group_name.clone(), history: None },
+            FromClient::Post {
+                group_name: group_name.clone(),
+                message: Arc::new("psst".to_string()),
+                id: None,
+            },
+        ] {
+            utils::send_as_json(&mut writer, &request).await?;
+        }
+        writer.flush().await?;
+
+        let reply = replies
+            .next()
+            .timeout(Duration::from_secs(10))
+            .await?
+            .expect("server closed the connection")?;
+        match reply {
+            FromServer::Message { group_name: name, sender, message, .. } => {
+                assert_eq!(name, group_name);
+                assert_eq!(sender.as_str(), "alice");
+                assert_eq!(message.as_str(), "psst");
+            }
+            other => panic!("unexpected reply {:?}", other),
+        }
+        Ok(())
+    });
+
+    std::fs::remove_dir_all(&dir)?;
+    result
+}
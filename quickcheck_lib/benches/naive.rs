@@ -4,72 +4,69 @@ extern crate criterion;
 extern crate naive_hashmap;
 extern crate rand;
 
-use criterion::{Criterion, Fun};
-use rand::{Rng, SeedableRng, XorShiftRng};
+use criterion::{BenchmarkId, Criterion};
+use naive_hashmap::workload::{self, Mix};
+use rand::distributions::{Distribution, Standard};
+use std::hash::Hash;
 // end snippet lib-hashmap-bench-naive-preamble
 
-// start snippet lib-hashmap-bench-naive-ialn
-fn insert_and_lookup_naive(mut n: u64) {
-    let mut rng: XorShiftRng = SeedableRng::from_seed([1981, 1986, 2003, 2011]);
-    let mut hash_map = naive_hashmap::HashMap::new();
+const SIZES: [u64; 6] = [1, 10, 100, 1_000, 10_000, 100_000];
 
-    while n != 0 {
-        let key = rng.gen::<u8>();
-        if rng.gen::<bool>() {
-            let value = rng.gen::<u32>();
-            hash_map.insert(key, value);
-        } else {
-            hash_map.get(&key);
+// start snippet lib-hashmap-bench-naive-insert_lookup
+fn insert_lookup<K>(c: &mut Criterion, key_type: &str)
+where
+    K: Eq + Hash + ::std::fmt::Debug,
+    Standard: Distribution<K>,
+{
+    let mut group = c.benchmark_group(format!("HashMap/{}", key_type));
+    for &(mix_name, mix) in Mix::all().iter() {
+        for &n in SIZES.iter() {
+            let parameter = format!("{}/{}", mix_name, n);
+            group.bench_with_input(
+                BenchmarkId::new("naive", &parameter),
+                &n,
+                |b, &n| {
+                    b.iter(|| {
+                        let mut hash_map = naive_hashmap::HashMap::new();
+                        let mut rng = workload::seeded_rng();
+                        workload::run::<_, K, _>(&mut hash_map, &mut rng, mix, n)
+                    })
+                },
+            );
+            group.bench_with_input(
+                BenchmarkId::new("standard", &parameter),
+                &n,
+                |b, &n| {
+                    b.iter(|| {
+                        let mut hash_map = ::std::collections::HashMap::new();
+                        let mut rng = workload::seeded_rng();
+                        workload::run::<_, K, _>(&mut hash_map, &mut rng, mix, n)
+                    })
+                },
+            );
         }
-        n -= 1;
     }
+    group.finish();
 }
-// end snippet lib-hashmap-bench-naive-ialn
-
-// start snippet lib-hashmap-bench-naive-standard
-fn insert_and_lookup_standard(mut n: u64) {
-    let mut rng: XorShiftRng = SeedableRng::from_seed([1981, 1986, 2003, 2011]);
-    let mut hash_map = ::std::collections::HashMap::new();
 
-    while n != 0 {
-        let key = rng.gen::<u8>();
-        if rng.gen::<bool>() {
-            let value = rng.gen::<u32>();
-            hash_map.insert(key, value);
-        } else {
-            hash_map.get(&key);
-        }
-        n -= 1;
-    }
+fn insert_lookup_u8(c: &mut Criterion) {
+    insert_lookup::<u8>(c, "u8");
 }
-// end snippet lib-hashmap-bench-naive-standard
 
-// start snippet lib-hashmap-bench-naive-insert_lookup
-macro_rules! insert_lookup {
-    ($fn:ident, $s:expr) => {
-        fn $fn(c: &mut Criterion) {
-            let mut group = c.benchmark_group(&format!("HashMap/{}", $s));
-            group.bench_with_input("naive",&$s ,|b, i| b.iter(|| insert_and_lookup_naive(*i)));
-            group.bench_with_input("standard", &$s,|b, i| b.iter(|| insert_and_lookup_standard(*i)));
-            group.finish();
-
-        }
-    }
+fn insert_lookup_u16(c: &mut Criterion) {
+    insert_lookup::<u16>(c, "u16");
 }
 
-insert_lookup!(insert_lookup_100000, 100_000);
-insert_lookup!(insert_lookup_10000, 10_000);
-insert_lookup!(insert_lookup_1000, 1_000);
-insert_lookup!(insert_lookup_100, 100);
-insert_lookup!(insert_lookup_10, 10);
-insert_lookup!(insert_lookup_1, 1);
+fn insert_lookup_u64(c: &mut Criterion) {
+    insert_lookup::<u64>(c, "u64");
+}
 // end snippet lib-hashmap-bench-naive-insert_lookup
 
 // start snippet lib-hashmap-bench-naive-main
 criterion_group!{
     name = benches;
     config = Criterion::default();
-    targets = insert_lookup_100000, insert_lookup_10000, insert_lookup_1000, insert_lookup_100, insert_lookup_10, insert_lookup_1
+    targets = insert_lookup_u8, insert_lookup_u16, insert_lookup_u64
 }
 criterion_main!(benches);
 // end snippet lib-hashmap-bench-naive-main
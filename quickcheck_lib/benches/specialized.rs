@@ -1,65 +1,47 @@
 #[macro_use]
 extern crate criterion;
 extern crate naive_hashmap;
-extern crate rand;
 
-use criterion::{Criterion, Fun};
-use rand::{Rng, SeedableRng, XorShiftRng};
-
-fn insert_and_lookup_specialized(mut n: u64) {
-    let mut rng: XorShiftRng = SeedableRng::from_seed([1981, 1986, 2003, 2011]);
-    let mut hash_map = naive_hashmap::HashMapU8::new();
-
-    while n != 0 {
-        let key = rng.gen::<u8>();
-        if rng.gen::<bool>() {
-            let value = rng.gen::<u32>();
-            hash_map.insert(key, value);
-        } else {
-            hash_map.get(&key);
-        }
-        n -= 1;
-    }
-}
-
-fn insert_and_lookup_standard(mut n: u64) {
-    let mut rng: XorShiftRng = SeedableRng::from_seed([1981, 1986, 2003, 2011]);
-    let mut hash_map = ::std::collections::HashMap::new();
-
-    while n != 0 {
-        let key = rng.gen::<u8>();
-        if rng.gen::<bool>() {
-            let value = rng.gen::<u32>();
-            hash_map.insert(key, value);
-        } else {
-            hash_map.get(&key);
+use criterion::{BenchmarkId, Criterion};
+use naive_hashmap::workload::{self, Mix};
+
+const SIZES: [u64; 5] = [10, 100, 1_000, 10_000, 100_000];
+
+fn insert_lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("HashMap/u8");
+    for &(mix_name, mix) in Mix::all().iter() {
+        for &n in SIZES.iter() {
+            let parameter = format!("{}/{}", mix_name, n);
+            group.bench_with_input(
+                BenchmarkId::new("specialized", &parameter),
+                &n,
+                |b, &n| {
+                    b.iter(|| {
+                        let mut hash_map = naive_hashmap::HashMapU8::new();
+                        let mut rng = workload::seeded_rng();
+                        workload::run::<_, u8, _>(&mut hash_map, &mut rng, mix, n)
+                    })
+                },
+            );
+            group.bench_with_input(
+                BenchmarkId::new("standard", &parameter),
+                &n,
+                |b, &n| {
+                    b.iter(|| {
+                        let mut hash_map = ::std::collections::HashMap::new();
+                        let mut rng = workload::seeded_rng();
+                        workload::run::<_, u8, _>(&mut hash_map, &mut rng, mix, n)
+                    })
+                },
+            );
         }
-        n -= 1;
     }
+    group.finish();
 }
 
-macro_rules! insert_lookup {
-    ($fn:ident, $s:expr) => {
-        fn $fn(c: &mut Criterion) {
-            let mut group = c.benchmark_group(&format!("HashMap/{}", $s));
-            group.bench_with_input("specialized", &$s, |b, i| b.iter(|| insert_and_lookup_specialized(*i)));
-            group.bench_with_input("standard", &$s, |b, i| b.iter(|| insert_and_lookup_standard(*i)));
-
-            group.finish();
-        }
-    }
-}
-
-insert_lookup!(insert_lookup_100000, 100_000);
-insert_lookup!(insert_lookup_10000, 10_000);
-insert_lookup!(insert_lookup_1000, 1_000);
-insert_lookup!(insert_lookup_100, 100);
-insert_lookup!(insert_lookup_10, 10);
-insert_lookup!(insert_lookup_1, 1);
-
 criterion_group!{
     name = benches;
     config = Criterion::default();
-    targets = insert_lookup_100000, insert_lookup_10000, insert_lookup_1000, insert_lookup_100, insert_lookup_10, insert_lookup_1
+    targets = insert_lookup
 }
 criterion_main!(benches);
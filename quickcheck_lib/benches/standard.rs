@@ -1,32 +1,38 @@
 #[macro_use]
 extern crate criterion;
+extern crate naive_hashmap;
 extern crate rand;
 
-use criterion::Criterion;
-use rand::{Rng, SeedableRng, XorShiftRng};
+use criterion::{BenchmarkId, Criterion};
+use naive_hashmap::workload::{self, Mix};
+use rand::distributions::{Distribution, Standard};
+use std::hash::Hash;
 
-fn insert_and_lookup(mut n: u64) {
-    let mut rng: XorShiftRng = SeedableRng::from_seed([1981, 1986, 2003, 2011]);
-    let mut hash_map = ::std::collections::HashMap::new();
+const SIZES: [u64; 6] = [1, 10, 100, 1_000, 10_000, 100_000];
 
-    while n != 0 {
-        let key = rng.gen::<u8>();
-        if rng.gen::<bool>() {
-            let value = rng.gen::<u32>();
-            hash_map.insert(key, value);
-        } else {
-            hash_map.get(&key);
+fn insert_lookup<K>(c: &mut Criterion, key_type: &str)
+where
+    K: Eq + Hash,
+    Standard: Distribution<K>,
+{
+    let mut group = c.benchmark_group(format!("insert_and_lookup/{}", key_type));
+    for &(mix_name, mix) in Mix::all().iter() {
+        for &n in SIZES.iter() {
+            group.bench_with_input(BenchmarkId::new(mix_name, n), &n, |b, &n| {
+                b.iter(|| {
+                    let mut hash_map = ::std::collections::HashMap::new();
+                    let mut rng = workload::seeded_rng();
+                    workload::run::<_, K, _>(&mut hash_map, &mut rng, mix, n)
+                })
+            });
         }
-        n -= 1;
     }
+    group.finish();
 }
 
 fn criterion_benchmark(c: &mut Criterion) {
-    c.bench_function_over_inputs(
-        "insert_and_lookup",
-        |b, &&size| b.iter(|| insert_and_lookup(size)),
-        &[1, 10, 100, 1_000, 10_000, 100_000],
-    );
+    insert_lookup::<u8>(c, "u8");
+    insert_lookup::<u16>(c, "u16");
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -0,0 +1,78 @@
+//! Report the heap cost of the map implementations over the workload the
+//! `naive`, `specialized` and `standard` binaries run.
+//!
+//!     $ cargo run --release --features bench-alloc --bin alloc_report
+
+extern crate naive_hashmap;
+extern crate rand;
+
+use naive_hashmap::alloc_counter::{self, CountingAlloc, Stats};
+use naive_hashmap::workload::{self, Map, Mix};
+use rand::distributions::{Distribution, Standard};
+use std::mem;
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const OPERATIONS: u64 = 100_000;
+
+/// Run the workload against the map `make` builds, counting from just
+/// before the map is created until just before it is dropped. Also returns
+/// the size of the map value itself, which lives outside the heap.
+fn measure<M, K, F>(make: F) -> (Stats, usize)
+where
+    M: Map<K, u32>,
+    F: FnOnce() -> M,
+    Standard: Distribution<K>,
+{
+    let mut rng = workload::seeded_rng();
+    let base = alloc_counter::stats().live;
+    alloc_counter::reset();
+
+    let mut hash_map = make();
+    workload::run::<_, K, _>(&mut hash_map, &mut rng, Mix::BALANCED, OPERATIONS);
+
+    let mut stats = alloc_counter::stats();
+    stats.live -= base;
+    stats.peak -= base;
+    (stats, mem::size_of_val(&hash_map))
+}
+
+fn main() {
+    let rows = [
+        (
+            "naive (u16 keys)",
+            measure::<_, u16, _>(naive_hashmap::HashMap::new),
+        ),
+        (
+            "specialized (u8 keys)",
+            measure::<_, u8, _>(naive_hashmap::HashMapU8::new),
+        ),
+        (
+            "standard (u16 keys)",
+            measure::<_, u16, _>(::std::collections::HashMap::new),
+        ),
+    ];
+
+    println!("{} operations, {:?}", OPERATIONS, Mix::BALANCED);
+    println!(
+        "{:<24}{:>16}{:>14}{:>14}{:>14}{:>14}",
+        "map",
+        "bytes allocated",
+        "allocations",
+        "peak heap",
+        "final heap",
+        "inline bytes"
+    );
+    for &(name, (stats, inline)) in rows.iter() {
+        println!(
+            "{:<24}{:>16}{:>14}{:>14}{:>14}{:>14}",
+            name,
+            stats.allocated,
+            stats.allocations,
+            stats.peak,
+            stats.live,
+            inline
+        );
+    }
+}
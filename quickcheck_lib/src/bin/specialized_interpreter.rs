@@ -1,11 +1,27 @@
 extern crate naive_hashmap;
 
+use naive_hashmap::persist;
+
 use std::io;
 use std::io::prelude::*;
+use std::process;
 use std::str::FromStr;
 
 fn main() {
-    let mut hash_map = naive_hashmap::HashMapU8::new();
+    // An optional snapshot path: the map is restored from it on startup, if
+    // it exists, and saved back to it once stdin is exhausted.
+    let snapshot = std::env::args().nth(1);
+    let mut hash_map: naive_hashmap::HashMapU8<String> = match snapshot {
+        Some(ref path) => match persist::load_from_path(path) {
+            Ok(Some(hash_map)) => hash_map,
+            Ok(None) => naive_hashmap::HashMapU8::new(),
+            Err(e) => {
+                eprintln!("cannot restore {}: {}", path, e);
+                process::exit(1);
+            }
+        },
+        None => naive_hashmap::HashMapU8::new(),
+    };
 
     let n = io::stdin();
     for line in n.lock().lines() {
@@ -42,4 +58,11 @@ fn main() {
             break;
         }
     }
+
+    if let Some(path) = snapshot {
+        if let Err(e) = persist::save_to_path(&hash_map, &path) {
+            eprintln!("cannot save {}: {}", path, e);
+            process::exit(1);
+        }
+    }
 }
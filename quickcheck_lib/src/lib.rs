@@ -1,9 +1,28 @@
 // start snippet lib-preamble
+extern crate rand;
+extern crate rand_xorshift;
+extern crate crc32fast;
+#[cfg(feature = "serde")]
+extern crate serde;
 
-use std::hash::{BuildHasher, Hash, Hasher};
+use std::hash::{BuildHasher, Hash};
 use std::borrow::Borrow;
 use std::collections::hash_map::RandomState;
-use std::{cmp, mem, ptr};
+use std::{cmp, mem};
+
+pub use multimap::MultiMap;
+pub use set::HashSet;
+pub use sharded::ShardedHashMap;
+
+#[cfg(feature = "bench-alloc")]
+pub mod alloc_counter;
+pub mod multimap;
+pub mod persist;
+pub mod set;
+pub mod sharded;
+#[cfg(feature = "serde")]
+mod serde_impls;
+pub mod workload;
 // end snippet lib-preamble
 
 // start snippet lib-hashmapu8
@@ -19,24 +38,44 @@ where
     V: ::std::fmt::Debug,
 {
     pub fn new() -> HashMapU8<V> {
-        let data = unsafe {
-            let mut data: [Option<V>; 256] = mem::uninitialized();
-            for element in data.iter_mut() {
-                ptr::write(element, None)
-            }
-            data
-        };
-        HashMapU8 { data: data }
+        HashMapU8 {
+            data: [(); 256].map(|_| None),
+        }
     }
 
     pub fn insert(&mut self, k: u8, v: V) -> Option<V> {
-        mem::replace(&mut self.data[(k as usize)], Some(v))
+        self.data[k as usize].replace(v)
     }
 
     pub fn get(&mut self, k: &u8) -> Option<&V> {
         let val = unsafe { self.data.get_unchecked(*k as usize) };
         val.as_ref()
     }
+
+    pub fn len(&self) -> usize {
+        self.data.iter().filter(|v| v.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.iter().all(|v| v.is_none())
+    }
+
+    /// Iterate over the occupied slots in key order.
+    pub fn iter(&self) -> impl Iterator<Item = (u8, &V)> {
+        self.data
+            .iter()
+            .enumerate()
+            .filter_map(|(k, v)| v.as_ref().map(|v| (k as u8, v)))
+    }
+}
+
+impl<V> Default for HashMapU8<V>
+where
+    V: ::std::fmt::Debug,
+{
+    fn default() -> HashMapU8<V> {
+        HashMapU8::new()
+    }
 }
 // end snippet lib-hashmapu8
 
@@ -53,7 +92,7 @@ where
 // end snippet lib-hashmap-struct
 
 // start snippet lib-hashmap-to-with_hasher
-impl<K: Eq, V> HashMap<K, V, RandomState>
+impl<K, V> HashMap<K, V, RandomState>
 where
     K: Eq + Hash,
     V: ::std::fmt::Debug,
@@ -66,14 +105,12 @@ where
     }
 }
 
-fn make_hash<T: ?Sized, S>(hash_builder: &S, t: &T) -> u64
+fn make_hash<T, S>(hash_builder: &S, t: &T) -> u64
 where
-    T: Hash,
+    T: Hash + ?Sized,
     S: BuildHasher,
 {
-    let mut state = hash_builder.build_hasher();
-    t.hash(&mut state);
-    state.finish()
+    hash_builder.hash_one(t)
 }
 
 impl<K, V, S> HashMap<K, V, S>
@@ -84,7 +121,7 @@ where
 {
     pub fn with_hasher(hash_builder: S) -> HashMap<K, V, S> {
         HashMap {
-            hash_builder: hash_builder,
+            hash_builder,
             data: Vec::new(),
         }
     }
@@ -114,10 +151,10 @@ where
     // end snippet lib-hashmap-insertion
 
     // start snippet lib-hashmap-get
-    pub fn get<Q: ?Sized>(&mut self, k: &Q) -> Option<&V>
+    pub fn get<Q>(&self, k: &Q) -> Option<&V>
     where
         K: Borrow<Q> + ::std::fmt::Debug,
-        Q: Hash + Eq + ::std::fmt::Debug,
+        Q: Hash + Eq + ::std::fmt::Debug + ?Sized,
     {
         let hash = make_hash(&self.hash_builder, k);
 
@@ -129,19 +166,89 @@ where
         None
     }
     // end snippet lib-hashmap-get
+
+    pub fn get_mut<Q>(&mut self, k: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q> + ::std::fmt::Debug,
+        Q: Hash + Eq + ::std::fmt::Debug + ?Sized,
+    {
+        let hash = make_hash(&self.hash_builder, k);
+
+        for &mut (bucket_hash, _, ref mut v) in self.data.iter_mut() {
+            if hash == bucket_hash {
+                return Some(v);
+            }
+        }
+        None
+    }
+
+    pub fn contains_key<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q> + ::std::fmt::Debug,
+        Q: Hash + Eq + ::std::fmt::Debug + ?Sized,
+    {
+        self.get(k).is_some()
+    }
+
+    pub fn remove<Q>(&mut self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q> + ::std::fmt::Debug,
+        Q: Hash + Eq + ::std::fmt::Debug + ?Sized,
+    {
+        let hash = make_hash(&self.hash_builder, k);
+
+        let idx = self.data.iter().position(|&(bucket_hash, _, _)| bucket_hash == hash)?;
+        Some(self.data.remove(idx).2)
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Iterate over the entries in hash order.
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            inner: self.data.iter(),
+        }
+    }
+}
+
+pub struct Iter<'a, K: 'a, V: 'a> {
+    inner: ::std::slice::Iter<'a, (u64, K, V)>,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        self.inner.next().map(|(_, k, v)| (k, v))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, K, V> Clone for Iter<'a, K, V> {
+    fn clone(&self) -> Iter<'a, K, V> {
+        Iter {
+            inner: self.inner.clone(),
+        }
+    }
 }
 
 // start snippet lib-hashmap-test-preamble
 #[cfg(test)]
 extern crate quickcheck;
-#[cfg(test)]
-extern crate rand;
 
 #[cfg(test)]
 mod test {
     use super::*;
     use quickcheck::{Arbitrary, Gen, QuickCheck, TestResult};
-    use rand::{thread_rng, Rng};
     // end snippet lib-hashmap-test-preamble
 
     // start snippet lib-hashmap-test-gwyg
@@ -175,13 +282,26 @@ mod test {
     where
         T: Arbitrary,
     {
-        fn arbitrary(g: &mut Gen) -> Action<T>
-        {
-            
-            let i: usize = thread_rng().gen_range(0, 100);
-            match i {
-                0...50 => Action::Insert(Arbitrary::arbitrary(g), u16::arbitrary(g)),
-                _ => Action::Lookup(Arbitrary::arbitrary(g)),
+        fn arbitrary(g: &mut Gen) -> Action<T> {
+            match u32::arbitrary(g) % 100 {
+                0..=50 => Action::Insert(T::arbitrary(g), u16::arbitrary(g)),
+                _ => Action::Lookup(T::arbitrary(g)),
+            }
+        }
+
+        fn shrink(&self) -> Box<dyn Iterator<Item = Action<T>>> {
+            match *self {
+                Action::Insert(ref k, v) => {
+                    let k_clone = k.clone();
+                    Box::new(
+                        k.shrink()
+                            .map(move |k| Action::Insert(k, v))
+                            .chain(v.shrink().map(move |v| {
+                                Action::Insert(k_clone.clone(), v)
+                            })),
+                    )
+                }
+                Action::Lookup(ref k) => Box::new(k.shrink().map(Action::Lookup)),
             }
         }
     }
@@ -238,4 +358,153 @@ mod test {
         QuickCheck::new().quickcheck(property as fn(Vec<Action<String>>) -> TestResult);
     }
     // end snippet lib-hashmap-test-action-sut
+
+    #[test]
+    fn sharded_operations() {
+        let map = ShardedHashMap::with_shards(4);
+        assert_eq!(None, map.insert(1u8, 10u16));
+        assert!(map.contains_key(&1));
+        assert_eq!(Some(11), map.with_mut(&1, |v| {
+            *v += 1;
+            *v
+        }));
+        assert_eq!(Some(11), map.get(&1));
+        assert_eq!(None, map.with_mut(&2, |v| *v));
+        assert_eq!(Some(11), map.remove(&1));
+        assert_eq!(None, map.remove(&1));
+        assert!(!map.contains_key(&1));
+        assert!(map.is_empty());
+    }
+
+    #[derive(Clone, Debug)]
+    enum ShardedAction {
+        Insert(u8, u16),
+        Remove(u8),
+        Lookup(u8),
+    }
+
+    impl Arbitrary for ShardedAction {
+        fn arbitrary(g: &mut Gen) -> ShardedAction {
+            // Fold keys into a small range so that the threads collide.
+            let k = u8::arbitrary(g) % 16;
+            match u32::arbitrary(g) % 100 {
+                0..=49 => ShardedAction::Insert(k, u16::arbitrary(g)),
+                50..=69 => ShardedAction::Remove(k),
+                _ => ShardedAction::Lookup(k),
+            }
+        }
+    }
+
+    #[test]
+    fn sharded_vs_genuine_article_concurrent() {
+        // Each thread replays its own log against the shared map, and the
+        // logs share keys, so the threads race to write and remove the same
+        // entries. Whatever a key ends up holding depends on the
+        // interleaving, but it must be what some thread's last write to
+        // that key left, and nothing read may be a value nobody wrote.
+        fn property(logs: Vec<Vec<ShardedAction>>) -> TestResult {
+            use std::collections::{HashMap as StdHashMap, HashSet};
+
+            let system_under_test = ShardedHashMap::with_shards(4);
+            // Every value written to each key, and what each thread's last
+            // write to it left: a value, or `None` for a removal.
+            let mut written: StdHashMap<u8, HashSet<u16>> = StdHashMap::new();
+            let mut finals: StdHashMap<u8, HashSet<Option<u16>>> =
+                StdHashMap::new();
+            for log in &logs {
+                let mut last = StdHashMap::new();
+                for action in log {
+                    match *action {
+                        ShardedAction::Insert(k, v) => {
+                            written.entry(k).or_default().insert(v);
+                            last.insert(k, Some(v));
+                        }
+                        ShardedAction::Remove(k) => {
+                            last.insert(k, None);
+                        }
+                        ShardedAction::Lookup(_) => {}
+                    }
+                }
+                for (k, v) in last {
+                    finals.entry(k).or_default().insert(v);
+                }
+            }
+            let was_written = |k: &u8, v: u16| {
+                written.get(k).is_some_and(|values| values.contains(&v))
+            };
+
+            ::std::thread::scope(|scope| {
+                for log in &logs {
+                    let sut = &system_under_test;
+                    let was_written = &was_written;
+                    scope.spawn(move || {
+                        for action in log {
+                            let (k, found) = match *action {
+                                ShardedAction::Insert(k, v) => {
+                                    (k, sut.insert(k, v))
+                                }
+                                ShardedAction::Remove(k) => (k, sut.remove(&k)),
+                                ShardedAction::Lookup(k) => (k, sut.get(&k)),
+                            };
+                            if let Some(v) = found {
+                                assert!(was_written(&k, v));
+                            }
+                        }
+                    });
+                }
+            });
+
+            let left = |k: &u8, found: Option<u16>| match finals.get(k) {
+                Some(outcomes) => outcomes.contains(&found),
+                None => found.is_none(),
+            };
+            let mut expected_len = 0;
+            for k in 0..16 {
+                let found = system_under_test.get(&k);
+                assert!(left(&k, found));
+                assert_eq!(found.is_some(), system_under_test.contains_key(&k));
+                expected_len += found.is_some() as usize;
+            }
+            assert_eq!(expected_len, system_under_test.len());
+            let merged = system_under_test.into_inner();
+            assert_eq!(expected_len, merged.len());
+            for k in 0..16 {
+                assert!(left(&k, merged.get(&k).cloned()));
+            }
+            TestResult::passed()
+        }
+        QuickCheck::new()
+            .quickcheck(property as fn(Vec<Vec<ShardedAction>>) -> TestResult);
+    }
+
+    #[test]
+    fn action_shrinks_towards_smaller_keys() {
+        let shrunk: Vec<Action<u8>> = Action::Insert(200, 7).shrink().collect();
+        assert!(!shrunk.is_empty());
+        for action in shrunk {
+            match action {
+                Action::Insert(k, v) => assert!((k < 200 && v == 7) || (k == 200 && v < 7)),
+                Action::Lookup(_) => panic!("an insert must shrink to an insert"),
+            }
+        }
+    }
+
+    #[test]
+    fn naive_and_std_agree_on_seeded_workload() {
+        let mut naive = HashMap::new();
+        let mut std = ::std::collections::HashMap::new();
+        let naive_tally = workload::run::<_, u16, _>(
+            &mut naive,
+            &mut workload::seeded_rng(),
+            workload::Mix::BALANCED,
+            10_000,
+        );
+        let std_tally = workload::run::<_, u16, _>(
+            &mut std,
+            &mut workload::seeded_rng(),
+            workload::Mix::BALANCED,
+            10_000,
+        );
+        assert_eq!(naive_tally, std_tally);
+    }
 }
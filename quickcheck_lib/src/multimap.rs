@@ -0,0 +1,176 @@
+//! A map that keeps every value inserted under a key, built on
+//! `HashMap<K, Vec<V>>`.
+
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+
+use HashMap;
+
+pub struct MultiMap<K, V, S = RandomState>
+where
+    K: Eq,
+    V: ::std::fmt::Debug,
+{
+    map: HashMap<K, Vec<V>, S>,
+}
+
+impl<K, V> MultiMap<K, V, RandomState>
+where
+    K: Eq + Hash,
+    V: ::std::fmt::Debug,
+{
+    pub fn new() -> MultiMap<K, V> {
+        MultiMap { map: HashMap::new() }
+    }
+}
+
+impl<K, V> Default for MultiMap<K, V, RandomState>
+where
+    K: Eq + Hash,
+    V: ::std::fmt::Debug,
+{
+    fn default() -> MultiMap<K, V> {
+        MultiMap::new()
+    }
+}
+
+impl<K, V, S> MultiMap<K, V, S>
+where
+    K: Eq + Hash + ::std::fmt::Debug,
+    V: ::std::fmt::Debug,
+    S: BuildHasher,
+{
+    pub fn with_hasher(hash_builder: S) -> MultiMap<K, V, S> {
+        MultiMap {
+            map: HashMap::with_hasher(hash_builder),
+        }
+    }
+
+    /// Add `v` after any values already stored under `k`.
+    pub fn insert(&mut self, k: K, v: V) {
+        if let Some(values) = self.map.get_mut(&k) {
+            values.push(v);
+            return;
+        }
+        self.map.insert(k, vec![v]);
+    }
+
+    /// The values stored under `k`, oldest first; empty if there are none.
+    pub fn get<Q>(&self, k: &Q) -> &[V]
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ::std::fmt::Debug + ?Sized,
+    {
+        self.map.get(k).map_or(&[], |values| &values[..])
+    }
+
+    pub fn contains_key<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ::std::fmt::Debug + ?Sized,
+    {
+        self.map.contains_key(k)
+    }
+
+    /// Remove and return every value stored under `k`.
+    pub fn remove<Q>(&mut self, k: &Q) -> Option<Vec<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ::std::fmt::Debug + ?Sized,
+    {
+        self.map.remove(k)
+    }
+
+    /// The number of distinct keys.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Iterate over every key/value pair, repeating a key once per value.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.map
+            .iter()
+            .flat_map(|(k, values)| values.iter().map(move |v| (k, v)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use quickcheck::{Arbitrary, Gen, QuickCheck, TestResult};
+    use std::collections;
+
+    #[derive(Clone, Debug)]
+    enum Action<T>
+    where
+        T: Arbitrary,
+    {
+        Insert(T, u16),
+        Remove(T),
+        Lookup(T),
+    }
+
+    impl<T> Arbitrary for Action<T>
+    where
+        T: Arbitrary,
+    {
+        fn arbitrary(g: &mut Gen) -> Action<T> {
+            match u32::arbitrary(g) % 100 {
+                0..=59 => Action::Insert(T::arbitrary(g), u16::arbitrary(g)),
+                60..=69 => Action::Remove(T::arbitrary(g)),
+                _ => Action::Lookup(T::arbitrary(g)),
+            }
+        }
+
+        fn shrink(&self) -> Box<dyn Iterator<Item = Action<T>>> {
+            match *self {
+                Action::Insert(ref k, v) => {
+                    Box::new(k.shrink().map(move |k| Action::Insert(k, v)))
+                }
+                Action::Remove(ref k) => Box::new(k.shrink().map(Action::Remove)),
+                Action::Lookup(ref k) => Box::new(k.shrink().map(Action::Lookup)),
+            }
+        }
+    }
+
+    #[test]
+    fn sut_vs_genuine_article() {
+        fn property<T>(actions: Vec<Action<T>>) -> TestResult
+        where
+            T: Arbitrary + Eq + Hash + ::std::fmt::Debug,
+        {
+            let mut model: collections::HashMap<T, Vec<u16>> =
+                collections::HashMap::new();
+            let mut system_under_test = MultiMap::new();
+
+            for action in actions.into_iter() {
+                match action {
+                    Action::Insert(k, v) => {
+                        model.entry(k.clone()).or_default().push(v);
+                        system_under_test.insert(k, v);
+                    }
+                    Action::Remove(k) => {
+                        assert_eq!(model.remove(&k), system_under_test.remove(&k));
+                    }
+                    Action::Lookup(k) => {
+                        let expected = model.get(&k).map_or(&[][..], |v| &v[..]);
+                        assert_eq!(expected, system_under_test.get(&k));
+                    }
+                }
+                assert_eq!(model.len(), system_under_test.len());
+            }
+            assert_eq!(
+                model.values().map(Vec::len).sum::<usize>(),
+                system_under_test.iter().count()
+            );
+            TestResult::passed()
+        }
+        QuickCheck::new().quickcheck(property as fn(Vec<Action<u8>>) -> TestResult);
+        QuickCheck::new().quickcheck(property as fn(Vec<Action<String>>) -> TestResult);
+    }
+}
@@ -0,0 +1,478 @@
+//! A compact, self-describing binary snapshot format for the maps in this
+//! crate.
+//!
+//! A snapshot is laid out as:
+//!
+//! ```text
+//! magic    b"NHM"
+//! version  u8
+//! kind     u8          0 = HashMap, 1 = HashMapU8
+//! count    u32 LE
+//! entries  count * (key, value), each a tagged value
+//! checksum u32 LE      CRC-32 of every preceding byte
+//! ```
+//!
+//! A tagged value is a one-byte type tag followed by its payload, so a
+//! snapshot written with one key or value type is rejected, rather than
+//! misread, when loaded as another.
+
+use std::error;
+use std::fmt;
+use std::fs::{self, File};
+use std::hash::{BuildHasher, Hash};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use crc32fast::Hasher as Crc32;
+
+use {HashMap, HashMapU8};
+
+pub const MAGIC: [u8; 3] = *b"NHM";
+pub const VERSION: u8 = 1;
+
+const KIND_HASHMAP: u8 = 0;
+const KIND_HASHMAP_U8: u8 = 1;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u8),
+    WrongKind { expected: u8, found: u8 },
+    TypeMismatch { expected: u8, found: u8 },
+    InvalidUtf8,
+    Checksum { expected: u32, found: u32 },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "i/o error: {}", e),
+            Error::BadMagic => write!(f, "not a naive_hashmap snapshot"),
+            Error::UnsupportedVersion(v) => {
+                write!(f, "unsupported snapshot version {}", v)
+            }
+            Error::WrongKind { expected, found } => write!(
+                f,
+                "snapshot holds map kind {}, expected {}",
+                found, expected
+            ),
+            Error::TypeMismatch { expected, found } => write!(
+                f,
+                "snapshot holds type tag {}, expected {}",
+                found, expected
+            ),
+            Error::InvalidUtf8 => write!(f, "snapshot string is not utf-8"),
+            Error::Checksum { expected, found } => write!(
+                f,
+                "snapshot checksum mismatch: stored {:08x}, computed {:08x}",
+                expected, found
+            ),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+/// A type that can be stored in a snapshot.
+pub trait Persist: Sized {
+    /// The type tag written before every value of this type.
+    const TAG: u8;
+
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()>;
+    fn decode<R: Read>(r: &mut R) -> Result<Self, Error>;
+}
+
+macro_rules! persist_int {
+    ($ty:ty, $tag:expr) => {
+        impl Persist for $ty {
+            const TAG: u8 = $tag;
+
+            fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+                w.write_all(&self.to_le_bytes())
+            }
+
+            fn decode<R: Read>(r: &mut R) -> Result<$ty, Error> {
+                let mut buf = [0; ::std::mem::size_of::<$ty>()];
+                r.read_exact(&mut buf)?;
+                Ok(<$ty>::from_le_bytes(buf))
+            }
+        }
+    };
+}
+
+persist_int!(u8, 1);
+persist_int!(u16, 2);
+persist_int!(u32, 3);
+persist_int!(u64, 4);
+
+impl Persist for String {
+    const TAG: u8 = 5;
+
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        (self.len() as u32).encode(w)?;
+        w.write_all(self.as_bytes())
+    }
+
+    fn decode<R: Read>(r: &mut R) -> Result<String, Error> {
+        let len = u32::decode(r)? as usize;
+        // The length isn't checksummed yet, so let the bytes actually read
+        // decide how much to allocate, not `len`.
+        let mut buf = Vec::new();
+        r.take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() != len {
+            return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+        String::from_utf8(buf).map_err(|_| Error::InvalidUtf8)
+    }
+}
+
+fn write_tagged<T: Persist, W: Write>(value: &T, w: &mut W) -> io::Result<()> {
+    w.write_all(&[T::TAG])?;
+    value.encode(w)
+}
+
+fn read_tagged<T: Persist, R: Read>(r: &mut R) -> Result<T, Error> {
+    let found = u8::decode(r)?;
+    if found != T::TAG {
+        return Err(Error::TypeMismatch {
+            expected: T::TAG,
+            found,
+        });
+    }
+    T::decode(r)
+}
+
+/// Feeds everything written through it into a CRC-32.
+struct Checksummed<W> {
+    inner: W,
+    crc: Crc32,
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.crc.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Checksummed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.crc.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+fn dump_entries<'a, K, V, I, W>(kind: u8, len: usize, entries: I, w: W) -> io::Result<()>
+where
+    K: Persist + 'a,
+    V: Persist + 'a,
+    I: Iterator<Item = (&'a K, &'a V)>,
+    W: Write,
+{
+    let mut w = Checksummed {
+        inner: w,
+        crc: Crc32::new(),
+    };
+    w.write_all(&MAGIC)?;
+    w.write_all(&[VERSION, kind])?;
+    (len as u32).encode(&mut w)?;
+    for (k, v) in entries {
+        write_tagged(k, &mut w)?;
+        write_tagged(v, &mut w)?;
+    }
+    let checksum = w.crc.clone().finalize();
+    checksum.encode(&mut w.inner)?;
+    w.flush()
+}
+
+fn load_entries<K, V, R, F>(kind: u8, r: R, mut insert: F) -> Result<(), Error>
+where
+    K: Persist,
+    V: Persist,
+    R: Read,
+    F: FnMut(K, V),
+{
+    let mut r = Checksummed {
+        inner: r,
+        crc: Crc32::new(),
+    };
+    let mut magic = [0; 3];
+    r.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(Error::BadMagic);
+    }
+    let version = u8::decode(&mut r)?;
+    if version != VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    let found = u8::decode(&mut r)?;
+    if found != kind {
+        return Err(Error::WrongKind {
+            expected: kind,
+            found,
+        });
+    }
+    let count = u32::decode(&mut r)?;
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let k = read_tagged(&mut r)?;
+        let v = read_tagged(&mut r)?;
+        entries.push((k, v));
+    }
+    let found = r.crc.clone().finalize();
+    let expected = u32::decode(&mut r.inner)?;
+    if expected != found {
+        return Err(Error::Checksum { expected, found });
+    }
+    for (k, v) in entries {
+        insert(k, v);
+    }
+    Ok(())
+}
+
+impl<K, V, S> HashMap<K, V, S>
+where
+    K: Eq + Hash + Persist,
+    V: ::std::fmt::Debug + Persist,
+    S: BuildHasher,
+{
+    /// Write a snapshot of this map to `w`.
+    pub fn dump<W: Write>(&self, w: W) -> io::Result<()> {
+        dump_entries(KIND_HASHMAP, self.len(), self.iter(), w)
+    }
+
+    /// Read a snapshot written by `dump`, inserting its entries into a new
+    /// map that uses `hash_builder`.
+    pub fn load_with_hasher<R: Read>(
+        r: R,
+        hash_builder: S,
+    ) -> Result<HashMap<K, V, S>, Error> {
+        let mut map = HashMap::with_hasher(hash_builder);
+        load_entries(KIND_HASHMAP, r, |k, v| {
+            map.insert(k, v);
+        })?;
+        Ok(map)
+    }
+}
+
+impl<K, V> HashMap<K, V>
+where
+    K: Eq + Hash + Persist,
+    V: ::std::fmt::Debug + Persist,
+{
+    /// Read a snapshot written by `dump`.
+    pub fn load<R: Read>(r: R) -> Result<HashMap<K, V>, Error> {
+        HashMap::load_with_hasher(r, Default::default())
+    }
+}
+
+impl<V> HashMapU8<V>
+where
+    V: ::std::fmt::Debug + Persist,
+{
+    /// Write a snapshot of this map to `w`.
+    pub fn dump<W: Write>(&self, w: W) -> io::Result<()> {
+        let keys: Vec<u8> = self.iter().map(|(k, _)| k).collect();
+        let entries = keys.iter().zip(self.iter().map(|(_, v)| v));
+        dump_entries(KIND_HASHMAP_U8, keys.len(), entries, w)
+    }
+
+    /// Read a snapshot written by `dump`.
+    pub fn load<R: Read>(r: R) -> Result<HashMapU8<V>, Error> {
+        let mut map = HashMapU8::new();
+        load_entries(KIND_HASHMAP_U8, r, |k, v| {
+            map.insert(k, v);
+        })?;
+        Ok(map)
+    }
+}
+
+/// A map that can be written to and restored from a snapshot file.
+pub trait Snapshot: Sized {
+    fn dump<W: Write>(&self, w: W) -> io::Result<()>;
+    fn load<R: Read>(r: R) -> Result<Self, Error>;
+}
+
+impl<K, V, S> Snapshot for HashMap<K, V, S>
+where
+    K: Eq + Hash + Persist,
+    V: ::std::fmt::Debug + Persist,
+    S: BuildHasher + Default,
+{
+    fn dump<W: Write>(&self, w: W) -> io::Result<()> {
+        HashMap::dump(self, w)
+    }
+
+    fn load<R: Read>(r: R) -> Result<Self, Error> {
+        HashMap::load_with_hasher(r, S::default())
+    }
+}
+
+impl<V> Snapshot for HashMapU8<V>
+where
+    V: ::std::fmt::Debug + Persist,
+{
+    fn dump<W: Write>(&self, w: W) -> io::Result<()> {
+        HashMapU8::dump(self, w)
+    }
+
+    fn load<R: Read>(r: R) -> Result<Self, Error> {
+        HashMapU8::load(r)
+    }
+}
+
+/// Save a snapshot of `map` to `path`. The snapshot is written and synced
+/// beside `path` first, so a failed save never clobbers the previous one.
+pub fn save_to_path<M: Snapshot, P: AsRef<Path>>(map: &M, path: P) -> io::Result<()> {
+    let path = path.as_ref();
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    {
+        let mut out = io::BufWriter::new(File::create(&tmp)?);
+        map.dump(&mut out)?;
+        out.flush()?;
+        out.get_ref().sync_all()?;
+    }
+    fs::rename(tmp, path)
+}
+
+/// Restore a map from the snapshot at `path`, or return `None` if there is
+/// no file there yet.
+pub fn load_from_path<M: Snapshot, P: AsRef<Path>>(path: P) -> Result<Option<M>, Error> {
+    match File::open(path) {
+        Ok(file) => M::load(io::BufReader::new(file)).map(Some),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::Io(e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use quickcheck::{QuickCheck, TestResult};
+
+    #[test]
+    fn dump_load_round_trip() {
+        fn property(entries: Vec<(u16, String)>) -> TestResult {
+            let mut map = HashMap::new();
+            for (k, v) in entries {
+                map.insert(k, v);
+            }
+            let mut bytes = Vec::new();
+            map.dump(&mut bytes).unwrap();
+
+            let loaded: HashMap<u16, String> =
+                HashMap::load(&bytes[..]).unwrap();
+            assert_eq!(map.len(), loaded.len());
+            for (k, v) in map.iter() {
+                assert_eq!(Some(v), loaded.get(k));
+            }
+            TestResult::passed()
+        }
+        QuickCheck::new()
+            .quickcheck(property as fn(Vec<(u16, String)>) -> TestResult);
+    }
+
+    #[test]
+    fn dump_load_round_trip_u8() {
+        fn property(entries: Vec<(u8, u32)>) -> TestResult {
+            let mut map = HashMapU8::new();
+            for (k, v) in entries {
+                map.insert(k, v);
+            }
+            let mut bytes = Vec::new();
+            map.dump(&mut bytes).unwrap();
+
+            let loaded: HashMapU8<u32> = HashMapU8::load(&bytes[..]).unwrap();
+            assert!(map.iter().eq(loaded.iter()));
+            TestResult::passed()
+        }
+        QuickCheck::new().quickcheck(property as fn(Vec<(u8, u32)>) -> TestResult);
+    }
+
+    #[test]
+    fn load_rejects_corruption() {
+        let mut map = HashMap::new();
+        map.insert("waffle".to_string(), "house".to_string());
+        let mut bytes = Vec::new();
+        map.dump(&mut bytes).unwrap();
+
+        let mut flipped = bytes.clone();
+        let last_payload = flipped.len() - 5;
+        flipped[last_payload] ^= 1;
+        match HashMap::<String, String>::load(&flipped[..]) {
+            Err(Error::Checksum { .. }) => {}
+            other => panic!("expected checksum error, got {:?}", other.err()),
+        }
+
+        match HashMap::<u16, String>::load(&bytes[..]) {
+            Err(Error::TypeMismatch { expected: 2, found: 5 }) => {}
+            other => panic!("expected type mismatch, got {:?}", other.err()),
+        }
+
+        match HashMapU8::<String>::load(&bytes[..]) {
+            Err(Error::WrongKind { .. }) => {}
+            other => panic!("expected wrong kind, got {:?}", other.err()),
+        }
+
+        match HashMap::<String, String>::load(&b"NHX"[..]) {
+            Err(Error::BadMagic) => {}
+            other => panic!("expected bad magic, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn save_load_path_round_trip() {
+        let path = ::std::env::temp_dir()
+            .join(format!("naive-hashmap-snapshot-{}", ::std::process::id()));
+        let _ = fs::remove_file(&path);
+        let missing: Option<HashMapU8<String>> = load_from_path(&path).unwrap();
+        assert!(missing.is_none());
+
+        let mut map = HashMapU8::new();
+        map.insert(7, "waffle".to_string());
+        save_to_path(&map, &path).unwrap();
+        map.insert(8, "house".to_string());
+        save_to_path(&map, &path).unwrap();
+
+        let loaded: HashMapU8<String> = load_from_path(&path).unwrap().unwrap();
+        assert!(map.iter().eq(loaded.iter()));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn load_rejects_truncation() {
+        let mut map = HashMap::new();
+        map.insert("waffle".to_string(), "house".to_string());
+        let mut bytes = Vec::new();
+        map.dump(&mut bytes).unwrap();
+
+        for len in 0..bytes.len() {
+            assert!(HashMap::<String, String>::load(&bytes[..len]).is_err());
+        }
+
+        // Header (magic, version, kind, count), then the first key's tag.
+        // Claim the key is 4 GiB long.
+        let key_len = 3 + 1 + 1 + 4 + 1;
+        let mut oversized = bytes.clone();
+        oversized[key_len..key_len + 4].copy_from_slice(&[0xff; 4]);
+        match HashMap::<String, String>::load(&oversized[..]) {
+            Err(Error::Io(ref error))
+                if error.kind() == io::ErrorKind::UnexpectedEof => {}
+            other => panic!("expected unexpected EOF, got {:?}", other.err()),
+        }
+    }
+}
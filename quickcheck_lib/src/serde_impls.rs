@@ -0,0 +1,134 @@
+//! `Serialize` and `Deserialize` for the maps, enabled by the `serde`
+//! feature. Both maps serialize as ordinary serde maps, so any format that
+//! can hold a `std::collections::HashMap` can hold them too.
+
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::marker::PhantomData;
+
+use serde::de::{Deserialize, Deserializer, MapAccess, Visitor};
+use serde::ser::{Serialize, SerializeMap, Serializer};
+
+use {HashMap, HashMapU8};
+
+impl<K, V, S> Serialize for HashMap<K, V, S>
+where
+    K: Eq + Hash + Serialize,
+    V: ::std::fmt::Debug + Serialize,
+    S: BuildHasher,
+{
+    fn serialize<Z: Serializer>(&self, serializer: Z) -> Result<Z::Ok, Z::Error> {
+        let mut map = serializer.serialize_map(Some(self.len()))?;
+        for (k, v) in self.iter() {
+            map.serialize_entry(k, v)?;
+        }
+        map.end()
+    }
+}
+
+impl<V> Serialize for HashMapU8<V>
+where
+    V: ::std::fmt::Debug + Serialize,
+{
+    fn serialize<Z: Serializer>(&self, serializer: Z) -> Result<Z::Ok, Z::Error> {
+        let mut map = serializer.serialize_map(Some(self.len()))?;
+        for (k, v) in self.iter() {
+            map.serialize_entry(&k, v)?;
+        }
+        map.end()
+    }
+}
+
+struct HashMapVisitor<K, V, S>(PhantomData<HashMap<K, V, S>>)
+where
+    K: Eq,
+    V: ::std::fmt::Debug;
+
+impl<'de, K, V, S> Visitor<'de> for HashMapVisitor<K, V, S>
+where
+    K: Eq + Hash + Deserialize<'de>,
+    V: ::std::fmt::Debug + Deserialize<'de>,
+    S: BuildHasher + Default,
+{
+    type Value = HashMap<K, V, S>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a map")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Self::Value, A::Error> {
+        let mut map = HashMap::with_hasher(S::default());
+        while let Some((k, v)) = access.next_entry()? {
+            map.insert(k, v);
+        }
+        Ok(map)
+    }
+}
+
+impl<'de, K, V, S> Deserialize<'de> for HashMap<K, V, S>
+where
+    K: Eq + Hash + Deserialize<'de>,
+    V: ::std::fmt::Debug + Deserialize<'de>,
+    S: BuildHasher + Default,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(HashMapVisitor(PhantomData))
+    }
+}
+
+struct HashMapU8Visitor<V>(PhantomData<V>);
+
+impl<'de, V> Visitor<'de> for HashMapU8Visitor<V>
+where
+    V: ::std::fmt::Debug + Deserialize<'de>,
+{
+    type Value = HashMapU8<V>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a map with u8 keys")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Self::Value, A::Error> {
+        let mut map = HashMapU8::new();
+        while let Some((k, v)) = access.next_entry::<u8, V>()? {
+            map.insert(k, v);
+        }
+        Ok(map)
+    }
+}
+
+impl<'de, V> Deserialize<'de> for HashMapU8<V>
+where
+    V: ::std::fmt::Debug + Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(HashMapU8Visitor(PhantomData))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate serde_json;
+
+    use {HashMap, HashMapU8};
+
+    #[test]
+    fn json_round_trip() {
+        let mut map = HashMap::new();
+        map.insert("waffle".to_string(), 10u32);
+        map.insert("house".to_string(), 20u32);
+
+        let json = serde_json::to_string(&map).unwrap();
+        let back: HashMap<String, u32> = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.len(), 2);
+        assert_eq!(back.get("waffle"), Some(&10));
+        assert_eq!(back.get("house"), Some(&20));
+
+        let mut map = HashMapU8::new();
+        map.insert(3, "three".to_string());
+        let json = serde_json::to_string(&map).unwrap();
+        assert_eq!(json, r#"{"3":"three"}"#);
+        let back: HashMapU8<String> = serde_json::from_str(&json).unwrap();
+        assert!(map.iter().eq(back.iter()));
+    }
+}
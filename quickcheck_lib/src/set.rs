@@ -0,0 +1,288 @@
+//! A set built on `HashMap<T, ()>`.
+
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::iter::Chain;
+
+use HashMap;
+
+pub struct HashSet<T, S = RandomState>
+where
+    T: Eq,
+{
+    map: HashMap<T, (), S>,
+}
+
+impl<T> HashSet<T, RandomState>
+where
+    T: Eq + Hash,
+{
+    pub fn new() -> HashSet<T> {
+        HashSet { map: HashMap::new() }
+    }
+}
+
+impl<T> Default for HashSet<T, RandomState>
+where
+    T: Eq + Hash,
+{
+    fn default() -> HashSet<T> {
+        HashSet::new()
+    }
+}
+
+impl<T, S> HashSet<T, S>
+where
+    T: Eq + Hash + ::std::fmt::Debug,
+    S: BuildHasher,
+{
+    pub fn with_hasher(hash_builder: S) -> HashSet<T, S> {
+        HashSet {
+            map: HashMap::with_hasher(hash_builder),
+        }
+    }
+
+    /// Returns `true` if `value` was not already present.
+    pub fn insert(&mut self, value: T) -> bool {
+        self.map.insert(value, ()).is_none()
+    }
+
+    pub fn contains<Q>(&self, value: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ::std::fmt::Debug + ?Sized,
+    {
+        self.map.contains_key(value)
+    }
+
+    /// Returns `true` if `value` was present.
+    pub fn remove<Q>(&mut self, value: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ::std::fmt::Debug + ?Sized,
+    {
+        self.map.remove(value).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            inner: self.map.iter(),
+        }
+    }
+
+    /// Values in `self` or `other`, each yielded once.
+    pub fn union<'a>(&'a self, other: &'a HashSet<T, S>) -> Union<'a, T, S> {
+        Union {
+            inner: self.iter().chain(other.difference(self)),
+        }
+    }
+
+    /// Values in both `self` and `other`.
+    pub fn intersection<'a>(
+        &'a self,
+        other: &'a HashSet<T, S>,
+    ) -> Intersection<'a, T, S> {
+        Intersection {
+            iter: self.iter(),
+            other,
+        }
+    }
+
+    /// Values in `self` but not in `other`.
+    pub fn difference<'a>(
+        &'a self,
+        other: &'a HashSet<T, S>,
+    ) -> Difference<'a, T, S> {
+        Difference {
+            iter: self.iter(),
+            other,
+        }
+    }
+}
+
+pub struct Iter<'a, T: 'a> {
+    inner: ::Iter<'a, T, ()>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        self.inner.next().map(|(k, _)| k)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+pub struct Union<'a, T: 'a + Eq, S: 'a> {
+    inner: Chain<Iter<'a, T>, Difference<'a, T, S>>,
+}
+
+impl<'a, T, S> Iterator for Union<'a, T, S>
+where
+    T: Eq + Hash + ::std::fmt::Debug,
+    S: BuildHasher,
+{
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        self.inner.next()
+    }
+}
+
+pub struct Intersection<'a, T: 'a + Eq, S: 'a> {
+    iter: Iter<'a, T>,
+    other: &'a HashSet<T, S>,
+}
+
+impl<'a, T, S> Iterator for Intersection<'a, T, S>
+where
+    T: Eq + Hash + ::std::fmt::Debug,
+    S: BuildHasher,
+{
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        let other = self.other;
+        self.iter.by_ref().find(|v| other.contains(*v))
+    }
+}
+
+pub struct Difference<'a, T: 'a + Eq, S: 'a> {
+    iter: Iter<'a, T>,
+    other: &'a HashSet<T, S>,
+}
+
+impl<'a, T, S> Iterator for Difference<'a, T, S>
+where
+    T: Eq + Hash + ::std::fmt::Debug,
+    S: BuildHasher,
+{
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        let other = self.other;
+        self.iter.by_ref().find(|v| !other.contains(*v))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use quickcheck::{Arbitrary, Gen, QuickCheck, TestResult};
+    use std::collections;
+
+    #[derive(Clone, Debug)]
+    enum Action<T>
+    where
+        T: Arbitrary,
+    {
+        Insert(T),
+        Remove(T),
+        Contains(T),
+    }
+
+    impl<T> Arbitrary for Action<T>
+    where
+        T: Arbitrary,
+    {
+        fn arbitrary(g: &mut Gen) -> Action<T> {
+            match u32::arbitrary(g) % 100 {
+                0..=59 => Action::Insert(T::arbitrary(g)),
+                60..=79 => Action::Remove(T::arbitrary(g)),
+                _ => Action::Contains(T::arbitrary(g)),
+            }
+        }
+
+        fn shrink(&self) -> Box<dyn Iterator<Item = Action<T>>> {
+            match *self {
+                Action::Insert(ref v) => Box::new(v.shrink().map(Action::Insert)),
+                Action::Remove(ref v) => Box::new(v.shrink().map(Action::Remove)),
+                Action::Contains(ref v) => {
+                    Box::new(v.shrink().map(Action::Contains))
+                }
+            }
+        }
+    }
+
+    fn replay<T>(
+        actions: Vec<Action<T>>,
+    ) -> (collections::HashSet<T>, HashSet<T>)
+    where
+        T: Arbitrary + Eq + Hash + ::std::fmt::Debug,
+    {
+        let mut model = collections::HashSet::new();
+        let mut system_under_test = HashSet::new();
+
+        for action in actions.into_iter() {
+            match action {
+                Action::Insert(v) => {
+                    assert_eq!(model.insert(v.clone()), system_under_test.insert(v));
+                }
+                Action::Remove(v) => {
+                    assert_eq!(model.remove(&v), system_under_test.remove(&v));
+                }
+                Action::Contains(v) => {
+                    assert_eq!(model.contains(&v), system_under_test.contains(&v));
+                }
+            }
+            assert_eq!(model.len(), system_under_test.len());
+        }
+        (model, system_under_test)
+    }
+
+    fn sorted<'a, T: 'a + Ord + Clone, I: Iterator<Item = &'a T>>(iter: I) -> Vec<T> {
+        let mut v: Vec<T> = iter.cloned().collect();
+        v.sort();
+        v
+    }
+
+    #[test]
+    fn sut_vs_genuine_article() {
+        fn property<T>(actions: Vec<Action<T>>) -> TestResult
+        where
+            T: Arbitrary + Eq + Hash + ::std::fmt::Debug + Ord,
+        {
+            let (model, system_under_test) = replay(actions);
+            assert_eq!(sorted(model.iter()), sorted(system_under_test.iter()));
+            TestResult::passed()
+        }
+        QuickCheck::new().quickcheck(property as fn(Vec<Action<u8>>) -> TestResult);
+        QuickCheck::new().quickcheck(property as fn(Vec<Action<String>>) -> TestResult);
+    }
+
+    #[test]
+    fn set_operations_vs_genuine_article() {
+        fn property(left: Vec<Action<u8>>, right: Vec<Action<u8>>) -> TestResult {
+            let (model_left, sut_left) = replay(left);
+            let (model_right, sut_right) = replay(right);
+
+            assert_eq!(
+                sorted(model_left.union(&model_right)),
+                sorted(sut_left.union(&sut_right))
+            );
+            assert_eq!(
+                sorted(model_left.intersection(&model_right)),
+                sorted(sut_left.intersection(&sut_right))
+            );
+            assert_eq!(
+                sorted(model_left.difference(&model_right)),
+                sorted(sut_left.difference(&sut_right))
+            );
+            TestResult::passed()
+        }
+        QuickCheck::new()
+            .quickcheck(property as fn(Vec<Action<u8>>, Vec<Action<u8>>) -> TestResult);
+    }
+}
@@ -0,0 +1,156 @@
+//! A `HashMap` split into independently locked shards, for use from several
+//! threads at once.
+//!
+//! A key's shard is chosen by the top bits of its hash. Each shard is an
+//! ordinary `HashMap` built with a clone of the same hasher, so a key hashes
+//! identically whichever shard it lands in.
+
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::sync::RwLock;
+
+use {make_hash, HashMap};
+
+/// The shard count `ShardedHashMap::new` uses.
+pub const DEFAULT_SHARDS: usize = 16;
+
+pub struct ShardedHashMap<K, V, S = RandomState>
+where
+    K: Eq,
+    V: ::std::fmt::Debug,
+{
+    hash_builder: S,
+    shift: u32,
+    shards: Vec<RwLock<HashMap<K, V, S>>>,
+}
+
+impl<K, V> ShardedHashMap<K, V, RandomState>
+where
+    K: Eq + Hash,
+    V: ::std::fmt::Debug,
+{
+    pub fn new() -> ShardedHashMap<K, V> {
+        ShardedHashMap::with_shards_and_hasher(DEFAULT_SHARDS, RandomState::new())
+    }
+
+    pub fn with_shards(shards: usize) -> ShardedHashMap<K, V> {
+        ShardedHashMap::with_shards_and_hasher(shards, RandomState::new())
+    }
+}
+
+impl<K, V> Default for ShardedHashMap<K, V, RandomState>
+where
+    K: Eq + Hash,
+    V: ::std::fmt::Debug,
+{
+    fn default() -> ShardedHashMap<K, V> {
+        ShardedHashMap::new()
+    }
+}
+
+impl<K, V, S> ShardedHashMap<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher + Clone,
+    V: ::std::fmt::Debug,
+{
+    pub fn with_hasher(hash_builder: S) -> ShardedHashMap<K, V, S> {
+        ShardedHashMap::with_shards_and_hasher(DEFAULT_SHARDS, hash_builder)
+    }
+
+    /// `shards` must be a power of two.
+    pub fn with_shards_and_hasher(
+        shards: usize,
+        hash_builder: S,
+    ) -> ShardedHashMap<K, V, S> {
+        assert!(
+            shards.is_power_of_two(),
+            "shard count must be a power of two, got {}",
+            shards
+        );
+        ShardedHashMap {
+            shift: 64 - shards.trailing_zeros(),
+            shards: (0..shards)
+                .map(|_| RwLock::new(HashMap::with_hasher(hash_builder.clone())))
+                .collect(),
+            hash_builder,
+        }
+    }
+
+    fn shard_for<Q>(&self, k: &Q) -> &RwLock<HashMap<K, V, S>>
+    where
+        Q: Hash + ?Sized,
+    {
+        let hash = make_hash(&self.hash_builder, k);
+        // With a single shard the shift is 64, which `checked_shr` rejects.
+        let idx = hash.checked_shr(self.shift).unwrap_or(0);
+        &self.shards[idx as usize]
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn insert(&self, k: K, v: V) -> Option<V> {
+        self.shard_for(&k).write().unwrap().insert(k, v)
+    }
+
+    /// Values are returned by copy, since a reference could not outlive the
+    /// shard's lock.
+    pub fn get<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q> + ::std::fmt::Debug,
+        Q: Hash + Eq + ::std::fmt::Debug + ?Sized,
+        V: Clone,
+    {
+        self.shard_for(k).read().unwrap().get(k).cloned()
+    }
+
+    pub fn contains_key<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q> + ::std::fmt::Debug,
+        Q: Hash + Eq + ::std::fmt::Debug + ?Sized,
+    {
+        self.shard_for(k).read().unwrap().contains_key(k)
+    }
+
+    pub fn remove<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q> + ::std::fmt::Debug,
+        Q: Hash + Eq + ::std::fmt::Debug + ?Sized,
+    {
+        self.shard_for(k).write().unwrap().remove(k)
+    }
+
+    /// Call `f` on the value for `k`, if there is one, with its shard
+    /// locked for writing, and return what `f` does. This stands in for
+    /// `get_mut`, whose reference could not outlive the lock.
+    pub fn with_mut<Q, F, R>(&self, k: &Q, f: F) -> Option<R>
+    where
+        K: Borrow<Q> + ::std::fmt::Debug,
+        Q: Hash + Eq + ::std::fmt::Debug + ?Sized,
+        F: FnOnce(&mut V) -> R,
+    {
+        self.shard_for(k).write().unwrap().get_mut(k).map(f)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.read().unwrap().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|s| s.read().unwrap().is_empty())
+    }
+
+    /// Merge the shards back into a single `HashMap`.
+    pub fn into_inner(self) -> HashMap<K, V, S> {
+        let mut merged = HashMap::with_hasher(self.hash_builder);
+        for shard in self.shards {
+            for (_, k, v) in shard.into_inner().unwrap().data {
+                merged.insert(k, v);
+            }
+        }
+        merged
+    }
+}
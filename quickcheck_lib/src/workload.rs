@@ -0,0 +1,141 @@
+//! Seeded insert/lookup workloads shared by the benches and the driver
+//! binaries.
+//!
+//! Every run draws from a caller-supplied RNG, so two runs started from
+//! `seeded_rng()` perform exactly the same sequence of operations.
+
+use std::collections;
+use std::hash::{BuildHasher, Hash};
+
+use rand::distributions::{Distribution, Standard};
+use rand::{Rng, SeedableRng};
+use rand_xorshift::XorShiftRng;
+
+use {HashMap, HashMapU8};
+
+/// The seed the original benchmarks used, `[1981, 1986, 2003, 2011]`, laid
+/// out as little-endian bytes.
+pub const SEED: [u8; 16] = [
+    0xbd, 0x07, 0x00, 0x00, 0xc2, 0x07, 0x00, 0x00, 0xd3, 0x07, 0x00, 0x00,
+    0xdb, 0x07, 0x00, 0x00,
+];
+
+pub fn seeded_rng() -> XorShiftRng {
+    XorShiftRng::from_seed(SEED)
+}
+
+/// Proportion of inserts to lookups in a workload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mix {
+    /// Percentage of operations, `0..=100`, that are inserts.
+    pub insert_percent: u32,
+}
+
+impl Mix {
+    pub const READ_HEAVY: Mix = Mix { insert_percent: 10 };
+    pub const BALANCED: Mix = Mix { insert_percent: 50 };
+    pub const WRITE_HEAVY: Mix = Mix { insert_percent: 90 };
+
+    pub fn all() -> [(&'static str, Mix); 3] {
+        [
+            ("read_heavy", Mix::READ_HEAVY),
+            ("balanced", Mix::BALANCED),
+            ("write_heavy", Mix::WRITE_HEAVY),
+        ]
+    }
+}
+
+/// The operations a workload needs from a map.
+pub trait Map<K, V> {
+    fn insert(&mut self, k: K, v: V) -> Option<V>;
+    fn contains(&mut self, k: &K) -> bool;
+}
+
+impl<K, V, S> Map<K, V> for HashMap<K, V, S>
+where
+    K: Eq + Hash + ::std::fmt::Debug,
+    S: BuildHasher,
+    V: ::std::fmt::Debug,
+{
+    fn insert(&mut self, k: K, v: V) -> Option<V> {
+        HashMap::insert(self, k, v)
+    }
+
+    fn contains(&mut self, k: &K) -> bool {
+        self.get(k).is_some()
+    }
+}
+
+impl<V> Map<u8, V> for HashMapU8<V>
+where
+    V: ::std::fmt::Debug,
+{
+    fn insert(&mut self, k: u8, v: V) -> Option<V> {
+        HashMapU8::insert(self, k, v)
+    }
+
+    fn contains(&mut self, k: &u8) -> bool {
+        self.get(k).is_some()
+    }
+}
+
+impl<K, V, S> Map<K, V> for collections::HashMap<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    fn insert(&mut self, k: K, v: V) -> Option<V> {
+        collections::HashMap::insert(self, k, v)
+    }
+
+    fn contains(&mut self, k: &K) -> bool {
+        self.get(k).is_some()
+    }
+}
+
+/// Outcome counts of a workload run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Tally {
+    pub insert_empty: u64,
+    pub insert_present: u64,
+    pub get_fail: u64,
+    pub get_success: u64,
+}
+
+impl Tally {
+    pub fn print(&self) {
+        println!("INSERT");
+        println!("  empty:   {}", self.insert_empty);
+        println!("  present: {}", self.insert_present);
+        println!("LOOKUP");
+        println!("  fail:    {}", self.get_fail);
+        println!("  success: {}", self.get_success);
+    }
+}
+
+/// Perform `n` random operations against `map`, drawing keys of type `K` and
+/// `u32` values from `rng`.
+pub fn run<M, K, R>(map: &mut M, rng: &mut R, mix: Mix, n: u64) -> Tally
+where
+    M: Map<K, u32>,
+    R: Rng,
+    Standard: Distribution<K>,
+{
+    let mut tally = Tally::default();
+    for _ in 0..n {
+        let key = rng.gen::<K>();
+        if rng.gen_range(0..100) < mix.insert_percent {
+            let value = rng.gen::<u32>();
+            if map.insert(key, value).is_none() {
+                tally.insert_empty += 1;
+            } else {
+                tally.insert_present += 1;
+            }
+        } else if map.contains(&key) {
+            tally.get_success += 1;
+        } else {
+            tally.get_fail += 1;
+        }
+    }
+    tally
+}